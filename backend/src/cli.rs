@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use neo4rs::Graph;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+use crate::services::export::{self, ExportFormat, ExportScope};
+use crate::services::import::{self, ImportFormat};
+use crate::services::threading;
+
+const USAGE: &str = "usage:
+  gmail-clone-backend                      run the API server
+  gmail-clone-backend import <path> [--format mbox|maildir|eml|zip] [--label NAME]...
+  gmail-clone-backend export [--format mbox|eml|ndjson] [--label NAME] [--thread ID] [--search TEXT] [--output FILE]
+  gmail-clone-backend rethread             rebuild all conversation threads";
+
+/// Run a command-line subcommand against the database.
+pub async fn run(graph: &Graph, args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("import") => import_command(graph, &args[1..]).await,
+        Some("export") => export_command(graph, &args[1..]).await,
+        Some("rethread") => {
+            let threads = threading::rebuild(graph).await?;
+            eprintln!("Rebuilt {} threads", threads);
+            Ok(())
+        }
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(other) => Err(anyhow!("Unknown command: {}\n{}", other, USAGE)),
+        None => Err(anyhow!(USAGE)),
+    }
+}
+
+async fn import_command(graph: &Graph, args: &[String]) -> Result<()> {
+    let mut path = None;
+    let mut format = None;
+    let mut labels = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let value = args.next().ok_or_else(|| anyhow!("--format needs a value"))?;
+                format = Some(ImportFormat::parse(value)?);
+            }
+            "--label" => labels.push(args.next().ok_or_else(|| anyhow!("--label needs a value"))?.clone()),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(anyhow!("Unexpected argument: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let path = path.ok_or_else(|| anyhow!("Missing path\n{}", USAGE))?;
+    let path = Path::new(&path);
+    let format = format.unwrap_or_else(|| ImportFormat::detect(path));
+    if labels.is_empty() {
+        labels.push("INBOX".into());
+    }
+
+    let messages = import::read_path(path, format)?;
+    eprintln!("Importing {} messages from {}", messages.len(), path.display());
+
+    let report = import::run_import(graph, messages, &labels, |p| {
+        if p.processed % 100 == 0 || p.processed == p.total {
+            eprint!("\r{}/{} processed, {} imported, {} duplicates, {} failed", p.processed, p.total, p.imported, p.duplicates, p.failed);
+        }
+    })
+    .await;
+    eprintln!();
+
+    for failure in &report.errors {
+        eprintln!("{}: {}", failure.source, failure.error);
+    }
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+async fn export_command(graph: &Graph, args: &[String]) -> Result<()> {
+    let mut format = ExportFormat::Mbox;
+    let mut scope = ExportScope::default();
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| anyhow!("{} needs a value", arg));
+        match arg.as_str() {
+            "--format" => format = ExportFormat::parse(&value()?)?,
+            "--label" => scope.label = Some(value()?),
+            "--thread" => scope.thread_id = Some(value()?.parse()?),
+            "--search" => scope.search = Some(value()?),
+            "--output" => output = Some(value()?),
+            _ => return Err(anyhow!("Unexpected argument: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let mut out: Box<dyn tokio::io::AsyncWrite + Unpin> = match &output {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+
+    let mut rx = export::spawn_export(graph.clone(), scope, format);
+    let mut bytes = 0;
+    while let Some(chunk) = rx.recv().await {
+        let chunk = chunk?;
+        bytes += chunk.len();
+        out.write_all(&chunk).await?;
+    }
+    out.flush().await?;
+
+    if let Some(path) = output {
+        eprintln!("Wrote {} bytes to {}", bytes, path);
+    }
+    Ok(())
+}
@@ -1,3 +1,4 @@
+mod cli;
 mod models;
 mod routes;
 mod services;
@@ -9,6 +10,8 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 pub struct AppState {
     pub db: neo4rs::Graph,
+    pub mail: Arc<dyn services::mail::MailTransport>,
+    pub ai: Arc<services::ai::AiEnv>,
 }
 
 #[tokio::main]
@@ -31,7 +34,22 @@ async fn main() -> anyhow::Result<()> {
     // Initialize schema
     services::db::init_schema(&graph).await?;
 
-    let state = Arc::new(AppState { db: graph });
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&graph, &args).await;
+    }
+
+    let mail = services::mail::transport_from_env()?;
+    tracing::info!("Sending mail with the {} transport", mail.name());
+    let ai = Arc::new(services::ai::AiEnv::from_env());
+    services::outbox::spawn_worker(graph.clone(), mail.clone());
+    services::extract::spawn_worker(graph.clone(), ai.clone());
+    services::trash::spawn_worker(graph.clone());
+    services::undo::spawn_worker(graph.clone());
+    services::attachments::spawn_worker(graph.clone());
+    services::imap::spawn_all(graph.clone())?;
+
+    let state = Arc::new(AppState { db: graph, mail, ai });
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
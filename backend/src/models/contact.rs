@@ -6,4 +6,148 @@ pub struct ContactInfo {
     pub name: Option<String>,
     pub email_count: u64,
     pub last_contacted: Option<String>,
+    /// Other addresses of the same person; stats cover all of them.
+    #[serde(default)]
+    pub aliases: Vec<String>,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct ContactQuery {
+    #[serde(default = "default_page")]
+    pub page: u32,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    /// Matches email address or name, case-insensitively.
+    pub q: Option<String>,
+}
+
+fn default_page() -> u32 { 1 }
+fn default_limit() -> u32 { 50 }
+
+#[derive(Debug, Serialize)]
+pub struct ContactListResponse {
+    pub contacts: Vec<ContactInfo>,
+    pub total: u64,
+    pub page: u32,
+    pub limit: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AutocompleteQuery {
+    #[serde(default)]
+    pub q: String,
+    #[serde(default = "default_suggestions")]
+    pub limit: usize,
+}
+
+fn default_suggestions() -> usize { 10 }
+
+#[derive(Debug, Deserialize)]
+pub struct CreateContactRequest {
+    pub email: String,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateContactRequest {
+    pub name: Option<String>,
+}
+
+/// Body of `POST /contacts/:email/addresses`.
+#[derive(Debug, Deserialize)]
+pub struct AddAddressRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ContactImportSummary {
+    pub created: u64,
+    pub updated: u64,
+    /// Entries without a usable email address.
+    pub skipped: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeContactRequest {
+    /// Contact folded into this one and then removed.
+    pub email: String,
+}
+
+/// Someone this contact exchanged email with.
+#[derive(Debug, Clone, Serialize)]
+pub struct Correspondent {
+    pub email: String,
+    pub name: Option<String>,
+    /// Emails from the contact to this correspondent.
+    pub sent: u64,
+    /// Emails from this correspondent to the contact.
+    pub received: u64,
+    pub last_contacted: Option<String>,
+}
+
+/// The email where two contacts first appeared together, sent by a third
+/// person who copied at least one of them.
+#[derive(Debug, Clone, Serialize)]
+pub struct Introduction {
+    pub introducer: super::Contact,
+    pub introduced: Vec<super::Contact>,
+    pub email_id: String,
+    pub subject: String,
+    pub date: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Introductions {
+    /// People introduced to the contact.
+    pub received: Vec<Introduction>,
+    /// People the contact introduced to each other.
+    pub made: Vec<Introduction>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SharedThread {
+    pub thread_id: String,
+    pub subject: String,
+    pub message_count: u64,
+    pub last_date: Option<String>,
+}
+
+/// Reply delays in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ResponseTimeStats {
+    pub count: u64,
+    pub mean_secs: Option<i64>,
+    pub median_secs: Option<i64>,
+    pub p90_secs: Option<i64>,
+    pub fastest_secs: Option<i64>,
+    pub slowest_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponseTimes {
+    /// How quickly the contact answers others.
+    pub their_replies: ResponseTimeStats,
+    /// How quickly others answer the contact.
+    pub replies_to_them: ResponseTimeStats,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnalyticsQuery {
+    #[serde(default = "default_suggestions")]
+    pub limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DormantQuery {
+    /// Days without contact before a relationship counts as dormant.
+    #[serde(default = "default_dormant_days")]
+    pub days: i64,
+    /// Ignore relationships with fewer emails than this.
+    #[serde(default = "default_min_emails")]
+    pub min_emails: u64,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_dormant_days() -> i64 { 90 }
+fn default_min_emails() -> u64 { 3 }
@@ -7,17 +7,83 @@ pub struct Email {
     pub id: Uuid,
     pub subject: String,
     pub body: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html_body: Option<String>,
     pub snippet: String,
     pub date: DateTime<Utc>,
     pub is_read: bool,
     pub is_starred: bool,
     pub thread_id: Option<Uuid>,
+    /// RFC 5322 `Message-ID`, including the angle brackets.
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    #[serde(default)]
+    pub references: Vec<String>,
     pub from: Contact,
     pub to: Vec<Contact>,
     pub cc: Vec<Contact>,
     pub labels: Vec<String>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub embedding: Option<Vec<f32>>,
+    /// Outbound delivery state; `None` for mail that was never sent from here.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery: Option<Delivery>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Queued,
+    Sending,
+    Retrying,
+    Sent,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Queued => "queued",
+            DeliveryStatus::Sending => "sending",
+            DeliveryStatus::Retrying => "retrying",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "queued" => Some(DeliveryStatus::Queued),
+            "sending" => Some(DeliveryStatus::Sending),
+            "retrying" => Some(DeliveryStatus::Retrying),
+            "sent" => Some(DeliveryStatus::Sent),
+            "failed" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+/// File attached to one or more emails. The content lives in the blob store
+/// under its SHA-256, so identical files are stored once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub filename: String,
+    pub content_type: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the content.
+    pub sha256: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,16 +99,59 @@ pub struct EmailThread {
     pub subject: String,
     pub last_message_date: DateTime<Utc>,
     pub participant_count: usize,
+    /// Replies to a muted thread skip the inbox.
+    pub muted: bool,
+}
+
+/// One row of the threaded inbox.
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadSummary {
+    pub id: Uuid,
+    pub subject: String,
+    /// Senders first, in order of their first message, then other recipients.
+    pub participants: Vec<Contact>,
+    pub message_count: u64,
+    pub unread_count: u64,
+    /// Snippet of the latest message.
+    pub snippet: String,
+    /// Labels on any message in the thread.
+    pub labels: Vec<String>,
+    pub is_starred: bool,
+    pub has_attachments: bool,
+    pub last_date: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadListResponse {
+    pub threads: Vec<ThreadSummary>,
+    pub total: u64,
+    pub page: u32,
+    pub limit: u32,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateEmailRequest {
     pub subject: String,
     pub body: String,
+    /// Optional HTML alternative to the plain-text `body`.
+    pub html_body: Option<String>,
     pub to: Vec<String>,
     #[serde(default)]
     pub cc: Vec<String>,
     pub reply_to: Option<Uuid>,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentUpload>,
+    /// Attachments uploaded beforehand through `POST /api/attachments`.
+    #[serde(default)]
+    pub attachment_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttachmentUpload {
+    pub filename: String,
+    pub content_type: Option<String>,
+    /// Base64-encoded file contents.
+    pub content: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,6 +161,39 @@ pub struct UpdateEmailRequest {
     pub labels: Option<Vec<String>>,
 }
 
+/// Changes applied to every email of a thread.
+#[derive(Debug, Deserialize)]
+pub struct UpdateThreadRequest {
+    pub is_read: Option<bool>,
+    pub is_starred: Option<bool>,
+    /// Replaces the labels of every email.
+    pub labels: Option<Vec<String>>,
+    #[serde(default)]
+    pub add_labels: Vec<String>,
+    #[serde(default)]
+    pub remove_labels: Vec<String>,
+    /// Remove the thread from the inbox.
+    #[serde(default)]
+    pub archive: bool,
+    /// Move the thread to the trash.
+    #[serde(default)]
+    pub trash: bool,
+    /// Muting also archives the thread.
+    pub muted: Option<bool>,
+}
+
+/// Body of `POST /threads/:id/emails` and `POST /threads/:id/split`.
+#[derive(Debug, Deserialize)]
+pub struct ThreadEmailRequest {
+    pub email_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeThreadRequest {
+    /// Thread whose emails move into this one.
+    pub thread_id: Uuid,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct EmailQuery {
     #[serde(default = "default_page")]
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+use super::Contact;
+
+/// A named set of contacts, usable as the recipient `group:<name>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub description: Option<String>,
+    pub member_count: u64,
+    /// Only filled in when fetching a single group.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<Contact>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateGroupRequest {
+    pub description: Option<String>,
+    /// Replaces all members.
+    pub members: Option<Vec<String>>,
+    #[serde(default)]
+    pub add_members: Vec<String>,
+    #[serde(default)]
+    pub remove_members: Vec<String>,
+}
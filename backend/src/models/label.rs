@@ -2,13 +2,95 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Label {
+    /// Full path; nested labels use `/`, e.g. `Work/Clients/Acme`.
     pub name: String,
     pub color: Option<String>,
     pub email_count: u64,
+    /// Emails carrying this label or any label nested under it.
+    pub total_count: u64,
+    /// Name of the enclosing label, if nested.
+    pub parent: Option<String>,
+    pub unread_count: u64,
+    /// Conversations with at least one email carrying this label.
+    pub thread_count: u64,
+    /// Emails with this label are never sent to external AI providers.
+    pub ai_excluded: bool,
+    pub list_visibility: LabelListVisibility,
+    pub message_list_visibility: MessageListVisibility,
+}
+
+/// Whether the label appears in the sidebar label list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelListVisibility {
+    #[default]
+    Show,
+    Hide,
+    ShowIfUnread,
+}
+
+impl LabelListVisibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LabelListVisibility::Show => "show",
+            LabelListVisibility::Hide => "hide",
+            LabelListVisibility::ShowIfUnread => "show_if_unread",
+        }
+    }
+
+    /// Stored value, falling back to `Show` for labels without one.
+    pub fn from_stored(value: Option<String>) -> Self {
+        match value.as_deref() {
+            Some("hide") => LabelListVisibility::Hide,
+            Some("show_if_unread") => LabelListVisibility::ShowIfUnread,
+            _ => LabelListVisibility::Show,
+        }
+    }
+}
+
+/// Whether the label chip appears on emails in message lists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageListVisibility {
+    #[default]
+    Show,
+    Hide,
+}
+
+impl MessageListVisibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageListVisibility::Show => "show",
+            MessageListVisibility::Hide => "hide",
+        }
+    }
+
+    pub fn from_stored(value: Option<String>) -> Self {
+        match value.as_deref() {
+            Some("hide") => MessageListVisibility::Hide,
+            _ => MessageListVisibility::Show,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateLabelRequest {
     pub name: String,
     pub color: Option<String>,
+    #[serde(default)]
+    pub ai_excluded: bool,
+    #[serde(default)]
+    pub list_visibility: LabelListVisibility,
+    #[serde(default)]
+    pub message_list_visibility: MessageListVisibility,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateLabelRequest {
+    /// New full name; nested labels move along with it.
+    pub name: Option<String>,
+    pub color: Option<String>,
+    pub ai_excluded: Option<bool>,
+    pub list_visibility: Option<LabelListVisibility>,
+    pub message_list_visibility: Option<MessageListVisibility>,
 }
@@ -1,7 +1,11 @@
 mod email;
 mod contact;
+mod group;
 mod label;
+mod undo;
 
 pub use email::*;
 pub use contact::*;
+pub use group::*;
 pub use label::*;
+pub use undo::*;
@@ -0,0 +1,18 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Returned by permanent deletions; `POST /api/undo/:token` brings the
+/// deleted data back until `expires_at`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UndoToken {
+    pub undo_token: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreSummary {
+    pub emails: u64,
+    pub threads: u64,
+    pub labels: u64,
+}
@@ -1,14 +1,15 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    routing::post,
+    routing::{get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::{services, AppState};
+use super::UserId;
+use crate::{models::Attachment, services, AppState};
 
 pub fn routes() -> Router<Arc<AppState>> {
     Router::new()
@@ -18,6 +19,19 @@ pub fn routes() -> Router<Arc<AppState>> {
         .route("/categorize", post(categorize))
         .route("/index", post(index_emails))
         .route("/index/:id", post(index_single_email))
+        .route("/usage", get(usage_report))
+        .route("/prompts", get(list_prompts))
+        .route("/prompts/reload", post(reload_prompts))
+}
+
+fn ai_error(e: anyhow::Error) -> (StatusCode, String) {
+    if e.is::<services::usage::QuotaError>() {
+        (StatusCode::TOO_MANY_REQUESTS, e.to_string())
+    } else if e.is::<services::providers::ProviderError>() {
+        (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,21 +39,26 @@ pub struct SummarizeRequest {
     pub email_id: Option<Uuid>,
     pub thread_id: Option<Uuid>,
     pub text: Option<String>,
+    pub prompt_version: Option<u32>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct SummarizeResponse {
     pub summary: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    pub degraded: bool,
 }
 
 async fn summarize(
     State(state): State<Arc<AppState>>,
+    UserId(user): UserId,
     Json(req): Json<SummarizeRequest>,
 ) -> Result<Json<SummarizeResponse>, (StatusCode, String)> {
-    services::ai::summarize(&state.db, req)
+    services::ai::summarize(&state.db, &state.ai, &user, req)
         .await
-        .map(|summary| Json(SummarizeResponse { summary }))
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map(|out| Json(SummarizeResponse { summary: out.value, prompt: out.prompt, degraded: out.degraded }))
+        .map_err(ai_error)
 }
 
 #[derive(Debug, Deserialize)]
@@ -47,21 +66,26 @@ pub struct ComposeRequest {
     pub context: Option<String>,
     pub reply_to: Option<Uuid>,
     pub prompt: Option<String>,
+    pub prompt_version: Option<u32>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ComposeResponse {
     pub suggestions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    pub degraded: bool,
 }
 
 async fn smart_compose(
     State(state): State<Arc<AppState>>,
+    UserId(user): UserId,
     Json(req): Json<ComposeRequest>,
 ) -> Result<Json<ComposeResponse>, (StatusCode, String)> {
-    services::ai::smart_compose(&state.db, req)
+    services::ai::smart_compose(&state.db, &state.ai, &user, req)
         .await
-        .map(|suggestions| Json(ComposeResponse { suggestions }))
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map(|out| Json(ComposeResponse { suggestions: out.value, prompt: out.prompt, degraded: out.degraded }))
+        .map_err(ai_error)
 }
 
 #[derive(Debug, Deserialize)]
@@ -73,48 +97,61 @@ pub struct SearchRequest {
 
 fn default_limit() -> usize { 20 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SearchResult {
     pub email_id: Uuid,
     pub subject: String,
     pub snippet: String,
     pub score: f32,
+    /// Set when the match came from this attachment's text rather than the email.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct SearchResponse {
     pub results: Vec<SearchResult>,
+    pub degraded: bool,
 }
 
 async fn semantic_search(
     State(state): State<Arc<AppState>>,
+    UserId(user): UserId,
     Json(req): Json<SearchRequest>,
 ) -> Result<Json<SearchResponse>, (StatusCode, String)> {
-    services::ai::semantic_search(&state.db, req)
+    services::ai::semantic_search(&state.db, &state.ai, &user, req)
         .await
-        .map(|results| Json(SearchResponse { results }))
-        .map_err(|e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map(|out| Json(SearchResponse { results: out.value, degraded: out.degraded }))
+        .map_err(ai_error)
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CategorizeRequest {
     pub email_id: Uuid,
+    pub prompt_version: Option<u32>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct CategorizeResponse {
     pub suggested_labels: Vec<String>,
     pub priority: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    pub degraded: bool,
 }
 
 async fn categorize(
     State(state): State<Arc<AppState>>,
+    UserId(user): UserId,
     Json(req): Json<CategorizeRequest>,
 ) -> Result<Json<CategorizeResponse>, (StatusCode, String)> {
-    services::ai::categorize(&state.db, req)
+    services::ai::categorize(&state.db, &state.ai, &user, req)
         .await
-        .map(|(labels, priority)| Json(CategorizeResponse { suggested_labels: labels, priority }))
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map(|out| {
+            let (labels, priority) = out.value;
+            Json(CategorizeResponse { suggested_labels: labels, priority, prompt: out.prompt, degraded: out.degraded })
+        })
+        .map_err(ai_error)
 }
 
 #[derive(Debug, Serialize)]
@@ -124,19 +161,56 @@ pub struct IndexResponse {
 
 async fn index_emails(
     State(state): State<Arc<AppState>>,
+    UserId(user): UserId,
 ) -> Result<Json<IndexResponse>, (StatusCode, String)> {
-    services::ai::batch_index_emails(&state.db)
+    services::ai::batch_index_emails(&state.db, &state.ai, &user)
         .await
         .map(|count| Json(IndexResponse { indexed_count: count }))
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map_err(ai_error)
 }
 
 async fn index_single_email(
     State(state): State<Arc<AppState>>,
+    UserId(user): UserId,
     Path(id): Path<Uuid>,
 ) -> Result<StatusCode, (StatusCode, String)> {
-    services::ai::index_email(&state.db, id)
+    services::ai::index_email(&state.db, &state.ai, &user, id)
         .await
         .map(|_| StatusCode::OK)
+        .map_err(ai_error)
+}
+
+async fn list_prompts(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<services::prompts::PromptInfo>>, (StatusCode, String)> {
+    state.ai.prompts.list()
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn reload_prompts(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<services::prompts::PromptInfo>>, (StatusCode, String)> {
+    state.ai.prompts.reload()
+        .map(Json)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    #[serde(default = "default_usage_days")]
+    pub days: u32,
+}
+
+fn default_usage_days() -> u32 { 30 }
+
+async fn usage_report(
+    State(state): State<Arc<AppState>>,
+    UserId(user): UserId,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<services::usage::UsageReport>, (StatusCode, String)> {
+    services::usage::usage_report(&state.db, &user, query.days)
+        .await
+        .map(Json)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
 }
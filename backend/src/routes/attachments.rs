@@ -0,0 +1,112 @@
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use std::io::SeekFrom;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{
+    models::Attachment,
+    services::{self, blobs::ByteRange},
+    AppState,
+};
+
+pub fn routes() -> Router<Arc<AppState>> {
+    let max_upload_mb: usize = std::env::var("ATTACHMENT_MAX_UPLOAD_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(25);
+
+    Router::new()
+        .route("/", post(upload).layer(DefaultBodyLimit::max(max_upload_mb * 1024 * 1024)))
+        .route("/:id", get(download))
+}
+
+/// Multipart upload of one or more `file` fields while composing. The returned
+/// ids go into `attachment_ids` when the email is sent.
+async fn upload(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<Vec<Attachment>>), (StatusCode, String)> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let mut uploaded = Vec::new();
+
+    while let Some(field) = multipart.next_field().await.map_err(|e| bad_request(e.to_string()))? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = field.file_name().unwrap_or("attachment").to_string();
+        let content_type = field.content_type().unwrap_or("application/octet-stream").to_string();
+        let data = field.bytes().await.map_err(|e| bad_request(e.to_string()))?;
+
+        let attachment = services::attachments::store(&state.db, None, &filename, &content_type, &data)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        uploaded.push(attachment);
+    }
+
+    if uploaded.is_empty() {
+        return Err(bad_request("No files in upload".into()));
+    }
+    Ok((StatusCode::CREATED, Json(uploaded)))
+}
+
+/// Stream an attachment, honouring a single `Range: bytes=` request.
+async fn download(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let internal = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let attachment = services::attachments::get(&state.db, id)
+        .await
+        .map_err(|e| match e.to_string().as_str() {
+            "Attachment not found" => (StatusCode::NOT_FOUND, e.to_string()),
+            _ => internal(e.to_string()),
+        })?;
+
+    let path = services::blobs::path(&attachment.sha256).map_err(|e| internal(e.to_string()))?;
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "Attachment content missing".to_string()))?;
+    let size = file.metadata().await.map_err(|e| internal(e.to_string()))?.len();
+
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) => ByteRange::parse(value, size).map_err(|e| (StatusCode::RANGE_NOT_SATISFIABLE, e.to_string()))?,
+        None => None,
+    };
+
+    let disposition = format!(
+        "attachment; filename=\"{}\"",
+        attachment.filename.replace(['"', '\\', '\r', '\n'], "_")
+    );
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, attachment.content_type)
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    let body = match range {
+        Some(range) => {
+            file.seek(SeekFrom::Start(range.start)).await.map_err(|e| internal(e.to_string()))?;
+            response = response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end, size))
+                .header(header::CONTENT_LENGTH, range.len());
+            Body::from_stream(ReaderStream::new(file.take(range.len())))
+        }
+        None => {
+            response = response.header(header::CONTENT_LENGTH, size);
+            Body::from_stream(ReaderStream::new(file))
+        }
+    };
+
+    response.body(body).map_err(|e| internal(e.to_string())).map(IntoResponse::into_response)
+}
@@ -0,0 +1,253 @@
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::{
+    models::{
+        AddAddressRequest, AnalyticsQuery, AutocompleteQuery, ContactImportSummary, ContactInfo, ContactListResponse,
+        ContactQuery, Correspondent, CreateContactRequest, DormantQuery, Introductions, MergeContactRequest,
+        ResponseTimes, SharedThread, UpdateContactRequest,
+    },
+    services::{self, address_book::AddressBookFormat},
+    AppState,
+};
+
+pub fn routes() -> Router<Arc<AppState>> {
+    let max_upload_mb: usize = std::env::var("IMPORT_MAX_UPLOAD_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100);
+
+    Router::new()
+        .route("/", get(list_contacts).post(create_contact))
+        .route("/autocomplete", get(autocomplete))
+        .route("/dormant", get(dormant))
+        .route("/import", post(import_contacts).layer(DefaultBodyLimit::max(max_upload_mb * 1024 * 1024)))
+        .route("/export", get(export_contacts))
+        .route("/:email", get(get_contact).patch(update_contact))
+        .route("/:email/merge", post(merge_contacts))
+        .route("/:email/addresses", post(add_address))
+        .route("/:email/addresses/:address", delete(remove_address))
+        .route("/:email/correspondents", get(top_correspondents))
+        .route("/:email/introductions", get(introductions))
+        .route("/:email/shared-threads/:other", get(shared_threads))
+        .route("/:email/response-times", get(response_times))
+}
+
+fn contact_error(e: anyhow::Error) -> (StatusCode, String) {
+    let message = e.to_string();
+    if message.starts_with("Invalid")
+        || message == "Cannot merge a contact with itself"
+        || message == "Cannot remove the primary address"
+    {
+        (StatusCode::BAD_REQUEST, message)
+    } else if message == "Contact not found" || message == "Address not found" {
+        (StatusCode::NOT_FOUND, message)
+    } else if message == "Contact already exists" {
+        (StatusCode::CONFLICT, message)
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+/// List contacts, optionally filtered by `q`, most emailed first.
+async fn list_contacts(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ContactQuery>,
+) -> Result<Json<ContactListResponse>, (StatusCode, String)> {
+    services::contacts::list_contacts(&state.db, params)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+/// Recipient suggestions for the compose form.
+async fn autocomplete(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AutocompleteQuery>,
+) -> Result<Json<Vec<ContactInfo>>, (StatusCode, String)> {
+    services::contacts::autocomplete(&state.db, params)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+async fn get_contact(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+) -> Result<Json<ContactInfo>, (StatusCode, String)> {
+    services::contacts::get_contact(&state.db, &email)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+async fn create_contact(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateContactRequest>,
+) -> Result<(StatusCode, Json<ContactInfo>), (StatusCode, String)> {
+    services::contacts::create_contact(&state.db, req)
+        .await
+        .map(|c| (StatusCode::CREATED, Json(c)))
+        .map_err(contact_error)
+}
+
+async fn update_contact(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+    Json(req): Json<UpdateContactRequest>,
+) -> Result<Json<ContactInfo>, (StatusCode, String)> {
+    services::contacts::update_contact(&state.db, &email, req)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+/// Merge the contact in the body into this one.
+async fn merge_contacts(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+    Json(req): Json<MergeContactRequest>,
+) -> Result<Json<ContactInfo>, (StatusCode, String)> {
+    services::contacts::merge_contacts(&state.db, &email, &req.email)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+/// Group another address under this contact.
+async fn add_address(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+    Json(req): Json<AddAddressRequest>,
+) -> Result<Json<ContactInfo>, (StatusCode, String)> {
+    services::contacts::add_address(&state.db, &email, &req.email)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+async fn remove_address(
+    State(state): State<Arc<AppState>>,
+    Path((email, address)): Path<(String, String)>,
+) -> Result<Json<ContactInfo>, (StatusCode, String)> {
+    services::contacts::remove_address(&state.db, &email, &address)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+/// Multipart upload of one or more `file` fields (vCard 3/4 or CSV). Entries
+/// are merged into existing contacts by email.
+async fn import_contacts(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Json<ContactImportSummary>, (StatusCode, String)> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let mut records = Vec::new();
+    let mut files = 0;
+
+    while let Some(field) = multipart.next_field().await.map_err(|e| bad_request(e.to_string()))? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let file_name = field.file_name().unwrap_or("contacts").to_string();
+        let text = field.text().await.map_err(|e| bad_request(e.to_string()))?;
+        let format = AddressBookFormat::detect(&file_name, &text);
+        records.extend(services::address_book::read(format, &text).map_err(|e| bad_request(e.to_string()))?);
+        files += 1;
+    }
+
+    if files == 0 {
+        return Err(bad_request("No files in upload".into()));
+    }
+    services::contacts::import_contacts(&state.db, records)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default = "default_format")]
+    format: String,
+}
+
+fn default_format() -> String {
+    "vcard".into()
+}
+
+/// Download every contact as `vcard` or `csv`, with email stats.
+async fn export_contacts(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExportQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let format = AddressBookFormat::parse(&params.format).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let contacts = services::contacts::all_contacts(&state.db).await.map_err(contact_error)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", format.file_name())),
+        ],
+        services::address_book::write(format, &contacts),
+    ))
+}
+
+async fn top_correspondents(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+    Query(params): Query<AnalyticsQuery>,
+) -> Result<Json<Vec<Correspondent>>, (StatusCode, String)> {
+    services::relationships::top_correspondents(&state.db, &email, params.limit)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+async fn introductions(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+) -> Result<Json<Introductions>, (StatusCode, String)> {
+    services::relationships::introductions(&state.db, &email)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+async fn shared_threads(
+    State(state): State<Arc<AppState>>,
+    Path((email, other)): Path<(String, String)>,
+) -> Result<Json<Vec<SharedThread>>, (StatusCode, String)> {
+    services::relationships::shared_threads(&state.db, &email, &other)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+async fn response_times(
+    State(state): State<Arc<AppState>>,
+    Path(email): Path<String>,
+) -> Result<Json<ResponseTimes>, (StatusCode, String)> {
+    services::relationships::response_times(&state.db, &email)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
+
+/// Contacts the mailbox has stopped emailing.
+async fn dormant(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DormantQuery>,
+) -> Result<Json<Vec<ContactInfo>>, (StatusCode, String)> {
+    services::relationships::dormant(&state.db, params)
+        .await
+        .map(Json)
+        .map_err(contact_error)
+}
@@ -1,7 +1,8 @@
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
-    routing::{delete, get, patch, post},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Json, Router,
 };
 use std::sync::Arc;
@@ -15,7 +16,11 @@ use crate::{
 pub fn routes() -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(list_emails).post(create_email))
+        .route("/outbox", get(list_outbox))
         .route("/:id", get(get_email).patch(update_email).delete(delete_email))
+        .route("/:id/send", post(send_email))
+        .route("/:id/archive", post(archive_email))
+        .route("/:id/raw", get(get_raw))
 }
 
 async fn list_emails(
@@ -45,12 +50,82 @@ async fn create_email(
     State(state): State<Arc<AppState>>,
     Json(req): Json<CreateEmailRequest>,
 ) -> Result<(StatusCode, Json<Email>), (StatusCode, String)> {
-    services::emails::create_email(&state.db, req)
+    let email = services::emails::create_email(&state.db, req)
         .await
-        .map(|e| (StatusCode::CREATED, Json(e)))
+        .map_err(|e| {
+            let message = e.to_string();
+            if message.starts_with("Invalid") {
+                (StatusCode::BAD_REQUEST, message)
+            } else if message == "Email not found" {
+                (StatusCode::NOT_FOUND, "Replied-to email not found".to_string())
+            } else if message == "Attachment not found" {
+                (StatusCode::BAD_REQUEST, message)
+            } else {
+                (StatusCode::INTERNAL_SERVER_ERROR, message)
+            }
+        })?;
+
+    // First attempt happens inline; failures stay in the outbox for the worker
+    let email = match services::outbox::deliver(&state.db, state.mail.as_ref(), email.id).await {
+        Ok(delivered) => delivered,
+        Err(e) => {
+            tracing::warn!("Could not attempt delivery of {}: {}", email.id, e);
+            email
+        }
+    };
+    Ok((StatusCode::CREATED, Json(email)))
+}
+
+async fn list_outbox(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Email>>, (StatusCode, String)> {
+    services::outbox::list_outbox(&state.db)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn send_email(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Email>, (StatusCode, String)> {
+    services::outbox::requeue(&state.db, id)
+        .await
+        .map_err(|e| match e.to_string().as_str() {
+            "Email not found" => (StatusCode::NOT_FOUND, e.to_string()),
+            "Email was not sent from this mailbox" | "Email is being sent" => {
+                (StatusCode::CONFLICT, e.to_string())
+            }
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+
+    services::outbox::deliver(&state.db, state.mail.as_ref(), id)
+        .await
+        .map(Json)
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
 }
 
+/// Download the message exactly as it was sent, as an `.eml` file.
+async fn get_raw(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let raw = services::emails::get_raw(&state.db, id)
+        .await
+        .map_err(|e| match e.to_string().as_str() {
+            "Email not found" | "Raw message not available" => (StatusCode::NOT_FOUND, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "message/rfc822".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.eml\"", id)),
+        ],
+        raw,
+    ))
+}
+
 async fn update_email(
     State(state): State<Arc<AppState>>,
     Path(id): Path<Uuid>,
@@ -62,12 +137,32 @@ async fn update_email(
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
 }
 
+async fn archive_email(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Email>, (StatusCode, String)> {
+    services::emails::archive_email(&state.db, id)
+        .await
+        .map(Json)
+        .map_err(|e| match e.to_string().as_str() {
+            "Email not found" => (StatusCode::NOT_FOUND, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
+}
+
+/// Move the email to TRASH (204). Deleting it from TRASH returns an undo token.
 async fn delete_email(
     State(state): State<Arc<AppState>>,
     Path(id): Path<Uuid>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<Response, (StatusCode, String)> {
     services::emails::delete_email(&state.db, id)
         .await
-        .map(|_| StatusCode::NO_CONTENT)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map(|token| match token {
+            Some(token) => Json(token).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        })
+        .map_err(|e| match e.to_string().as_str() {
+            "Email not found" => (StatusCode::NOT_FOUND, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
 }
@@ -0,0 +1,61 @@
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    services::{
+        self,
+        export::{ExportFormat, ExportScope},
+    },
+    AppState,
+};
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/", get(export))
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default = "default_format")]
+    format: String,
+    label: Option<String>,
+    thread_id: Option<Uuid>,
+    search: Option<String>,
+}
+
+fn default_format() -> String {
+    "mbox".into()
+}
+
+/// Stream a label, thread, search result or the whole mailbox as `mbox`,
+/// `eml` (zip) or `ndjson`.
+async fn export(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExportQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let format = ExportFormat::parse(&params.format).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let scope = ExportScope {
+        label: params.label,
+        thread_id: params.thread_id,
+        search: params.search,
+    };
+
+    let rx = services::export::spawn_export(state.db.clone(), scope, format);
+    let stream = futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", format.file_name())),
+        ],
+        Body::from_stream(stream),
+    ))
+}
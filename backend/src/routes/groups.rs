@@ -0,0 +1,79 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use std::sync::Arc;
+
+use crate::{
+    models::{CreateGroupRequest, Group, UpdateGroupRequest},
+    services, AppState,
+};
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(list_groups).post(create_group))
+        .route("/:name", get(get_group).patch(update_group).delete(delete_group))
+}
+
+fn group_error(e: anyhow::Error) -> (StatusCode, String) {
+    let message = e.to_string();
+    if message.starts_with("Invalid") {
+        (StatusCode::BAD_REQUEST, message)
+    } else if message == "Group not found" {
+        (StatusCode::NOT_FOUND, message)
+    } else if message == "Group already exists" {
+        (StatusCode::CONFLICT, message)
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+async fn list_groups(State(state): State<Arc<AppState>>) -> Result<Json<Vec<Group>>, (StatusCode, String)> {
+    services::groups::list_groups(&state.db)
+        .await
+        .map(Json)
+        .map_err(group_error)
+}
+
+async fn get_group(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<Group>, (StatusCode, String)> {
+    services::groups::get_group(&state.db, &name)
+        .await
+        .map(Json)
+        .map_err(group_error)
+}
+
+async fn create_group(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateGroupRequest>,
+) -> Result<(StatusCode, Json<Group>), (StatusCode, String)> {
+    services::groups::create_group(&state.db, req)
+        .await
+        .map(|g| (StatusCode::CREATED, Json(g)))
+        .map_err(group_error)
+}
+
+async fn update_group(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(req): Json<UpdateGroupRequest>,
+) -> Result<Json<Group>, (StatusCode, String)> {
+    services::groups::update_group(&state.db, &name, req)
+        .await
+        .map(Json)
+        .map_err(group_error)
+}
+
+async fn delete_group(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    services::groups::delete_group(&state.db, &name)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .map_err(group_error)
+}
@@ -0,0 +1,62 @@
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{services, services::import::ImportProgress, AppState};
+
+pub fn routes() -> Router<Arc<AppState>> {
+    let max_upload_mb: usize = std::env::var("IMPORT_MAX_UPLOAD_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100);
+
+    Router::new()
+        .route("/", post(start_import).layer(DefaultBodyLimit::max(max_upload_mb * 1024 * 1024)))
+        .route("/:id", get(get_import))
+}
+
+/// Multipart upload with one or more `file` fields (mbox, `.eml` or `.zip`)
+/// and optional repeated `label` fields applied to every imported email.
+async fn start_import(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<ImportProgress>), (StatusCode, String)> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let mut messages = Vec::new();
+    let mut labels = Vec::new();
+
+    while let Some(field) = multipart.next_field().await.map_err(|e| bad_request(e.to_string()))? {
+        match field.name() {
+            Some("label") => labels.push(field.text().await.map_err(|e| bad_request(e.to_string()))?),
+            Some("file") => {
+                let file_name = field.file_name().unwrap_or("upload.mbox").to_string();
+                let data = field.bytes().await.map_err(|e| bad_request(e.to_string()))?;
+                messages.extend(
+                    services::import::read_upload(&file_name, data.to_vec()).map_err(|e| bad_request(e.to_string()))?,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    if messages.is_empty() {
+        return Err(bad_request("No messages found in upload".into()));
+    }
+    if labels.is_empty() {
+        labels.push("INBOX".into());
+    }
+
+    let progress = services::import::start_job(state.db.clone(), messages, labels);
+    Ok((StatusCode::ACCEPTED, Json(progress)))
+}
+
+async fn get_import(Path(id): Path<Uuid>) -> Result<Json<ImportProgress>, (StatusCode, String)> {
+    services::import::job(id)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Import not found".to_string()))
+}
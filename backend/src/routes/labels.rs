@@ -1,20 +1,33 @@
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    routing::{delete, get, post},
+    routing::{get, patch},
     Json, Router,
 };
 use std::sync::Arc;
 
 use crate::{
-    models::{CreateLabelRequest, Label},
+    models::{CreateLabelRequest, Label, UndoToken, UpdateLabelRequest},
     services, AppState,
 };
 
 pub fn routes() -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(list_labels).post(create_label))
-        .route("/:name", delete(delete_label))
+        .route("/:name", patch(update_label).delete(delete_label))
+}
+
+fn label_error(e: anyhow::Error) -> (StatusCode, String) {
+    let message = e.to_string();
+    if message.starts_with("Invalid") || message.starts_with("Cannot") {
+        (StatusCode::BAD_REQUEST, message)
+    } else if message == "Label not found" {
+        (StatusCode::NOT_FOUND, message)
+    } else if message == "Label already exists" {
+        (StatusCode::CONFLICT, message)
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
 }
 
 async fn list_labels(
@@ -33,15 +46,28 @@ async fn create_label(
     services::labels::create_label(&state.db, req)
         .await
         .map(|l| (StatusCode::CREATED, Json(l)))
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map_err(label_error)
+}
+
+/// Rename (moving nested labels along), recolor, toggle AI exclusion or
+/// change visibility settings.
+async fn update_label(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(req): Json<UpdateLabelRequest>,
+) -> Result<Json<Label>, (StatusCode, String)> {
+    services::labels::update_label(&state.db, &name, req)
+        .await
+        .map(Json)
+        .map_err(label_error)
 }
 
 async fn delete_label(
     State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<Json<UndoToken>, (StatusCode, String)> {
     services::labels::delete_label(&state.db, &name)
         .await
-        .map(|_| StatusCode::NO_CONTENT)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map(Json)
+        .map_err(label_error)
 }
@@ -1,16 +1,61 @@
+mod attachments;
+mod contacts;
 mod emails;
+mod export;
+mod groups;
+mod imports;
 mod labels;
 mod threads;
+mod undo;
 pub mod ai;
 
-use axum::Router;
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    Router,
+};
 use std::sync::Arc;
-use crate::AppState;
+use crate::{services, AppState};
+
+/// Caller identity. With `API_KEYS` set (`key=user,...`) callers must send
+/// `Authorization: Bearer <key>` and act as that key's user. Without it the
+/// API is single-user and every caller is the mailbox owner (`MAIL_FROM`).
+pub struct UserId(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UserId {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Ok(keys) = std::env::var("API_KEYS") else {
+            return Ok(UserId(services::mail::sender().email));
+        };
+        let key = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+            .unwrap_or_default();
+        keys.split(',')
+            .filter_map(|entry| entry.trim().split_once('='))
+            .find(|(k, _)| !key.is_empty() && k.trim() == key)
+            .map(|(_, user)| UserId(user.trim().to_string()))
+            .ok_or((StatusCode::UNAUTHORIZED, "Missing or invalid API key".to_string()))
+    }
+}
 
 pub fn api_routes() -> Router<Arc<AppState>> {
     Router::new()
         .nest("/emails", emails::routes())
+        .nest("/attachments", attachments::routes())
         .nest("/threads", threads::routes())
+        .nest("/contacts", contacts::routes())
+        .nest("/groups", groups::routes())
         .nest("/labels", labels::routes())
+        .nest("/imports", imports::routes())
+        .nest("/export", export::routes())
+        .nest("/undo", undo::routes())
         .nest("/ai", ai::routes())
 }
@@ -1,16 +1,38 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    routing::get,
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Json, Router,
 };
 use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::{models::EmailThread, services, AppState};
+use crate::{
+    models::{
+        EmailQuery, EmailThread, MergeThreadRequest, ThreadEmailRequest, ThreadListResponse, UpdateThreadRequest,
+    },
+    services, AppState,
+};
 
 pub fn routes() -> Router<Arc<AppState>> {
-    Router::new().route("/:id", get(get_thread))
+    Router::new()
+        .route("/", get(list_threads))
+        .route("/:id", get(get_thread).patch(update_thread).delete(delete_thread))
+        .route("/:id/emails", post(move_email))
+        .route("/:id/split", post(split_thread))
+        .route("/:id/merge", post(merge_threads))
+}
+
+/// Conversation summaries; accepts the same filters as `GET /emails`.
+async fn list_threads(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EmailQuery>,
+) -> Result<Json<ThreadListResponse>, (StatusCode, String)> {
+    services::threads::list_threads(&state.db, query)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
 }
 
 async fn get_thread(
@@ -25,3 +47,81 @@ async fn get_thread(
             _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
         })
 }
+
+async fn update_thread(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<UpdateThreadRequest>,
+) -> Result<Json<EmailThread>, (StatusCode, String)> {
+    services::threads::update_thread(&state.db, id, req)
+        .await
+        .map(Json)
+        .map_err(|e| match e.to_string().as_str() {
+            "Thread not found" => (StatusCode::NOT_FOUND, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
+}
+
+/// Move the thread to TRASH (204). Deleting it from TRASH returns an undo token.
+async fn delete_thread(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, (StatusCode, String)> {
+    services::threads::delete_thread(&state.db, id)
+        .await
+        .map(|token| match token {
+            Some(token) => Json(token).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        })
+        .map_err(|e| match e.to_string().as_str() {
+            "Thread not found" => (StatusCode::NOT_FOUND, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
+}
+
+fn correction_error(e: anyhow::Error) -> (StatusCode, String) {
+    let message = e.to_string();
+    match message.as_str() {
+        "Thread not found" | "Email not found" => (StatusCode::NOT_FOUND, message),
+        "Email is not in this thread"
+        | "Cannot split a thread at its first message"
+        | "Cannot merge a thread with itself" => (StatusCode::BAD_REQUEST, message),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, message),
+    }
+}
+
+/// Move an email into this thread.
+async fn move_email(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<ThreadEmailRequest>,
+) -> Result<Json<EmailThread>, (StatusCode, String)> {
+    services::threads::move_email(&state.db, id, req.email_id)
+        .await
+        .map(Json)
+        .map_err(correction_error)
+}
+
+/// Split the thread at an email; returns the new thread.
+async fn split_thread(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<ThreadEmailRequest>,
+) -> Result<(StatusCode, Json<EmailThread>), (StatusCode, String)> {
+    services::threads::split_thread(&state.db, id, req.email_id)
+        .await
+        .map(|thread| (StatusCode::CREATED, Json(thread)))
+        .map_err(correction_error)
+}
+
+/// Merge another thread into this one.
+async fn merge_threads(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<MergeThreadRequest>,
+) -> Result<Json<EmailThread>, (StatusCode, String)> {
+    services::threads::merge_threads(&state.db, id, req.thread_id)
+        .await
+        .map(Json)
+        .map_err(correction_error)
+}
@@ -0,0 +1,30 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::post,
+    Json, Router,
+};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{models::RestoreSummary, services, AppState};
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/:token", post(restore))
+}
+
+/// Restore what a deletion removed, with its labels, thread and contacts.
+async fn restore(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<Uuid>,
+) -> Result<Json<RestoreSummary>, (StatusCode, String)> {
+    services::undo::restore(&state.db, token)
+        .await
+        .map(Json)
+        .map_err(|e| match e.to_string().as_str() {
+            "Undo token not found" => (StatusCode::NOT_FOUND, e.to_string()),
+            "Undo token has expired" => (StatusCode::GONE, e.to_string()),
+            "Label already exists" => (StatusCode::CONFLICT, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
+}
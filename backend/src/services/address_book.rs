@@ -0,0 +1,391 @@
+use anyhow::{anyhow, Result};
+
+use crate::models::ContactInfo;
+
+/// One person from an imported address book.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContactRecord {
+    pub name: Option<String>,
+    pub emails: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressBookFormat {
+    /// vCard 3.0 on export; 3.0 and 4.0 on import.
+    VCard,
+    Csv,
+}
+
+impl AddressBookFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "vcard" | "vcf" => Ok(AddressBookFormat::VCard),
+            "csv" => Ok(AddressBookFormat::Csv),
+            other => Err(anyhow!("Unknown contacts format: {}", other)),
+        }
+    }
+
+    /// Format of an uploaded file, by extension or else by content.
+    pub fn detect(file_name: &str, text: &str) -> Self {
+        let lower = file_name.to_lowercase();
+        if lower.ends_with(".vcf") || lower.ends_with(".vcard") {
+            AddressBookFormat::VCard
+        } else if lower.ends_with(".csv") {
+            AddressBookFormat::Csv
+        } else if text.trim_start().to_uppercase().starts_with("BEGIN:VCARD") {
+            AddressBookFormat::VCard
+        } else {
+            AddressBookFormat::Csv
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AddressBookFormat::VCard => "text/vcard; charset=utf-8",
+            AddressBookFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            AddressBookFormat::VCard => "contacts.vcf",
+            AddressBookFormat::Csv => "contacts.csv",
+        }
+    }
+}
+
+pub fn read(format: AddressBookFormat, text: &str) -> Result<Vec<ContactRecord>> {
+    match format {
+        AddressBookFormat::VCard => Ok(read_vcards(text)),
+        AddressBookFormat::Csv => read_csv(text),
+    }
+}
+
+pub fn write(format: AddressBookFormat, contacts: &[ContactInfo]) -> String {
+    match format {
+        AddressBookFormat::VCard => write_vcards(contacts),
+        AddressBookFormat::Csv => write_csv(contacts),
+    }
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
+}
+
+/// Unfolded content lines: continuation lines start with a space or tab.
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Split a content line into its property name (without group or
+/// parameters) and raw value.
+fn property(line: &str) -> Option<(String, &str)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let name = line[..colon].split(';').next().unwrap_or_default();
+    let name = name.rsplit('.').next().unwrap_or_default().to_uppercase();
+    Some((name, &line[colon + 1..]))
+}
+
+fn read_vcards(text: &str) -> Vec<ContactRecord> {
+    let mut records = Vec::new();
+    let mut current: Option<(ContactRecord, Option<String>)> = None;
+
+    for line in unfold(text) {
+        let Some((name, value)) = property(&line) else {
+            continue;
+        };
+        match name.as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VCARD") => current = Some(Default::default()),
+            "END" if value.eq_ignore_ascii_case("VCARD") => {
+                if let Some((mut record, structured)) = current.take() {
+                    record.name = record.name.or(structured);
+                    records.push(record);
+                }
+            }
+            "FN" => {
+                if let Some((record, _)) = current.as_mut() {
+                    record.name = Some(unescape(value).trim().to_string()).filter(|n| !n.is_empty());
+                }
+            }
+            "N" => {
+                if let Some((_, structured)) = current.as_mut() {
+                    // N:Family;Given;Additional;Prefix;Suffix
+                    let parts: Vec<String> = value.split(';').map(unescape).collect();
+                    let given = parts.get(1).map(String::as_str).unwrap_or_default();
+                    let family = parts.first().map(String::as_str).unwrap_or_default();
+                    let full = format!("{} {}", given.trim(), family.trim()).trim().to_string();
+                    *structured = Some(full).filter(|n| !n.is_empty());
+                }
+            }
+            "EMAIL" => {
+                if let Some((record, _)) = current.as_mut() {
+                    let value = unescape(value);
+                    let value = value.trim();
+                    let email = match value.get(..7) {
+                        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &value[7..],
+                        _ => value,
+                    };
+                    if !email.is_empty() {
+                        record.emails.push(email.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    records
+}
+
+/// Append a content line, folded so no line exceeds 75 octets.
+fn push_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn write_vcards(contacts: &[ContactInfo]) -> String {
+    let mut out = String::new();
+    for contact in contacts {
+        let name = contact.name.as_deref().unwrap_or(&contact.email);
+        push_line(&mut out, "BEGIN:VCARD");
+        push_line(&mut out, "VERSION:3.0");
+        push_line(&mut out, &format!("FN:{}", escape(name)));
+        push_line(&mut out, &format!("N:;{};;;", escape(name)));
+        push_line(&mut out, &format!("EMAIL;TYPE=INTERNET,PREF:{}", contact.email));
+        for alias in &contact.aliases {
+            push_line(&mut out, &format!("EMAIL;TYPE=INTERNET:{}", alias));
+        }
+        push_line(&mut out, &format!("X-EMAIL-COUNT:{}", contact.email_count));
+        if let Some(last) = &contact.last_contacted {
+            push_line(&mut out, &format!("X-LAST-CONTACTED:{}", last));
+        }
+        push_line(&mut out, "END:VCARD");
+    }
+    out
+}
+
+/// RFC 4180 rows: quoted fields may contain commas, quotes and newlines.
+fn csv_rows(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows.retain(|r| r.iter().any(|f| !f.trim().is_empty()));
+    rows
+}
+
+fn read_csv(text: &str) -> Result<Vec<ContactRecord>> {
+    let mut rows = csv_rows(text).into_iter();
+    let header: Vec<String> = rows
+        .next()
+        .ok_or_else(|| anyhow!("Invalid CSV: empty file"))?
+        .iter()
+        .map(|h| h.trim().to_lowercase())
+        .collect();
+
+    let column = |names: &[&str]| header.iter().position(|h| names.contains(&h.as_str()));
+    let full = column(&["name", "full name", "display name", "fn"]);
+    let first = column(&["first name", "given name"]);
+    let last = column(&["last name", "family name", "surname"]);
+    // "E-mail Address", "E-mail 1 - Value", "other_emails", but not
+    // "E-mail Type", "E-mail Display Name" or "email_count"
+    let email_columns: Vec<usize> = header
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| h.contains("mail") && !["type", "label", "display", "count"].iter().any(|x| h.contains(x)))
+        .map(|(i, _)| i)
+        .collect();
+    if email_columns.is_empty() {
+        return Err(anyhow!("Invalid CSV: no email column"));
+    }
+
+    let cell = |row: &[String], i: Option<usize>| {
+        i.and_then(|i| row.get(i)).map(|v| v.trim().to_string()).unwrap_or_default()
+    };
+    Ok(rows
+        .map(|row| {
+            let name = match cell(&row, full) {
+                n if !n.is_empty() => n,
+                _ => format!("{} {}", cell(&row, first), cell(&row, last)).trim().to_string(),
+            };
+            let emails = email_columns
+                .iter()
+                .flat_map(|&i| {
+                    // Google joins multiple values with " ::: "
+                    cell(&row, Some(i))
+                        .split(":::")
+                        .flat_map(|v| v.split(';'))
+                        .map(|v| v.trim().to_string())
+                        .filter(|v| !v.is_empty())
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            ContactRecord { name: Some(name).filter(|n| !n.is_empty()), emails }
+        })
+        .collect())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(contacts: &[ContactInfo]) -> String {
+    let mut out = String::from("name,email,other_emails,email_count,last_contacted\r\n");
+    for contact in contacts {
+        let fields = [
+            csv_field(contact.name.as_deref().unwrap_or_default()),
+            csv_field(&contact.email),
+            csv_field(&contact.aliases.join(";")),
+            contact.email_count.to_string(),
+            csv_field(contact.last_contacted.as_deref().unwrap_or_default()),
+        ];
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: Option<&str>, emails: &[&str]) -> ContactRecord {
+        ContactRecord {
+            name: name.map(Into::into),
+            emails: emails.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn reads_vcard_3_and_4() {
+        let text = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Ada Lovelace\\, Countess\r\nN:Lovelace;Ada;;;\r\n\
+                    item1.EMAIL;TYPE=INTERNET,PREF:ada@example.com\r\nEMAIL;TYPE=WORK:ada@analytical\r\n .engine\r\n\
+                    END:VCARD\r\n\
+                    BEGIN:VCARD\nVERSION:4.0\nN:Babbage;Charles;;;\nEMAIL;TYPE=\"work,pref\":mailto:charles@example.com\nEND:VCARD\n\
+                    BEGIN:VCARD\nVERSION:4.0\nFN:Nobody\nEND:VCARD\n";
+
+        assert_eq!(
+            read(AddressBookFormat::VCard, text).unwrap(),
+            vec![
+                record(Some("Ada Lovelace, Countess"), &["ada@example.com", "ada@analytical.engine"]),
+                record(Some("Charles Babbage"), &["charles@example.com"]),
+                record(Some("Nobody"), &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_csv_exports() {
+        let google = "\u{feff}Name,Given Name,Family Name,E-mail 1 - Type,E-mail 1 - Value,E-mail 2 - Value\n\
+                      \"Lovelace, Ada\",,,* Home,ada@example.com ::: ada@home.example,ada@work.example\n\
+                      ,Charles,Babbage,,charles@example.com,\n";
+        assert_eq!(
+            read(AddressBookFormat::Csv, google).unwrap(),
+            vec![
+                record(Some("Lovelace, Ada"), &["ada@example.com", "ada@home.example", "ada@work.example"]),
+                record(Some("Charles Babbage"), &["charles@example.com"]),
+            ]
+        );
+
+        let outlook = "First Name,Last Name,E-mail Address,E-mail Display Name\r\nGrace,Hopper,grace@example.com,\"Grace \"\"Amazing\"\" Hopper\"\r\n";
+        assert_eq!(
+            read(AddressBookFormat::Csv, outlook).unwrap(),
+            vec![record(Some("Grace Hopper"), &["grace@example.com"])]
+        );
+
+        assert!(read(AddressBookFormat::Csv, "name,phone\nAda,123\n").is_err());
+    }
+
+    #[test]
+    fn exports_round_trip() {
+        let contacts = vec![ContactInfo {
+            email: "ada@example.com".into(),
+            name: Some("Lovelace; Ada, \"the first\" programmer with a rather long display name".into()),
+            email_count: 12,
+            last_contacted: Some("2024-03-01T10:00:00+00:00".into()),
+            aliases: vec!["ada@home.example".into(), "ada@work.example".into()],
+        }];
+        let expected = vec![record(
+            contacts[0].name.as_deref(),
+            &["ada@example.com", "ada@home.example", "ada@work.example"],
+        )];
+
+        let vcard = write(AddressBookFormat::VCard, &contacts);
+        assert!(vcard.lines().all(|l| l.len() <= 75));
+        assert!(vcard.contains("X-EMAIL-COUNT:12\r\n"));
+        assert_eq!(read(AddressBookFormat::VCard, &vcard).unwrap(), expected);
+
+        let csv = write(AddressBookFormat::Csv, &contacts);
+        assert!(csv.contains(",12,2024-03-01T10:00:00+00:00\r\n"));
+        assert_eq!(read(AddressBookFormat::Csv, &csv).unwrap(), expected);
+    }
+}
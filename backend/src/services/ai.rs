@@ -1,37 +1,99 @@
 use anyhow::Result;
 use neo4rs::{query, Graph};
 use uuid::Uuid;
-use async_openai::{
-    Client,
-    config::OpenAIConfig,
-    types::{
-        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
-        CreateEmbeddingRequestArgs, EmbeddingInput,
-    },
-};
 
+use crate::models::Attachment;
 use crate::routes::ai::{CategorizeRequest, ComposeRequest, SearchRequest, SearchResult, SummarizeRequest};
+use crate::services::attachments::attachment_from_node;
 use crate::services::emails::get_email;
+use crate::services::prompts::{Prompts, RenderedPrompt};
+use crate::services::providers::{AiClient, Completion, ProviderError};
+use crate::services::redaction::{self, RedactionSession, Redactor};
+use crate::services::{labels, usage};
 
-fn get_openai_client() -> Option<Client<OpenAIConfig>> {
-    if std::env::var("OPENAI_API_KEY").is_ok() {
-        Some(Client::new())
-    } else {
-        None
+const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// Characters of attachment text sent for embedding, within the model's input limit.
+const ATTACHMENT_EMBED_CHARS: usize = 24_000;
+
+/// What the AI features run on: the provider client, redaction rules and
+/// prompt templates. Built once at startup and shared through `AppState`.
+pub struct AiEnv {
+    /// `None` when no provider is configured and callers use heuristics.
+    pub client: Option<AiClient>,
+    pub redactor: Redactor,
+    pub prompts: Prompts,
+}
+
+impl AiEnv {
+    pub fn from_env() -> Self {
+        AiEnv { client: AiClient::from_env(), redactor: Redactor::load(), prompts: Prompts::from_env() }
     }
 }
 
-/// Generate embeddings for text using OpenAI
-pub async fn generate_embedding(text: &str) -> Result<Vec<f32>> {
-    if let Some(client) = get_openai_client() {
-        let request = CreateEmbeddingRequestArgs::default()
-            .model("text-embedding-3-small")
-            .input(EmbeddingInput::String(text.to_string()))
-            .build()?;
-        
-        let response = client.embeddings().create(request).await?;
-        let embedding: Vec<f32> = response.data[0].embedding.iter().map(|&x| x as f32).collect();
-        Ok(embedding)
+/// Result of an AI operation together with how it was produced.
+pub struct AiOutput<T> {
+    pub value: T,
+    /// Template (`name@vN`) that produced the output; `None` for heuristic fallbacks.
+    pub prompt: Option<String>,
+    /// Set when the primary provider failed and a fallback produced the output.
+    pub degraded: bool,
+}
+
+impl<T> AiOutput<T> {
+    fn heuristic(value: T, degraded: bool) -> Self {
+        AiOutput { value, prompt: None, degraded }
+    }
+}
+
+/// Turn a provider failure into a degraded-mode fallback; other errors
+/// (quota, database) still propagate.
+fn fall_back_on(e: anyhow::Error) -> Result<bool> {
+    match e.downcast_ref::<ProviderError>() {
+        Some(provider_error) => {
+            tracing::warn!("AI providers unavailable, using heuristic fallback: {}", provider_error);
+            Ok(true)
+        }
+        None => Err(e),
+    }
+}
+
+/// The AI client, unless label policy forbids sending any of these emails
+/// to an external model, in which case callers use their local fallback.
+async fn client_for<'a>(graph: &Graph, ai: &'a AiEnv, email_ids: &[Uuid]) -> Result<Option<&'a AiClient>> {
+    let Some(client) = ai.client.as_ref() else {
+        return Ok(None);
+    };
+    if redaction::is_ai_blocked(graph, email_ids).await? {
+        tracing::info!("AI label policy excludes {:?}, using local fallback", email_ids);
+        return Ok(None);
+    }
+    Ok(Some(client))
+}
+
+/// Give back quota held for a failed call; the hold lapses with the day anyway.
+async fn release_quietly(graph: &Graph, reservation: &usage::Reservation) {
+    if let Err(e) = usage::release(graph, reservation).await {
+        tracing::warn!("Failed to release AI quota: {}", e);
+    }
+}
+
+/// Generate embeddings for text using OpenAI, charged to `user` under `feature`
+pub async fn generate_embedding(graph: &Graph, ai: &AiEnv, user: &str, feature: &str, text: &str) -> Result<Vec<f32>> {
+    if let Some(client) = &ai.client {
+        let reservation = usage::reserve(graph, user, feature, EMBEDDING_MODEL).await?;
+
+        let embedding = match client.embed(EMBEDDING_MODEL, text).await {
+            Ok(embedding) => embedding,
+            Err(e) => {
+                release_quietly(graph, &reservation).await;
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = usage::record(graph, &reservation, &embedding.model, None, embedding.usage).await {
+            tracing::warn!("Failed to record AI usage: {}", e);
+        }
+        Ok(embedding.vector)
     } else {
         // Fallback: return a zero vector (for testing without API key)
         Ok(vec![0.0; 1536])
@@ -51,118 +113,243 @@ pub async fn store_email_embedding(graph: &Graph, email_id: Uuid, embedding: &[f
     Ok(())
 }
 
-/// Index an email by generating and storing its embedding
-pub async fn index_email(graph: &Graph, email_id: Uuid) -> Result<()> {
+/// Index an email by generating and storing its embedding, and those of its
+/// attachments whose text has been extracted
+pub async fn index_email(graph: &Graph, ai: &AiEnv, user: &str, email_id: Uuid) -> Result<()> {
+    if redaction::is_ai_blocked(graph, &[email_id]).await? {
+        // Drop any embedding computed before the policy applied
+        graph.run(
+            query(r#"
+                MATCH (e:Email {id: $id})
+                REMOVE e.embedding
+                WITH e
+                OPTIONAL MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+                REMOVE a.embedding
+            "#)
+                .param("id", email_id.to_string())
+        ).await?;
+        return Ok(());
+    }
+
     let email = get_email(graph, email_id).await?;
     let text = format!("{}\n\n{}", email.subject, email.body);
-    let embedding = generate_embedding(&text).await?;
+    let text = ai.redactor.session().redact(&text);
+    let embedding = generate_embedding(graph, ai, user, "index", &text).await?;
     store_email_embedding(graph, email_id, &embedding).await?;
+
+    for attachment in email.attachments {
+        index_attachment(graph, ai, user, attachment.id).await?;
+    }
+    Ok(())
+}
+
+/// Embed an attachment's extracted text. Attachments without text, or
+/// attached to an email excluded from AI, are skipped.
+pub async fn index_attachment(graph: &Graph, ai: &AiEnv, user: &str, attachment_id: Uuid) -> Result<()> {
+    let mut result = graph
+        .execute(
+            query("MATCH (e:Email)-[:HAS_ATTACHMENT]->(:Attachment {id: $id}) RETURN e.id as id")
+                .param("id", attachment_id.to_string()),
+        )
+        .await?;
+    let mut email_ids = Vec::new();
+    while let Some(row) = result.next().await? {
+        if let Ok(id) = Uuid::parse_str(&row.get::<String>("id")?) {
+            email_ids.push(id);
+        }
+    }
+    if redaction::is_ai_blocked(graph, &email_ids).await? {
+        return Ok(());
+    }
+
+    let cypher = r#"
+        MATCH (a:Attachment {id: $id})
+        WHERE a.text IS NOT NULL AND a.text <> ''
+        RETURN a.filename as filename, a.text as text
+    "#;
+    let mut result = graph.execute(query(cypher).param("id", attachment_id.to_string())).await?;
+    let Some(row) = result.next().await? else {
+        return Ok(());
+    };
+
+    let attachment_text: String = row.get("text").unwrap_or_default();
+    let text = format!(
+        "{}\n\n{}",
+        row.get::<String>("filename").unwrap_or_default(),
+        attachment_text.chars().take(ATTACHMENT_EMBED_CHARS).collect::<String>()
+    );
+    let text = ai.redactor.session().redact(&text);
+    let embedding = generate_embedding(graph, ai, user, "index", &text).await?;
+
+    graph.run(
+        query("MATCH (a:Attachment {id: $id}) SET a.embedding = $embedding")
+            .param("id", attachment_id.to_string())
+            .param("embedding", serde_json::to_string(&embedding)?)
+    ).await?;
     Ok(())
 }
 
-pub async fn summarize(graph: &Graph, req: SummarizeRequest) -> Result<String> {
-    let text = if let Some(text) = req.text {
-        text
+/// Send a rendered prompt as a single user message and return the reply.
+/// The call is checked against and charged to `user`'s quota for `feature`.
+async fn complete(
+    graph: &Graph,
+    user: &str,
+    feature: &str,
+    client: &AiClient,
+    prompt: &RenderedPrompt,
+) -> Result<Completion> {
+    let reservation = usage::reserve(graph, user, feature, &prompt.model).await?;
+
+    let completion = match client.chat(&prompt.model, &prompt.text).await {
+        Ok(completion) => completion,
+        Err(e) => {
+            release_quietly(graph, &reservation).await;
+            return Err(e.into());
+        }
+    };
+
+    if let Err(e) = usage::record(graph, &reservation, &completion.model, Some(&prompt.template), completion.usage).await {
+        tracing::warn!("Failed to record AI usage: {}", e);
+    }
+
+    tracing::info!(
+        target: "ai::prompts",
+        template = %prompt.template,
+        model = %completion.model,
+        degraded = completion.degraded,
+        "Generated AI output"
+    );
+    Ok(completion)
+}
+
+pub async fn summarize(graph: &Graph, ai: &AiEnv, user: &str, req: SummarizeRequest) -> Result<AiOutput<String>> {
+    let (text, email_ids) = if let Some(text) = req.text {
+        (text, vec![])
     } else if let Some(email_id) = req.email_id {
         let email = get_email(graph, email_id).await?;
-        format!("Subject: {}\n\n{}", email.subject, email.body)
+        (format!("Subject: {}\n\n{}", email.subject, email.body), vec![email_id])
     } else if let Some(thread_id) = req.thread_id {
         let thread = crate::services::threads::get_thread(graph, thread_id).await?;
-        thread.emails
+        let text = thread.emails
             .iter()
             .map(|e| format!("From: {}\nSubject: {}\n{}\n---", e.from.email, e.subject, e.body))
             .collect::<Vec<_>>()
-            .join("\n")
+            .join("\n");
+        (text, thread.emails.iter().map(|e| e.id).collect())
     } else {
         return Err(anyhow::anyhow!("No content to summarize"));
     };
 
-    if let Some(client) = get_openai_client() {
-        let request = CreateChatCompletionRequestArgs::default()
-            .model("gpt-4o-mini")
-            .messages([
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content(format!(
-                        "Summarize this email in 2-3 concise sentences. Focus on the key points and any action items:\n\n{}",
-                        text
-                    ))
-                    .build()?
-                    .into(),
-            ])
-            .build()?;
-
-        let response = client.chat().create(request).await?;
-        let summary = response.choices[0]
-            .message
-            .content
-            .clone()
-            .unwrap_or_else(|| "Unable to generate summary.".to_string());
-        
-        Ok(summary)
+    let mut degraded = false;
+
+    if let Some(client) = client_for(graph, ai, &email_ids).await? {
+        let mut redaction = ai.redactor.session();
+        let prompt = summarize_prompt(&ai.prompts, &mut redaction, &text, req.prompt_version)?;
+        match complete(graph, user, "summarize", client, &prompt).await {
+            Ok(completion) => {
+                let summary = parse_summary(&redaction.restore(&completion.text));
+                return Ok(AiOutput { value: summary, prompt: Some(prompt.template), degraded: completion.degraded });
+            }
+            Err(e) => degraded = fall_back_on(e)?,
+        }
+    }
+
+    // Fallback without API key or when providers are down
+    Ok(AiOutput::heuristic(heuristic_summary(&text), degraded))
+}
+
+fn summarize_prompt(
+    prompts: &Prompts,
+    redaction: &mut RedactionSession,
+    text: &str,
+    version: Option<u32>,
+) -> Result<RenderedPrompt> {
+    prompts.render("summarize", version, &[("text", &redaction.redact(text))])
+}
+
+fn parse_summary(content: &str) -> String {
+    if content.is_empty() {
+        "Unable to generate summary.".to_string()
     } else {
-        // Fallback without API key
-        let summary = if text.len() > 200 {
-            format!("Summary: {}...", &text[..200])
-        } else {
-            format!("Summary: {}", text)
-        };
-        Ok(summary)
+        content.to_string()
     }
 }
 
-pub async fn smart_compose(_graph: &Graph, req: ComposeRequest) -> Result<Vec<String>> {
-    if let Some(client) = get_openai_client() {
-        let context = req.context.unwrap_or_default();
-        let prompt = req.prompt.unwrap_or_else(|| "general business email".to_string());
-        
-        let request = CreateChatCompletionRequestArgs::default()
-            .model("gpt-4o-mini")
-            .messages([
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content(format!(
-                        "Generate 3 different professional email reply suggestions for the following context. Each suggestion should be a complete, ready-to-send response.\n\nContext/Topic: {}\n\nPrevious message (if any): {}\n\nProvide exactly 3 suggestions, separated by '---'",
-                        prompt, context
-                    ))
-                    .build()?
-                    .into(),
-            ])
-            .build()?;
-
-        let response = client.chat().create(request).await?;
-        let content = response.choices[0]
-            .message
-            .content
-            .clone()
-            .unwrap_or_default();
-        
-        let suggestions: Vec<String> = content
-            .split("---")
-            .map(|s: &str| s.trim().to_string())
-            .filter(|s: &String| !s.is_empty())
-            .take(3)
-            .collect();
-        
-        if suggestions.is_empty() {
-            Ok(vec![content])
-        } else {
-            Ok(suggestions)
+fn heuristic_summary(text: &str) -> String {
+    match text.char_indices().nth(200) {
+        Some((end, _)) => format!("Summary: {}...", &text[..end]),
+        None => format!("Summary: {}", text),
+    }
+}
+
+pub async fn smart_compose(graph: &Graph, ai: &AiEnv, user: &str, req: ComposeRequest) -> Result<AiOutput<Vec<String>>> {
+    let email_ids: Vec<Uuid> = req.reply_to.into_iter().collect();
+    let mut degraded = false;
+
+    if let Some(client) = client_for(graph, ai, &email_ids).await? {
+        let context = match (req.context.clone(), req.reply_to) {
+            (Some(context), _) => context,
+            (None, Some(reply_to)) => {
+                let email = get_email(graph, reply_to).await?;
+                format!("From: {}\nSubject: {}\n\n{}", email.from.email, email.subject, email.body)
+            }
+            (None, None) => String::new(),
+        };
+        let topic = req.prompt.clone().unwrap_or_else(|| "general business email".to_string());
+
+        let mut redaction = ai.redactor.session();
+        let prompt = compose_prompt(&ai.prompts, &mut redaction, &topic, &context, req.prompt_version)?;
+        match complete(graph, user, "compose", client, &prompt).await {
+            Ok(completion) => {
+                let suggestions = parse_suggestions(&redaction.restore(&completion.text));
+                return Ok(AiOutput { value: suggestions, prompt: Some(prompt.template), degraded: completion.degraded });
+            }
+            Err(e) => degraded = fall_back_on(e)?,
         }
+    }
+
+    // Fallback without API key or when providers are down
+    Ok(AiOutput::heuristic(heuristic_suggestions(req.prompt.as_deref()), degraded))
+}
+
+fn compose_prompt(
+    prompts: &Prompts,
+    redaction: &mut RedactionSession,
+    topic: &str,
+    context: &str,
+    version: Option<u32>,
+) -> Result<RenderedPrompt> {
+    prompts.render(
+        "compose",
+        version,
+        &[("prompt", &redaction.redact(topic)), ("context", &redaction.redact(context))],
+    )
+}
+
+fn parse_suggestions(content: &str) -> Vec<String> {
+    let suggestions: Vec<String> = content
+        .split("---")
+        .map(|s: &str| s.trim().to_string())
+        .filter(|s: &String| !s.is_empty())
+        .take(3)
+        .collect();
+
+    if suggestions.is_empty() { vec![content.to_string()] } else { suggestions }
+}
+
+fn heuristic_suggestions(topic: Option<&str>) -> Vec<String> {
+    if let Some(prompt) = topic {
+        vec![
+            format!("Thank you for your message about {}. I'll review and get back to you shortly.", prompt),
+            format!("I appreciate you reaching out regarding {}. Let me look into this.", prompt),
+            format!("Thanks for the update on {}. I'll follow up with more details soon.", prompt),
+        ]
     } else {
-        // Fallback without API key
-        let suggestions = if let Some(prompt) = req.prompt {
-            vec![
-                format!("Thank you for your message about {}. I'll review and get back to you shortly.", prompt),
-                format!("I appreciate you reaching out regarding {}. Let me look into this.", prompt),
-                format!("Thanks for the update on {}. I'll follow up with more details soon.", prompt),
-            ]
-        } else {
-            vec![
-                "Thank you for your email. I'll get back to you as soon as possible.".into(),
-                "I appreciate you reaching out. Let me review this and follow up.".into(),
-                "Thanks for the message. I'll look into this and respond shortly.".into(),
-            ]
-        };
-        Ok(suggestions)
+        vec![
+            "Thank you for your email. I'll get back to you as soon as possible.".into(),
+            "I appreciate you reaching out. Let me review this and follow up.".into(),
+            "Thanks for the message. I'll look into this and respond shortly.".into(),
+        ]
     }
 }
 
@@ -178,60 +365,89 @@ fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     }
 }
 
-pub async fn semantic_search(graph: &Graph, req: SearchRequest) -> Result<Vec<SearchResult>> {
+/// Score candidates against the query embedding and keep the top `limit`,
+/// one per email (its best match, be it the email or an attachment).
+fn rank_by_similarity(query: &[f32], candidates: Vec<(SearchResult, Vec<f32>)>, limit: usize) -> Vec<SearchResult> {
+    let mut results: Vec<SearchResult> = candidates
+        .into_iter()
+        .map(|(mut result, embedding)| {
+            result.score = cosine_similarity(query, &embedding);
+            result
+        })
+        .collect();
+
+    // Sort by score descending
+    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    let mut seen = std::collections::HashSet::new();
+    results.retain(|r| seen.insert(r.email_id));
+    results.truncate(limit);
+    results
+}
+
+fn attachment_in(row: &neo4rs::Row) -> Option<Attachment> {
+    row.get::<neo4rs::Node>("attachment").ok().and_then(attachment_from_node)
+}
+
+pub async fn semantic_search(graph: &Graph, ai: &AiEnv, user: &str, req: SearchRequest) -> Result<AiOutput<Vec<SearchResult>>> {
     // Generate embedding for query
-    let query_embedding = generate_embedding(&req.query).await?;
+    let redacted_query = ai.redactor.session().redact(&req.query);
+    let (query_embedding, degraded) = match generate_embedding(graph, ai, user, "search", &redacted_query).await {
+        Ok(embedding) => (embedding, false),
+        Err(e) => (vec![], fall_back_on(e)?),
+    };
     
     // If we have a real embedding, do vector search
     if query_embedding.iter().any(|&x| x != 0.0) {
-        // Fetch all emails with embeddings
+        // Fetch all emails and attachments with embeddings
         let cypher = r#"
             MATCH (e:Email)
-            WHERE e.embedding IS NOT NULL
-            RETURN e.id as id, e.subject as subject, e.snippet as snippet, e.embedding as embedding
+            WHERE e.embedding IS NOT NULL AND NOT EXISTS { MATCH (e)-[:HAS_LABEL]->(h:Label) WHERE h.name IN $hidden }
+            RETURN e.id as id, e.subject as subject, e.snippet as snippet, e.embedding as embedding, null as attachment
+            UNION ALL
+            MATCH (e:Email)-[:HAS_ATTACHMENT]->(a:Attachment)
+            WHERE a.embedding IS NOT NULL AND NOT EXISTS { MATCH (e)-[:HAS_LABEL]->(h:Label) WHERE h.name IN $hidden }
+            RETURN e.id as id, e.subject as subject, e.snippet as snippet, a.embedding as embedding, a as attachment
         "#;
         
-        let mut result = graph.execute(query(cypher)).await?;
-        let mut results_with_scores: Vec<(SearchResult, f32)> = Vec::new();
+        let mut result = graph
+            .execute(query(cypher).param("hidden", labels::HIDDEN_LABELS.to_vec()))
+            .await?;
+        let mut candidates: Vec<(SearchResult, Vec<f32>)> = Vec::new();
         
         while let Some(row) = result.next().await? {
             let id_str: String = row.get("id")?;
             let embedding_json: String = row.get("embedding").unwrap_or_default();
             
             if let Ok(email_embedding) = serde_json::from_str::<Vec<f32>>(&embedding_json) {
-                let score = cosine_similarity(&query_embedding, &email_embedding);
-                
-                results_with_scores.push((
+                candidates.push((
                     SearchResult {
                         email_id: Uuid::parse_str(&id_str).unwrap_or_default(),
                         subject: row.get("subject").unwrap_or_default(),
                         snippet: row.get("snippet").unwrap_or_default(),
-                        score,
+                        score: 0.0,
+                        attachment: attachment_in(&row),
                     },
-                    score,
+                    email_embedding,
                 ));
             }
         }
         
-        // Sort by score descending
-        results_with_scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-        
-        // Take top N results
-        let results: Vec<SearchResult> = results_with_scores
-            .into_iter()
-            .take(req.limit)
-            .map(|(r, _)| r)
-            .collect();
-        
-        return Ok(results);
+        let results = rank_by_similarity(&query_embedding, candidates, req.limit);
+        // Model-ranked, but no prompt template is involved
+        return Ok(AiOutput { value: results, prompt: None, degraded });
     }
     
-    // Fallback: basic text search
+    // Fallback: basic text search over emails and attachment text
     let cypher = r#"
         MATCH (e:Email)
+        WHERE NOT EXISTS { MATCH (e)-[:HAS_LABEL]->(h:Label) WHERE h.name IN $hidden }
+        OPTIONAL MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+        WHERE toLower(a.text) CONTAINS toLower($query)
+        WITH e, head(collect(a)) as attachment
         WHERE toLower(e.subject) CONTAINS toLower($query) 
            OR toLower(e.body) CONTAINS toLower($query)
-        RETURN e.id as id, e.subject as subject, e.snippet as snippet
+           OR attachment IS NOT NULL
+        RETURN e.id as id, e.subject as subject, e.snippet as snippet, attachment
         LIMIT $limit
     "#;
 
@@ -239,6 +455,7 @@ pub async fn semantic_search(graph: &Graph, req: SearchRequest) -> Result<Vec<Se
         query(cypher)
             .param("query", req.query)
             .param("limit", req.limit as i64)
+            .param("hidden", labels::HIDDEN_LABELS.to_vec())
     ).await?;
 
     let mut results = Vec::new();
@@ -249,110 +466,132 @@ pub async fn semantic_search(graph: &Graph, req: SearchRequest) -> Result<Vec<Se
             subject: row.get("subject").unwrap_or_default(),
             snippet: row.get("snippet").unwrap_or_default(),
             score: 1.0,
+            attachment: attachment_in(&row),
         });
     }
 
-    Ok(results)
+    Ok(AiOutput::heuristic(results, degraded))
 }
 
-pub async fn categorize(graph: &Graph, req: CategorizeRequest) -> Result<(Vec<String>, String)> {
+pub async fn categorize(graph: &Graph, ai: &AiEnv, user: &str, req: CategorizeRequest) -> Result<AiOutput<(Vec<String>, String)>> {
     let email = get_email(graph, req.email_id).await?;
     
-    if let Some(client) = get_openai_client() {
-        let request = CreateChatCompletionRequestArgs::default()
-            .model("gpt-4o-mini")
-            .messages([
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content(format!(
-                        r#"Analyze this email and suggest:
-1. Appropriate labels (comma-separated list from: Work, Personal, Finance, Travel, Shopping, Social, Newsletters, Promotions, Updates, Important)
-2. Priority level (high, medium, or low)
-
-Email Subject: {}
-Email Body: {}
-
-Respond in this exact format:
-LABELS: label1, label2
-PRIORITY: level"#,
-                        email.subject, email.body
-                    ))
-                    .build()?
-                    .into(),
-            ])
-            .build()?;
-
-        let response = client.chat().create(request).await?;
-        let content = response.choices[0]
-            .message
-            .content
-            .clone()
-            .unwrap_or_default();
-        
-        // Parse response
-        let mut labels = vec!["INBOX".to_string()];
-        let mut priority = "medium".to_string();
-        
-        for line in content.lines() {
-            let line_str: &str = line;
-            if line_str.starts_with("LABELS:") {
-                let label_str = line_str.trim_start_matches("LABELS:").trim();
-                labels.extend(
-                    label_str
-                        .split(',')
-                        .map(|s: &str| s.trim().to_string())
-                        .filter(|s: &String| !s.is_empty())
-                );
-            } else if line_str.starts_with("PRIORITY:") {
-                priority = line_str.trim_start_matches("PRIORITY:").trim().to_lowercase();
+    let mut degraded = false;
+
+    if let Some(client) = client_for(graph, ai, &[email.id]).await? {
+        let mut redaction = ai.redactor.session();
+        let prompt = categorize_prompt(&ai.prompts, &mut redaction, &email.subject, &email.body, req.prompt_version)?;
+        match complete(graph, user, "categorize", client, &prompt).await {
+            Ok(completion) => {
+                return Ok(AiOutput {
+                    value: parse_categorization(&completion.text),
+                    prompt: Some(prompt.template),
+                    degraded: completion.degraded,
+                });
             }
+            Err(e) => degraded = fall_back_on(e)?,
         }
-        
-        Ok((labels, priority))
-    } else {
-        // Fallback: simple heuristics
-        let mut labels = vec!["INBOX".to_string()];
-        let priority;
-
-        let subject_lower = email.subject.to_lowercase();
-        let body_lower = email.body.to_lowercase();
-
-        if subject_lower.contains("urgent") || subject_lower.contains("asap") || subject_lower.contains("important") {
-            labels.push("IMPORTANT".to_string());
-            priority = "high".to_string();
-        } else if subject_lower.contains("newsletter") || body_lower.contains("unsubscribe") {
-            labels.push("Newsletters".to_string());
-            priority = "low".to_string();
-        } else if subject_lower.contains("meeting") || subject_lower.contains("calendar") || subject_lower.contains("invite") {
-            labels.push("Work".to_string());
-            priority = "medium".to_string();
-        } else if subject_lower.contains("order") || subject_lower.contains("shipping") || subject_lower.contains("delivery") {
-            labels.push("Shopping".to_string());
-            priority = "low".to_string();
-        } else {
-            priority = "medium".to_string();
+    }
+
+    // Fallback: simple heuristics
+    Ok(AiOutput::heuristic(heuristic_categorization(&email.subject, &email.body), degraded))
+}
+
+fn categorize_prompt(
+    prompts: &Prompts,
+    redaction: &mut RedactionSession,
+    subject: &str,
+    body: &str,
+    version: Option<u32>,
+) -> Result<RenderedPrompt> {
+    prompts.render(
+        "categorize",
+        version,
+        &[("subject", &redaction.redact(subject)), ("body", &redaction.redact(body))],
+    )
+}
+
+fn parse_categorization(content: &str) -> (Vec<String>, String) {
+    let mut labels = vec!["INBOX".to_string()];
+    let mut priority = "medium".to_string();
+
+    for line in content.lines() {
+        let line_str: &str = line;
+        if line_str.starts_with("LABELS:") {
+            let label_str = line_str.trim_start_matches("LABELS:").trim();
+            labels.extend(
+                label_str
+                    .split(',')
+                    .map(|s: &str| s.trim().to_string())
+                    .filter(|s: &String| !s.is_empty())
+            );
+        } else if line_str.starts_with("PRIORITY:") {
+            priority = line_str.trim_start_matches("PRIORITY:").trim().to_lowercase();
         }
+    }
+
+    (labels, priority)
+}
 
-        Ok((labels, priority))
+fn heuristic_categorization(subject: &str, body: &str) -> (Vec<String>, String) {
+    let mut labels = vec!["INBOX".to_string()];
+    let priority;
+
+    let subject_lower = subject.to_lowercase();
+    let body_lower = body.to_lowercase();
+
+    if subject_lower.contains("urgent") || subject_lower.contains("asap") || subject_lower.contains("important") {
+        labels.push("IMPORTANT".to_string());
+        priority = "high".to_string();
+    } else if subject_lower.contains("newsletter") || body_lower.contains("unsubscribe") {
+        labels.push("Newsletters".to_string());
+        priority = "low".to_string();
+    } else if subject_lower.contains("meeting") || subject_lower.contains("calendar") || subject_lower.contains("invite") {
+        labels.push("Work".to_string());
+        priority = "medium".to_string();
+    } else if subject_lower.contains("order") || subject_lower.contains("shipping") || subject_lower.contains("delivery") {
+        labels.push("Shopping".to_string());
+        priority = "low".to_string();
+    } else {
+        priority = "medium".to_string();
     }
+
+    (labels, priority)
 }
 
-/// Batch index all unindexed emails
-pub async fn batch_index_emails(graph: &Graph) -> Result<usize> {
+/// Batch index all unindexed emails, and emails with newly extracted attachments
+pub async fn batch_index_emails(graph: &Graph, ai: &AiEnv, user: &str) -> Result<usize> {
     let cypher = r#"
         MATCH (e:Email)
-        WHERE e.embedding IS NULL
+        WHERE (e.embedding IS NULL
+               OR EXISTS {
+                   MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+                   WHERE a.text <> '' AND a.embedding IS NULL
+               })
+          AND NOT EXISTS {
+              MATCH (e)-[:HAS_LABEL]->(l:Label)
+              WHERE l.ai_excluded = true OR l.name IN $excluded
+          }
         RETURN e.id as id
         LIMIT 100
     "#;
     
-    let mut result = graph.execute(query(cypher)).await?;
+    let mut result = graph
+        .execute(query(cypher).param("excluded", redaction::env_excluded_labels()))
+        .await?;
     let mut count = 0;
     
     while let Some(row) = result.next().await? {
         let id_str: String = row.get("id")?;
         if let Ok(id) = Uuid::parse_str(&id_str) {
-            if index_email(graph, id).await.is_ok() {
-                count += 1;
+            match index_email(graph, ai, user, id).await {
+                Ok(()) => count += 1,
+                // Stop early rather than failing every remaining email the same way
+                Err(e) if e.is::<usage::QuotaError>() || e.is::<ProviderError>() => {
+                    tracing::warn!("Stopping batch index: {}", e);
+                    break;
+                }
+                Err(_) => {}
             }
         }
     }
@@ -360,3 +599,176 @@ pub async fn batch_index_emails(graph: &Graph) -> Result<usize> {
     tracing::info!("Indexed {} emails", count);
     Ok(count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::db;
+    use crate::services::prompts::PromptRegistry;
+    use crate::services::providers::{ReplayProvider, RetryPolicy};
+    use std::sync::Arc;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/ai");
+
+    #[test]
+    fn heuristic_summary_truncates_on_char_boundaries() {
+        let text = "é".repeat(250);
+        assert_eq!(heuristic_summary(&text), format!("Summary: {}...", "é".repeat(200)));
+        assert_eq!(heuristic_summary("short"), "Summary: short");
+    }
+
+    /// Recorded responses, the built-in redaction kinds and prompt templates,
+    /// independent of the environment the tests run in.
+    fn replay_env() -> AiEnv {
+        AiEnv {
+            client: Some(AiClient::new(vec![Arc::new(ReplayProvider::replay(FIXTURES))], RetryPolicy::default())),
+            redactor: Redactor::from_config(true, "email,iban,card,phone", None).unwrap(),
+            prompts: Prompts::new(PromptRegistry::load_from(None, None).unwrap()),
+        }
+    }
+
+    fn replay_client(env: &AiEnv) -> &AiClient {
+        env.client.as_ref().unwrap()
+    }
+
+    fn result(subject: &str, embedding: Vec<f32>) -> (SearchResult, Vec<f32>) {
+        let search_result = SearchResult {
+            email_id: Uuid::new_v4(),
+            subject: subject.to_string(),
+            snippet: String::new(),
+            score: 0.0,
+            attachment: None,
+        };
+        (search_result, embedding)
+    }
+
+    #[tokio::test]
+    async fn summarize_redacts_and_restores_addresses() {
+        let text = "Subject: Contract renewal\n\nPlease send the signed contract to alice@example.com by Friday.";
+        let env = replay_env();
+        let mut redaction = env.redactor.session();
+        let prompt = summarize_prompt(&env.prompts, &mut redaction, text, None).unwrap();
+        assert!(!prompt.text.contains("alice@example.com"));
+        assert_eq!(prompt.template, "summarize@v1");
+
+        let completion = replay_client(&env).chat(&prompt.model, &prompt.text).await.unwrap();
+        let summary = parse_summary(&redaction.restore(&completion.text));
+
+        assert!(summary.contains("alice@example.com"));
+        assert!(!completion.degraded);
+    }
+
+    #[tokio::test]
+    async fn summarize_runs_end_to_end_against_fixtures() {
+        let Some(graph) = db::test_graph().await else { return };
+        let user = format!("test-{}", Uuid::new_v4());
+        let req = SummarizeRequest {
+            email_id: None,
+            thread_id: None,
+            text: Some("Subject: Contract renewal\n\nPlease send the signed contract to alice@example.com by Friday.".into()),
+            prompt_version: None,
+        };
+
+        let output = summarize(&graph, &replay_env(), &user, req).await.unwrap();
+
+        assert!(output.value.contains("alice@example.com"));
+        assert_eq!(output.prompt.as_deref(), Some("summarize@v1"));
+        assert!(!output.degraded);
+
+        graph
+            .run(query("MATCH (n) WHERE (n:AiQuota OR n:AiUsage) AND n.user = $user DELETE n").param("user", user))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn compose_splits_three_suggestions() {
+        let env = replay_env();
+        let mut redaction = env.redactor.session();
+        let prompt = compose_prompt(&env.prompts, &mut redaction, "quarterly budget review", "", None).unwrap();
+
+        let completion = replay_client(&env).chat(&prompt.model, &prompt.text).await.unwrap();
+        let suggestions = parse_suggestions(&redaction.restore(&completion.text));
+
+        assert_eq!(suggestions.len(), 3);
+        assert!(suggestions.iter().all(|s| s.contains("budget")));
+    }
+
+    #[tokio::test]
+    async fn categorize_parses_labels_and_priority() {
+        let env = replay_env();
+        let mut redaction = env.redactor.session();
+        let prompt = categorize_prompt(
+            &env.prompts,
+            &mut redaction,
+            "Urgent: invoice 4471 overdue",
+            "Your invoice is 30 days overdue. Please pay today.",
+            None,
+        )
+        .unwrap();
+
+        let completion = replay_client(&env).chat(&prompt.model, &prompt.text).await.unwrap();
+        let (labels, priority) = parse_categorization(&completion.text);
+
+        assert_eq!(labels, vec!["INBOX", "Finance", "Important"]);
+        assert_eq!(priority, "high");
+    }
+
+    #[tokio::test]
+    async fn search_ranks_by_embedding_similarity() {
+        let query = replay_client(&replay_env())
+            .embed(EMBEDDING_MODEL, "contract termination clause")
+            .await
+            .unwrap();
+
+        let ranked = rank_by_similarity(
+            &query.vector,
+            vec![
+                result("Lunch on Friday?", vec![0.0, 0.1, 0.9, 0.0]),
+                result("Contract with 90-day termination", vec![0.9, 0.1, 0.0, 0.0]),
+                result("Renewal terms", vec![0.5, 0.5, 0.1, 0.0]),
+            ],
+            2,
+        );
+
+        let subjects: Vec<&str> = ranked.iter().map(|r| r.subject.as_str()).collect();
+        assert_eq!(subjects, vec!["Contract with 90-day termination", "Renewal terms"]);
+        assert!(ranked[0].score > ranked[1].score);
+    }
+
+    #[test]
+    fn ranking_keeps_the_best_match_per_email() {
+        let (email, email_embedding) = result("Signed contract", vec![0.2, 0.8]);
+        let mut attachment = SearchResult { score: 0.0, ..email.clone() };
+        attachment.attachment = Some(Attachment {
+            id: Uuid::new_v4(),
+            filename: "contract.pdf".into(),
+            content_type: "application/pdf".into(),
+            size: 1024,
+            sha256: String::new(),
+        });
+
+        let ranked = rank_by_similarity(
+            &[1.0, 0.0],
+            vec![(email, email_embedding), (attachment, vec![0.9, 0.1]), result("Other", vec![0.5, 0.5])],
+            10,
+        );
+
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].attachment.as_ref().unwrap().filename, "contract.pdf");
+    }
+
+    #[tokio::test]
+    async fn missing_fixture_is_a_permanent_error() {
+        let err = replay_client(&replay_env()).chat("gpt-4o-mini", "no fixture for this prompt").await.err().unwrap();
+        assert!(!err.is_transient());
+        assert!(err.to_string().contains("No AI fixture"));
+    }
+
+    #[test]
+    fn heuristic_categorization_flags_urgent_mail() {
+        let (labels, priority) = heuristic_categorization("URGENT: server down", "");
+        assert_eq!(labels, vec!["INBOX", "IMPORTANT"]);
+        assert_eq!(priority, "high");
+    }
+}
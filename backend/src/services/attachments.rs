@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Result};
+use lettre::message::header::ContentType;
+use neo4rs::{query, Graph};
+use uuid::Uuid;
+
+use crate::models::Attachment;
+use crate::services::{blobs, poller};
+use crate::services::mime::OutgoingAttachment;
+
+pub(crate) fn attachment_from_node(n: neo4rs::Node) -> Option<Attachment> {
+    Some(Attachment {
+        id: Uuid::parse_str(&n.get::<String>("id").ok()?).ok()?,
+        filename: n.get("filename").unwrap_or_default(),
+        content_type: content_type_or_default(&n.get::<String>("content_type").unwrap_or_default()),
+        size: n.get::<i64>("size").unwrap_or(0) as u64,
+        sha256: n.get("sha256").unwrap_or_default(),
+    })
+}
+
+/// `value` if it is a well-formed media type, otherwise
+/// `application/octet-stream`, so a client-supplied type can be served back as
+/// a header as is.
+pub(crate) fn content_type_or_default(value: &str) -> String {
+    let value = value.trim();
+    match ContentType::parse(value) {
+        Ok(_) if value.is_ascii() && !value.chars().any(|c| c.is_ascii_control()) => value.to_string(),
+        _ => "application/octet-stream".to_string(),
+    }
+}
+
+/// Store the content and create an `:Attachment` node for it, linked to
+/// `email` when given. Uploads made while composing are linked at send time.
+pub async fn store(
+    graph: &Graph,
+    email: Option<Uuid>,
+    filename: &str,
+    content_type: &str,
+    data: &[u8],
+) -> Result<Attachment> {
+    let attachment = Attachment {
+        id: Uuid::new_v4(),
+        filename: filename.to_string(),
+        content_type: content_type_or_default(content_type),
+        size: data.len() as u64,
+        sha256: blobs::put(data).await?,
+    };
+
+    let cypher = r#"
+        CREATE (a:Attachment {
+            id: $id,
+            filename: $filename,
+            content_type: $content_type,
+            size: $size,
+            sha256: $sha256,
+            created_at: $created_at
+        })
+        WITH a
+        OPTIONAL MATCH (e:Email {id: $email_id})
+        FOREACH (_ IN CASE WHEN e IS NULL THEN [] ELSE [1] END | CREATE (e)-[:HAS_ATTACHMENT]->(a))
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("id", attachment.id.to_string())
+                .param("filename", attachment.filename.clone())
+                .param("content_type", attachment.content_type.clone())
+                .param("size", attachment.size as i64)
+                .param("sha256", attachment.sha256.clone())
+                .param("created_at", chrono::Utc::now().to_rfc3339())
+                .param("email_id", email.map(|id| id.to_string()).unwrap_or_default()),
+        )
+        .await?;
+
+    Ok(attachment)
+}
+
+pub async fn get(graph: &Graph, id: Uuid) -> Result<Attachment> {
+    let mut result = graph
+        .execute(query("MATCH (a:Attachment {id: $id}) RETURN a").param("id", id.to_string()))
+        .await?;
+
+    match result.next().await? {
+        Some(row) => attachment_from_node(row.get("a")?).ok_or_else(|| anyhow!("Attachment not found")),
+        None => Err(anyhow!("Attachment not found")),
+    }
+}
+
+/// Link previously uploaded attachments to an email.
+pub async fn link(graph: &Graph, email: Uuid, ids: &[Uuid]) -> Result<()> {
+    let cypher = r#"
+        MATCH (e:Email {id: $email_id}), (a:Attachment)
+        WHERE a.id IN $ids
+        MERGE (e)-[:HAS_ATTACHMENT]->(a)
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("email_id", email.to_string())
+                .param("ids", ids.iter().map(Uuid::to_string).collect::<Vec<_>>()),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Load stored attachments for placing them in an outgoing message.
+pub async fn outgoing(attachments: &[Attachment]) -> Result<Vec<OutgoingAttachment>> {
+    let mut outgoing = Vec::new();
+    for attachment in attachments {
+        outgoing.push(OutgoingAttachment {
+            filename: attachment.filename.clone(),
+            content_type: ContentType::parse(&attachment.content_type)
+                .unwrap_or(ContentType::parse("application/octet-stream")?),
+            data: blobs::get(&attachment.sha256).await?,
+        });
+    }
+    Ok(outgoing)
+}
+
+/// Remove attachment nodes no email refers to any more, counting deleted
+/// emails that can still be restored. Blobs are kept since other attachments
+/// may share them.
+pub async fn delete_orphans(graph: &Graph, ids: &[Uuid]) -> Result<()> {
+    let cypher = r#"
+        MATCH (a:Attachment)
+        WHERE a.id IN $ids AND NOT EXISTS { MATCH (a)<-[:HAS_ATTACHMENT]-() }
+        DETACH DELETE a
+    "#;
+    graph
+        .run(query(cypher).param("ids", ids.iter().map(Uuid::to_string).collect::<Vec<_>>()))
+        .await?;
+    Ok(())
+}
+
+/// Attachments removed per sweep round.
+const ORPHAN_BATCH: i64 = 100;
+
+/// Remove uploads never linked to an email once they are older than
+/// `ATTACHMENT_ORPHAN_HOURS` (default 24), e.g. from abandoned drafts.
+pub async fn purge_unlinked(graph: &Graph) -> Result<usize> {
+    let hours = std::env::var("ATTACHMENT_ORPHAN_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(24);
+    let cutoff = chrono::Utc::now() - chrono::Duration::hours(hours);
+
+    let cypher = r#"
+        MATCH (a:Attachment)
+        WHERE a.created_at < $cutoff AND NOT EXISTS { MATCH (a)<-[:HAS_ATTACHMENT]-() }
+        WITH a LIMIT $limit
+        DETACH DELETE a
+        RETURN count(*) as purged
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("cutoff", cutoff.to_rfc3339()).param("limit", ORPHAN_BATCH))
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get::<i64>("purged").unwrap_or(0) as usize,
+        None => 0,
+    })
+}
+
+/// Sweep unlinked uploads in the background, checking every
+/// `ATTACHMENT_ORPHAN_POLL_SECS` (default 3600).
+pub fn spawn_worker(graph: Graph) {
+    poller::spawn_poller(
+        "Attachment sweep",
+        "ATTACHMENT_ORPHAN_POLL_SECS",
+        3600,
+        Some(ORPHAN_BATCH as usize),
+        move || {
+            let graph = graph.clone();
+            async move { purge_unlinked(&graph).await }
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_octet_stream_for_malformed_types() {
+        assert_eq!(content_type_or_default("image/png"), "image/png");
+        assert_eq!(content_type_or_default(" text/plain; charset=utf-8 "), "text/plain; charset=utf-8");
+        assert_eq!(content_type_or_default(""), "application/octet-stream");
+        assert_eq!(content_type_or_default("not a type"), "application/octet-stream");
+        assert_eq!(content_type_or_default("text/html\r\nX-Injected: 1"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Root of the content-addressed store, from `BLOB_DIR` (default `blobs`).
+fn root() -> PathBuf {
+    std::env::var("BLOB_DIR").unwrap_or_else(|_| "blobs".into()).into()
+}
+
+/// Lowercase hex SHA-256 of some content.
+pub fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+/// Where a blob lives: `<root>/ab/abcdef...`, fanned out on the first byte.
+pub fn path(hash: &str) -> Result<PathBuf> {
+    path_in(&root(), hash)
+}
+
+fn path_in(root: &Path, hash: &str) -> Result<PathBuf> {
+    if !valid_hash(hash) {
+        return Err(anyhow!("Invalid blob hash: {}", hash));
+    }
+    Ok(root.join(&hash[..2]).join(hash))
+}
+
+/// Store content and return its hash. Content that is already stored is not
+/// written again.
+pub async fn put(data: &[u8]) -> Result<String> {
+    put_in(&root(), data).await
+}
+
+async fn put_in(root: &Path, data: &[u8]) -> Result<String> {
+    let hash = sha256(data);
+    let path = path_in(root, &hash)?;
+    if tokio::fs::try_exists(&path).await? {
+        return Ok(hash);
+    }
+
+    // Write to a temporary name first so readers never see a partial blob
+    let dir = path.parent().expect("blob path has a parent");
+    tokio::fs::create_dir_all(dir).await?;
+    let tmp = dir.join(format!(".{}.tmp", Uuid::new_v4().simple()));
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+    Ok(hash)
+}
+
+pub async fn get(hash: &str) -> Result<Vec<u8>> {
+    get_from(&root(), hash).await
+}
+
+async fn get_from(root: &Path, hash: &str) -> Result<Vec<u8>> {
+    tokio::fs::read(path_in(root, hash)?)
+        .await
+        .map_err(|_| anyhow!("Blob not found: {}", hash))
+}
+
+/// Inclusive byte range requested by an HTTP `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Parse a single-range `bytes=` header against a blob of `size` bytes.
+    /// `Ok(None)` means the header should be ignored and the whole blob sent;
+    /// an error means the range cannot be satisfied.
+    pub fn parse(header: &str, size: u64) -> Result<Option<Self>> {
+        let Some(spec) = header.trim().strip_prefix("bytes=") else {
+            return Ok(None);
+        };
+        // Multiple ranges are allowed to be answered with the full content
+        if spec.contains(',') {
+            return Ok(None);
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Ok(None);
+        };
+
+        let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+            // Suffix range: the last `end` bytes
+            (None, Some(suffix)) if start.is_empty() && suffix > 0 => ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size.saturating_sub(1),
+            },
+            (Some(start), None) if end.is_empty() => ByteRange { start, end: size.saturating_sub(1) },
+            (Some(start), Some(end)) if start <= end => ByteRange { start, end: end.min(size.saturating_sub(1)) },
+            _ => return Ok(None),
+        };
+
+        if size == 0 || range.start >= size {
+            return Err(anyhow!("Range not satisfiable"));
+        }
+        Ok(Some(range))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_content_once_by_hash() {
+        let dir = std::env::temp_dir().join(format!("blobs-{}", Uuid::new_v4()));
+
+        let first = put_in(&dir, b"hello").await.unwrap();
+        let second = put_in(&dir, b"hello").await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(get_from(&dir, &first).await.unwrap(), b"hello");
+        assert_eq!(std::fs::read_dir(dir.join("2c")).unwrap().count(), 1);
+        assert!(path_in(&dir, "../../etc/passwd").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_range_headers() {
+        let range = |h| ByteRange::parse(h, 100);
+        assert_eq!(range("bytes=0-9").unwrap(), Some(ByteRange { start: 0, end: 9 }));
+        assert_eq!(range("bytes=90-").unwrap(), Some(ByteRange { start: 90, end: 99 }));
+        assert_eq!(range("bytes=-10").unwrap(), Some(ByteRange { start: 90, end: 99 }));
+        assert_eq!(range("bytes=50-500").unwrap(), Some(ByteRange { start: 50, end: 99 }));
+        assert_eq!(range("bytes=0-1,5-6").unwrap(), None);
+        assert_eq!(range("items=0-1").unwrap(), None);
+        assert!(range("bytes=100-").is_err());
+    }
+}
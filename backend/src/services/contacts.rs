@@ -0,0 +1,482 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use neo4rs::{query, Graph};
+use std::collections::HashMap;
+
+use crate::models::{
+    AutocompleteQuery, ContactImportSummary, ContactInfo, ContactListResponse, ContactQuery, CreateContactRequest,
+    UpdateContactRequest,
+};
+use crate::services::address_book::ContactRecord;
+use crate::services::{db::run_atomically, emails::parse_date, mail, mime};
+
+/// Days after which the recency part of an autocomplete score halves.
+const RECENCY_HALF_LIFE_DAYS: f64 = 30.0;
+
+/// Contacts considered before ranking autocomplete suggestions.
+const AUTOCOMPLETE_CANDIDATES: i64 = 200;
+
+/// Contacts that are not an alias of another contact.
+pub(crate) const PRIMARY: &str = "NOT EXISTS { MATCH (c)-[:ALIAS_OF]->(:Contact) }";
+
+/// Aggregates `aliases`, `email_count` and `last_contacted` for each matched
+/// `c`, counting email to any of its addresses.
+pub(crate) const STATS: &str = r#"
+    OPTIONAL MATCH (alias:Contact)-[:ALIAS_OF]->(c)
+    WITH c, collect(alias) as alias_nodes
+    UNWIND [c] + alias_nodes as address
+    OPTIONAL MATCH (address)<-[:SENT_BY|SENT_TO|CC]-(e:Email)
+    WITH c, [a IN alias_nodes | a.email] as aliases,
+         count(DISTINCT e) as email_count, max(e.date) as last_contacted
+"#;
+
+pub(crate) const RETURN_INFO: &str =
+    "RETURN c.email as email, c.name as name, aliases, email_count, last_contacted";
+
+pub(crate) fn info_from_row(row: &neo4rs::Row) -> Result<ContactInfo> {
+    let mut aliases: Vec<String> = row.get("aliases").unwrap_or_default();
+    aliases.sort();
+    Ok(ContactInfo {
+        email: row.get("email")?,
+        name: row.get("name").ok(),
+        email_count: row.get::<i64>("email_count").unwrap_or(0) as u64,
+        last_contacted: row.get("last_contacted").ok(),
+        aliases,
+    })
+}
+
+/// Lowercased search term, or `None` when blank.
+fn search_term(q: Option<&str>) -> Option<String> {
+    q.map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty())
+}
+
+pub async fn list_contacts(graph: &Graph, params: ContactQuery) -> Result<ContactListResponse> {
+    let limit = params.limit.clamp(1, 200);
+    let page = params.page.max(1);
+    let skip = ((page - 1) * limit) as i64;
+    let q = search_term(params.q.as_deref());
+    let matches = format!(
+        r#"
+        MATCH (c:Contact)
+        WHERE {}
+          AND ($q IS NULL OR toLower(c.email) CONTAINS $q OR toLower(coalesce(c.name, '')) CONTAINS $q
+               OR EXISTS {{ MATCH (a:Contact)-[:ALIAS_OF]->(c) WHERE toLower(a.email) CONTAINS $q }})
+        "#,
+        PRIMARY
+    );
+
+    let cypher = format!(
+        "{}{}{} ORDER BY email_count DESC, c.email SKIP $skip LIMIT $limit",
+        matches, STATS, RETURN_INFO
+    );
+    let mut result = graph
+        .execute(
+            query(&cypher)
+                .param("q", q.clone())
+                .param("skip", skip)
+                .param("limit", limit as i64),
+        )
+        .await?;
+
+    let mut contacts = Vec::new();
+    while let Some(row) = result.next().await? {
+        contacts.push(info_from_row(&row)?);
+    }
+
+    let count_cypher = format!("{} RETURN count(c) as total", matches);
+    let mut count_result = graph.execute(query(&count_cypher).param("q", q)).await?;
+    let total = match count_result.next().await? {
+        Some(row) => row.get::<i64>("total").unwrap_or(0) as u64,
+        None => 0,
+    };
+
+    Ok(ContactListResponse { contacts, total, page, limit })
+}
+
+/// Every primary contact with its stats, for export.
+pub async fn all_contacts(graph: &Graph) -> Result<Vec<ContactInfo>> {
+    let cypher = format!("MATCH (c:Contact) WHERE {}{}{} ORDER BY c.email", PRIMARY, STATS, RETURN_INFO);
+    let mut result = graph.execute(query(&cypher)).await?;
+    let mut contacts = Vec::new();
+    while let Some(row) = result.next().await? {
+        contacts.push(info_from_row(&row)?);
+    }
+    Ok(contacts)
+}
+
+/// Resolves `$email` to its contact identity as `c`.
+const RESOLVE: &str = r#"
+    MATCH (x:Contact {email: $email})
+    OPTIONAL MATCH (x)-[:ALIAS_OF]->(p:Contact)
+    WITH coalesce(p, x) as c
+"#;
+
+/// Look a contact up by any of its addresses.
+pub async fn get_contact(graph: &Graph, email: &str) -> Result<ContactInfo> {
+    let cypher = format!("{}{}{}", RESOLVE, STATS, RETURN_INFO);
+    let mut result = graph.execute(query(&cypher).param("email", email)).await?;
+    match result.next().await? {
+        Some(row) => info_from_row(&row),
+        None => Err(anyhow!("Contact not found")),
+    }
+}
+
+async fn contact_exists(graph: &Graph, email: &str) -> Result<bool> {
+    let mut result = graph
+        .execute(query("MATCH (c:Contact {email: $email}) RETURN count(c) > 0 as exists").param("email", email))
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get("exists").unwrap_or(false),
+        None => false,
+    })
+}
+
+pub async fn create_contact(graph: &Graph, req: CreateContactRequest) -> Result<ContactInfo> {
+    let email = req.email.trim().to_string();
+    mime::validate_addresses(std::slice::from_ref(&email))?;
+    if contact_exists(graph, &email).await? {
+        return Err(anyhow!("Contact already exists"));
+    }
+
+    let name = req.name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    graph
+        .run(
+            query("CREATE (c:Contact {email: $email, name: $name})")
+                .param("email", email.clone())
+                .param("name", name),
+        )
+        .await?;
+    get_contact(graph, &email).await
+}
+
+pub async fn update_contact(graph: &Graph, email: &str, req: UpdateContactRequest) -> Result<ContactInfo> {
+    if !contact_exists(graph, email).await? {
+        return Err(anyhow!("Contact not found"));
+    }
+
+    let name = req.name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    graph
+        .run(
+            query(&format!("{} SET c.name = $name", RESOLVE))
+                .param("email", email)
+                .param("name", name),
+        )
+        .await?;
+    get_contact(graph, email).await
+}
+
+/// Fold `source` into `target`: its emails, deleted ones included so undo
+/// still restores them intact, point at `target`, which keeps its own name
+/// unless it has none, and `source` is removed.
+pub async fn merge_contacts(graph: &Graph, target: &str, source: &str) -> Result<ContactInfo> {
+    if target == source {
+        return Err(anyhow!("Cannot merge a contact with itself"));
+    }
+    if !contact_exists(graph, target).await? || !contact_exists(graph, source).await? {
+        return Err(anyhow!("Contact not found"));
+    }
+
+    let mut queries: Vec<neo4rs::Query> = ["SENT_BY", "SENT_TO", "CC"]
+        .iter()
+        .map(|rel| {
+            let cypher = format!(
+                r#"
+                MATCH (s:Contact {{email: $source}})<-[r:{rel}]-(e)
+                WHERE e:Email OR e:DeletedEmail
+                MATCH (t:Contact {{email: $target}})
+                MERGE (e)-[:{rel}]->(t)
+                DELETE r
+                "#,
+                rel = rel
+            );
+            query(&cypher).param("source", source).param("target", target)
+        })
+        .collect();
+    queries.push(
+        query(
+            r#"
+            MATCH (s:Contact {email: $source})-[r:MEMBER_OF]->(g:Group)
+            MATCH (t:Contact {email: $target})
+            MERGE (t)-[:MEMBER_OF]->(g)
+            DELETE r
+            "#,
+        )
+        .param("source", source)
+        .param("target", target),
+    );
+    queries.push(
+        query(
+            r#"
+            MATCH (a:Contact)-[r:ALIAS_OF]->(s:Contact {email: $source})
+            MATCH (t:Contact {email: $target})
+            DELETE r
+            WITH a, t
+            WHERE a <> t
+            MERGE (a)-[:ALIAS_OF]->(t)
+            "#,
+        )
+        .param("source", source)
+        .param("target", target),
+    );
+    queries.push(
+        query(
+            r#"
+            MATCH (s:Contact {email: $source}), (t:Contact {email: $target})
+            SET t.name = coalesce(t.name, s.name)
+            DETACH DELETE s
+            "#,
+        )
+        .param("source", source)
+        .param("target", target),
+    );
+
+    run_atomically(graph, queries).await?;
+    get_contact(graph, target).await
+}
+
+/// Queries making `primary` a contact identity with `aliases` as its other
+/// addresses. Aliases that were identities themselves bring their own aliases
+/// and group memberships.
+fn group_queries(primary: &str, aliases: &[String]) -> Vec<neo4rs::Query> {
+    vec![
+        query("MERGE (:Contact {email: $primary})").param("primary", primary),
+        query(
+            r#"
+            UNWIND $aliases as address
+            MATCH (sub:Contact)-[r:ALIAS_OF]->(:Contact {email: address})
+            MATCH (c:Contact {email: $primary})
+            DELETE r
+            WITH sub, c
+            WHERE sub <> c
+            MERGE (sub)-[:ALIAS_OF]->(c)
+            "#,
+        )
+        .param("primary", primary)
+        .param("aliases", aliases.to_vec()),
+        query(
+            r#"
+            UNWIND $aliases as address
+            MATCH (:Contact {email: address})-[r:MEMBER_OF]->(g:Group)
+            MATCH (c:Contact {email: $primary})
+            MERGE (c)-[:MEMBER_OF]->(g)
+            DELETE r
+            "#,
+        )
+        .param("primary", primary)
+        .param("aliases", aliases.to_vec()),
+        query(
+            r#"
+            UNWIND $aliases as address
+            MATCH (c:Contact {email: $primary})
+            MERGE (a:Contact {email: address})
+            WITH a, c
+            WHERE a <> c
+            OPTIONAL MATCH (a)-[r:ALIAS_OF]->()
+            DELETE r
+            WITH DISTINCT a, c
+            MERGE (a)-[:ALIAS_OF]->(c)
+            "#,
+        )
+        .param("primary", primary)
+        .param("aliases", aliases.to_vec()),
+    ]
+}
+
+/// Group another address under a contact, creating it if needed.
+pub async fn add_address(graph: &Graph, email: &str, address: &str) -> Result<ContactInfo> {
+    let address = address.trim().to_string();
+    mime::validate_addresses(std::slice::from_ref(&address))?;
+    let primary = get_contact(graph, email).await?.email;
+
+    run_atomically(graph, group_queries(&primary, &[address])).await?;
+    get_contact(graph, &primary).await
+}
+
+/// Split an address off a contact; it stays as a contact of its own.
+pub async fn remove_address(graph: &Graph, email: &str, address: &str) -> Result<ContactInfo> {
+    let primary = get_contact(graph, email).await?.email;
+    if primary == address {
+        return Err(anyhow!("Cannot remove the primary address"));
+    }
+
+    let cypher = r#"
+        MATCH (:Contact {email: $address})-[r:ALIAS_OF]->(:Contact {email: $primary})
+        DELETE r
+        RETURN count(r) as removed
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("address", address).param("primary", primary.clone()))
+        .await?;
+    let removed = match result.next().await? {
+        Some(row) => row.get::<i64>("removed").unwrap_or(0),
+        None => 0,
+    };
+    if removed == 0 {
+        return Err(anyhow!("Address not found"));
+    }
+    get_contact(graph, &primary).await
+}
+
+/// Create or update the contact identity owning any of `record`'s addresses,
+/// grouping all of them under it. Returns whether a new contact was created,
+/// or `None` when the record has no valid address.
+async fn upsert(graph: &Graph, record: ContactRecord) -> Result<Option<bool>> {
+    let mut emails: Vec<String> = Vec::new();
+    for email in record.emails {
+        let email = email.trim().to_string();
+        if !emails.contains(&email) && mime::validate_addresses(std::slice::from_ref(&email)).is_ok() {
+            emails.push(email);
+        }
+    }
+    if emails.is_empty() {
+        return Ok(None);
+    }
+
+    let cypher = r#"
+        UNWIND $emails as email
+        MATCH (x:Contact {email: email})
+        OPTIONAL MATCH (x)-[:ALIAS_OF]->(p:Contact)
+        RETURN email, coalesce(p.email, x.email) as primary
+    "#;
+    let mut result = graph.execute(query(cypher).param("emails", emails.clone())).await?;
+    let mut existing: HashMap<String, String> = HashMap::new();
+    while let Some(row) = result.next().await? {
+        existing.insert(row.get("email")?, row.get("primary")?);
+    }
+
+    let primary = emails
+        .iter()
+        .find_map(|e| existing.get(e).cloned())
+        .unwrap_or_else(|| emails[0].clone());
+    let mut aliases: Vec<String> = Vec::new();
+    for address in emails.iter().chain(existing.values()) {
+        if *address != primary && !aliases.contains(address) {
+            aliases.push(address.clone());
+        }
+    }
+
+    let mut queries = group_queries(&primary, &aliases);
+    if let Some(name) = record.name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty()) {
+        queries.push(
+            query("MATCH (c:Contact {email: $primary}) SET c.name = $name")
+                .param("primary", primary.clone())
+                .param("name", name),
+        );
+    }
+    run_atomically(graph, queries).await?;
+    Ok(Some(existing.is_empty()))
+}
+
+/// Upsert address book entries, merging by email.
+pub async fn import_contacts(graph: &Graph, records: Vec<ContactRecord>) -> Result<ContactImportSummary> {
+    let mut summary = ContactImportSummary::default();
+    for record in records {
+        match upsert(graph, record).await? {
+            Some(true) => summary.created += 1,
+            Some(false) => summary.updated += 1,
+            None => summary.skipped += 1,
+        }
+    }
+    Ok(summary)
+}
+
+/// Autocomplete score: interaction count on a log scale plus a recency bonus
+/// that halves every `RECENCY_HALF_LIFE_DAYS`.
+fn score(contact: &ContactInfo, now: DateTime<Utc>) -> f64 {
+    let frequency = (contact.email_count as f64).ln_1p();
+    let recency = parse_date(contact.last_contacted.clone())
+        .map(|last| {
+            let days = (now - last).num_seconds().max(0) as f64 / 86_400.0;
+            2.0 * 0.5f64.powf(days / RECENCY_HALF_LIFE_DAYS)
+        })
+        .unwrap_or(0.0);
+    frequency + recency
+}
+
+fn rank(mut contacts: Vec<ContactInfo>, now: DateTime<Utc>, limit: usize) -> Vec<ContactInfo> {
+    contacts.sort_by(|a, b| {
+        score(b, now)
+            .total_cmp(&score(a, now))
+            .then_with(|| a.email.cmp(&b.email))
+    });
+    contacts.truncate(limit);
+    contacts
+}
+
+/// Recipient suggestions whose address or any word of the name starts with
+/// `q`, best first. The mailbox's own address is never suggested.
+pub async fn autocomplete(graph: &Graph, params: AutocompleteQuery) -> Result<Vec<ContactInfo>> {
+    let Some(q) = search_term(Some(&params.q)) else {
+        return Ok(Vec::new());
+    };
+
+    let cypher = format!(
+        r#"
+        MATCH (c:Contact)
+        WHERE c.email <> $me AND {}
+          AND (toLower(c.email) STARTS WITH $q
+               OR toLower(coalesce(c.name, '')) STARTS WITH $q
+               OR toLower(coalesce(c.name, '')) CONTAINS (' ' + $q)
+               OR EXISTS {{ MATCH (a:Contact)-[:ALIAS_OF]->(c) WHERE toLower(a.email) STARTS WITH $q }})
+        {}{}
+        ORDER BY email_count DESC
+        LIMIT $candidates
+        "#,
+        PRIMARY, STATS, RETURN_INFO
+    );
+    let mut result = graph
+        .execute(
+            query(&cypher)
+                .param("q", q)
+                .param("me", mail::sender().email)
+                .param("candidates", AUTOCOMPLETE_CANDIDATES),
+        )
+        .await?;
+
+    let mut candidates = Vec::new();
+    while let Some(row) = result.next().await? {
+        candidates.push(info_from_row(&row)?);
+    }
+    Ok(rank(candidates, Utc::now(), params.limit.clamp(1, 50)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn contact(email: &str, email_count: u64, days_ago: Option<i64>, now: DateTime<Utc>) -> ContactInfo {
+        ContactInfo {
+            email: email.into(),
+            name: None,
+            email_count,
+            last_contacted: days_ago.map(|d| (now - Duration::days(d)).to_rfc3339()),
+            aliases: vec![],
+        }
+    }
+
+    #[test]
+    fn ranks_by_frequency_and_recency() {
+        let now = Utc::now();
+        let ranked = rank(
+            vec![
+                contact("never@example.com", 0, None, now),
+                contact("old-frequent@example.com", 40, Some(400), now),
+                contact("recent@example.com", 3, Some(1), now),
+                contact("stale@example.com", 3, Some(200), now),
+            ],
+            now,
+            3,
+        );
+        let emails: Vec<_> = ranked.iter().map(|c| c.email.as_str()).collect();
+        assert_eq!(emails, ["old-frequent@example.com", "recent@example.com", "stale@example.com"]);
+
+        // Equal counts: the more recent contact wins
+        let ranked = rank(
+            vec![contact("a@example.com", 5, Some(90), now), contact("b@example.com", 5, Some(2), now)],
+            now,
+            10,
+        );
+        assert_eq!(ranked[0].email, "b@example.com");
+    }
+}
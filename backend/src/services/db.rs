@@ -8,9 +8,23 @@ pub async fn init_schema(graph: &Graph) -> Result<()> {
         "CREATE CONSTRAINT contact_email IF NOT EXISTS FOR (c:Contact) REQUIRE c.email IS UNIQUE",
         "CREATE CONSTRAINT label_name IF NOT EXISTS FOR (l:Label) REQUIRE l.name IS UNIQUE",
         "CREATE CONSTRAINT thread_id IF NOT EXISTS FOR (t:Thread) REQUIRE t.id IS UNIQUE",
+        "CREATE CONSTRAINT group_name IF NOT EXISTS FOR (g:Group) REQUIRE g.name IS UNIQUE",
+        "CREATE CONSTRAINT attachment_id IF NOT EXISTS FOR (a:Attachment) REQUIRE a.id IS UNIQUE",
         "CREATE INDEX email_date IF NOT EXISTS FOR (e:Email) ON (e.date)",
         "CREATE INDEX email_read IF NOT EXISTS FOR (e:Email) ON (e.is_read)",
         "CREATE INDEX email_starred IF NOT EXISTS FOR (e:Email) ON (e.is_starred)",
+        // Replaced by the unique constraint below, which brings its own index
+        "DROP INDEX email_message_id IF EXISTS",
+        "CREATE CONSTRAINT email_message_id_unique IF NOT EXISTS FOR (e:Email) REQUIRE e.message_id IS UNIQUE",
+        "CREATE INDEX email_thread_id IF NOT EXISTS FOR (e:Email) ON (e.thread_id)",
+        "CREATE INDEX thread_last_date IF NOT EXISTS FOR (t:Thread) ON (t.last_date)",
+        "CREATE INDEX attachment_sha256 IF NOT EXISTS FOR (a:Attachment) ON (a.sha256)",
+        "CREATE INDEX imap_folder IF NOT EXISTS FOR (f:ImapFolder) ON (f.account, f.name)",
+        "CREATE INDEX deleted_email_token IF NOT EXISTS FOR (e:DeletedEmail) ON (e.undo_token)",
+        "CREATE INDEX deleted_thread_token IF NOT EXISTS FOR (t:DeletedThread) ON (t.undo_token)",
+        "CREATE INDEX deleted_label_token IF NOT EXISTS FOR (l:DeletedLabel) ON (l.undo_token)",
+        "CREATE CONSTRAINT ai_quota_user_day IF NOT EXISTS FOR (q:AiQuota) REQUIRE (q.user, q.day) IS UNIQUE",
+        "CREATE INDEX ai_usage_user_day IF NOT EXISTS FOR (u:AiUsage) ON (u.user, u.day)",
     ];
 
     for query in queries {
@@ -40,3 +54,27 @@ pub async fn init_schema(graph: &Graph) -> Result<()> {
     tracing::info!("Database schema initialized");
     Ok(())
 }
+
+/// Run queries in one transaction, rolling back if any fails.
+pub(crate) async fn run_atomically(graph: &Graph, queries: Vec<neo4rs::Query>) -> Result<()> {
+    let mut txn = graph.start_txn().await?;
+    match txn.run_queries(queries).await {
+        Ok(()) => Ok(txn.commit().await?),
+        Err(e) => {
+            if let Err(rollback) = txn.rollback().await {
+                tracing::warn!("Rollback failed: {}", rollback);
+            }
+            Err(e.into())
+        }
+    }
+}
+
+/// A database for tests that need one, from `NEO4J_TEST_URI` (with
+/// `NEO4J_TEST_USER`/`NEO4J_TEST_PASSWORD`); `None` skips the test.
+#[cfg(test)]
+pub(crate) async fn test_graph() -> Option<Graph> {
+    let uri = std::env::var("NEO4J_TEST_URI").ok()?;
+    let user = std::env::var("NEO4J_TEST_USER").unwrap_or_else(|_| "neo4j".into());
+    let password = std::env::var("NEO4J_TEST_PASSWORD").unwrap_or_else(|_| "password123".into());
+    Some(Graph::new(&uri, &user, &password).await.expect("test database is reachable"))
+}
@@ -1,18 +1,126 @@
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use neo4rs::{query, Graph};
 use uuid::Uuid;
 
-use crate::models::{Contact, CreateEmailRequest, Email, EmailListResponse, EmailQuery, UpdateEmailRequest};
+use crate::models::{
+    Contact, CreateEmailRequest, Delivery, DeliveryStatus, Email, EmailListResponse, EmailQuery, UndoToken,
+    UpdateEmailRequest,
+};
+use crate::services::attachments::{self, attachment_from_node};
+use crate::services::blobs;
+use crate::services::groups;
+use crate::services::html;
+use crate::services::labels;
+use crate::services::mime::{self, OutgoingAttachment};
+use crate::services::threading;
+use crate::services::db::run_atomically;
+use crate::services::undo;
 
-pub async fn list_emails(graph: &Graph, query_params: EmailQuery) -> Result<EmailListResponse> {
-    let skip = ((query_params.page - 1) * query_params.limit) as i64;
-    let limit = query_params.limit as i64;
+fn contact_from_node(n: neo4rs::Node) -> Contact {
+    Contact {
+        email: n.get("email").unwrap_or_default(),
+        name: n.get("name").ok(),
+    }
+}
+
+pub(crate) fn parse_date(value: Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|d| DateTime::parse_from_rfc3339(&d).ok())
+        .map(|d| d.with_timezone(&Utc))
+}
 
+/// Build an `Email` from a row with the node as `e`, its sender as `from`, and
+/// collected `tos`, `ccs` (optional), label names as `labels` and attachment
+/// nodes as `attachments` (optional).
+pub(crate) fn email_from_row(row: &neo4rs::Row) -> Result<Email> {
+    let e: neo4rs::Node = row.get("e")?;
+    let from_node: Option<neo4rs::Node> = row.get("from").ok();
+    let to_nodes: Vec<neo4rs::Node> = row.get("tos").unwrap_or_default();
+    let cc_nodes: Vec<neo4rs::Node> = row.get("ccs").unwrap_or_default();
+    let labels: Vec<String> = row.get("labels").unwrap_or_default();
+    let attachment_nodes: Vec<neo4rs::Node> = row.get("attachments").unwrap_or_default();
+
+    let from = from_node
+        .map(contact_from_node)
+        .unwrap_or(Contact { email: "unknown@example.com".into(), name: None });
+
+    let delivery = e
+        .get::<String>("delivery_status")
+        .ok()
+        .and_then(|s| DeliveryStatus::parse(&s))
+        .map(|status| Delivery {
+            status,
+            attempts: e.get::<i64>("delivery_attempts").unwrap_or(0) as u32,
+            last_error: e.get("delivery_error").ok(),
+            next_attempt_at: parse_date(e.get("next_attempt_at").ok()),
+            sent_at: parse_date(e.get("sent_at").ok()),
+        });
+
+    Ok(Email {
+        id: Uuid::parse_str(&e.get::<String>("id")?).unwrap_or_default(),
+        subject: e.get("subject").unwrap_or_default(),
+        body: e.get("body").unwrap_or_default(),
+        html_body: e.get("html_body").ok(),
+        snippet: e.get("snippet").unwrap_or_default(),
+        date: parse_date(e.get("date").ok()).unwrap_or_else(Utc::now),
+        is_read: e.get("is_read").unwrap_or(false),
+        is_starred: e.get("is_starred").unwrap_or(false),
+        thread_id: e.get::<String>("thread_id").ok().and_then(|s| Uuid::parse_str(&s).ok()),
+        message_id: e.get("message_id").ok(),
+        in_reply_to: e.get("in_reply_to").ok(),
+        references: e.get("references").unwrap_or_default(),
+        from,
+        to: to_nodes.into_iter().map(contact_from_node).collect(),
+        cc: cc_nodes.into_iter().map(contact_from_node).collect(),
+        labels,
+        attachments: attachment_nodes.into_iter().filter_map(attachment_from_node).collect(),
+        embedding: None,
+        delivery,
+    })
+}
+
+/// Free text plus the `has:`, `in:` and `from:group:` operators understood by
+/// `list_emails`.
+#[derive(Debug, Default, PartialEq)]
+struct SearchFilter {
+    text: Option<String>,
+    has_attachment: bool,
+    /// `from:group:<name>`: sent by a member of the group.
+    from_group: Option<String>,
+    /// `in:anywhere`: include SPAM and TRASH.
+    anywhere: bool,
+}
+
+impl SearchFilter {
+    fn parse(search: &str) -> Self {
+        let mut filter = SearchFilter::default();
+        let mut words = Vec::new();
+        for word in search.split_whitespace() {
+            match word.to_lowercase().as_str() {
+                "has:attachment" | "has:attachments" => filter.has_attachment = true,
+                "in:anywhere" => filter.anywhere = true,
+                w if w.starts_with("from:group:") && w.len() > "from:group:".len() => {
+                    filter.from_group = Some(word["from:group:".len()..].to_string());
+                }
+                _ => words.push(word),
+            }
+        }
+        if !words.is_empty() {
+            filter.text = Some(words.join(" "));
+        }
+        filter
+    }
+}
+
+/// `WHERE` clause over `e` for the filters of an `EmailQuery`, used with
+/// the `label` and `search` parameters from `filter_params`. SPAM and TRASH
+/// are excluded unless requested by label or `in:anywhere`.
+pub(crate) fn email_conditions(query_params: &EmailQuery) -> String {
     let mut conditions = vec!["1=1".to_string()];
-    
-    if let Some(label) = &query_params.label {
-        conditions.push(format!("EXISTS((e)-[:HAS_LABEL]->(:Label {{name: '{}'}})", label));
+
+    if query_params.label.is_some() {
+        conditions.push("EXISTS { MATCH (e)-[:HAS_LABEL]->(:Label {name: $label}) }".to_string());
     }
     if let Some(is_read) = query_params.is_read {
         conditions.push(format!("e.is_read = {}", is_read));
@@ -20,9 +128,57 @@ pub async fn list_emails(graph: &Graph, query_params: EmailQuery) -> Result<Emai
     if let Some(is_starred) = query_params.is_starred {
         conditions.push(format!("e.is_starred = {}", is_starred));
     }
+    let filter = SearchFilter::parse(query_params.search.as_deref().unwrap_or_default());
+    // SPAM and TRASH only show up when asked for
+    let hidden_requested = query_params
+        .label
+        .as_deref()
+        .is_some_and(|label| labels::HIDDEN_LABELS.contains(&label));
+    if !filter.anywhere && !hidden_requested {
+        conditions.push(format!(
+            "NOT EXISTS {{ MATCH (e)-[:HAS_LABEL]->(h:Label) WHERE h.name IN ['{}'] }}",
+            labels::HIDDEN_LABELS.join("', '")
+        ));
+    }
+    if filter.text.is_some() {
+        conditions.push(
+            r#"(toLower(e.subject) CONTAINS toLower($search) OR toLower(e.body) CONTAINS toLower($search)
+                OR EXISTS {
+                    MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+                    WHERE toLower(a.filename) CONTAINS toLower($search) OR toLower(a.text) CONTAINS toLower($search)
+                })"#
+                .to_string(),
+        );
+    }
+    if filter.has_attachment {
+        conditions.push("EXISTS { MATCH (e)-[:HAS_ATTACHMENT]->(:Attachment) }".to_string());
+    }
+    if filter.from_group.is_some() {
+        conditions.push(
+            r#"EXISTS {
+                MATCH (e)-[:SENT_BY]->(s:Contact)
+                WHERE (s)-[:MEMBER_OF]->(:Group {name: $from_group})
+                   OR EXISTS { MATCH (s)-[:ALIAS_OF]->(:Contact)-[:MEMBER_OF]->(:Group {name: $from_group}) }
+            }"#
+            .to_string(),
+        );
+    }
+    conditions.join(" AND ")
+}
+
+/// Add the parameters referenced by `email_conditions`.
+pub(crate) fn filter_params(q: neo4rs::Query, query_params: &EmailQuery) -> neo4rs::Query {
+    let filter = SearchFilter::parse(query_params.search.as_deref().unwrap_or_default());
+    q.param("label", query_params.label.clone().unwrap_or_default())
+        .param("search", filter.text.unwrap_or_default())
+        .param("from_group", filter.from_group.unwrap_or_default())
+}
+
+pub async fn list_emails(graph: &Graph, query_params: EmailQuery) -> Result<EmailListResponse> {
+    let skip = (query_params.page.max(1) - 1) as i64 * query_params.limit as i64;
+    let limit = query_params.limit as i64;
+    let where_clause = email_conditions(&query_params);
 
-    let where_clause = conditions.join(" AND ");
-    
     let cypher = format!(
         r#"
         MATCH (e:Email)
@@ -30,61 +186,28 @@ pub async fn list_emails(graph: &Graph, query_params: EmailQuery) -> Result<Emai
         OPTIONAL MATCH (e)-[:SENT_BY]->(from:Contact)
         OPTIONAL MATCH (e)-[:SENT_TO]->(to:Contact)
         OPTIONAL MATCH (e)-[:HAS_LABEL]->(l:Label)
-        WITH e, from, collect(DISTINCT to) as tos, collect(DISTINCT l.name) as labels
+        OPTIONAL MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+        WITH e, from, collect(DISTINCT to) as tos, collect(DISTINCT l.name) as labels, collect(DISTINCT a) as attachments
         ORDER BY e.date DESC
         SKIP $skip LIMIT $limit
-        RETURN e, from, tos, labels
+        RETURN e, from, tos, labels, attachments
         "#,
         where_clause
     );
 
     let mut result = graph
-        .execute(query(&cypher).param("skip", skip).param("limit", limit))
+        .execute(filter_params(query(&cypher), &query_params).param("skip", skip).param("limit", limit))
         .await?;
 
     let mut emails = Vec::new();
     
     while let Some(row) = result.next().await? {
-        let e: neo4rs::Node = row.get("e")?;
-        let from_node: Option<neo4rs::Node> = row.get("from").ok();
-        let to_nodes: Vec<neo4rs::Node> = row.get("tos").unwrap_or_default();
-        let labels: Vec<String> = row.get("labels").unwrap_or_default();
-
-        let from = from_node.map(|n| Contact {
-            email: n.get("email").unwrap_or_default(),
-            name: n.get("name").ok(),
-        }).unwrap_or(Contact { email: "unknown@example.com".into(), name: None });
-
-        let to: Vec<Contact> = to_nodes.into_iter().map(|n| Contact {
-            email: n.get("email").unwrap_or_default(),
-            name: n.get("name").ok(),
-        }).collect();
-
-        emails.push(Email {
-            id: Uuid::parse_str(&e.get::<String>("id")?).unwrap_or_default(),
-            subject: e.get("subject").unwrap_or_default(),
-            body: e.get("body").unwrap_or_default(),
-            snippet: e.get("snippet").unwrap_or_default(),
-            date: e.get::<String>("date")
-                .map(|d| chrono::DateTime::parse_from_rfc3339(&d).ok())
-                .ok()
-                .flatten()
-                .map(|d| d.with_timezone(&Utc))
-                .unwrap_or_else(Utc::now),
-            is_read: e.get("is_read").unwrap_or(false),
-            is_starred: e.get("is_starred").unwrap_or(false),
-            thread_id: e.get::<String>("thread_id").ok().and_then(|s| Uuid::parse_str(&s).ok()),
-            from,
-            to,
-            cc: vec![],
-            labels,
-            embedding: None,
-        });
+        emails.push(email_from_row(&row)?);
     }
 
     // Get total count
     let count_cypher = format!("MATCH (e:Email) WHERE {} RETURN count(e) as total", where_clause);
-    let mut count_result = graph.execute(query(&count_cypher)).await?;
+    let mut count_result = graph.execute(filter_params(query(&count_cypher), &query_params)).await?;
     let total: u64 = if let Some(row) = count_result.next().await? {
         row.get::<i64>("total").unwrap_or(0) as u64
     } else {
@@ -106,63 +229,53 @@ pub async fn get_email(graph: &Graph, id: Uuid) -> Result<Email> {
         OPTIONAL MATCH (e)-[:SENT_TO]->(to:Contact)
         OPTIONAL MATCH (e)-[:CC]->(cc:Contact)
         OPTIONAL MATCH (e)-[:HAS_LABEL]->(l:Label)
-        RETURN e, from, collect(DISTINCT to) as tos, collect(DISTINCT cc) as ccs, collect(DISTINCT l.name) as labels
+        OPTIONAL MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+        RETURN e, from, collect(DISTINCT to) as tos, collect(DISTINCT cc) as ccs, collect(DISTINCT l.name) as labels,
+               collect(DISTINCT a) as attachments
     "#;
 
     let mut result = graph.execute(query(cypher).param("id", id.to_string())).await?;
     
     if let Some(row) = result.next().await? {
-        let e: neo4rs::Node = row.get("e")?;
-        let from_node: Option<neo4rs::Node> = row.get("from").ok();
-        let to_nodes: Vec<neo4rs::Node> = row.get("tos").unwrap_or_default();
-        let cc_nodes: Vec<neo4rs::Node> = row.get("ccs").unwrap_or_default();
-        let labels: Vec<String> = row.get("labels").unwrap_or_default();
-
-        let from = from_node.map(|n| Contact {
-            email: n.get("email").unwrap_or_default(),
-            name: n.get("name").ok(),
-        }).unwrap_or(Contact { email: "unknown@example.com".into(), name: None });
-
-        let to: Vec<Contact> = to_nodes.into_iter().map(|n| Contact {
-            email: n.get("email").unwrap_or_default(),
-            name: n.get("name").ok(),
-        }).collect();
-
-        let cc: Vec<Contact> = cc_nodes.into_iter().map(|n| Contact {
-            email: n.get("email").unwrap_or_default(),
-            name: n.get("name").ok(),
-        }).collect();
-
-        return Ok(Email {
-            id: Uuid::parse_str(&e.get::<String>("id")?).unwrap_or_default(),
-            subject: e.get("subject").unwrap_or_default(),
-            body: e.get("body").unwrap_or_default(),
-            snippet: e.get("snippet").unwrap_or_default(),
-            date: e.get::<String>("date")
-                .map(|d| chrono::DateTime::parse_from_rfc3339(&d).ok())
-                .ok()
-                .flatten()
-                .map(|d| d.with_timezone(&Utc))
-                .unwrap_or_else(Utc::now),
-            is_read: e.get("is_read").unwrap_or(false),
-            is_starred: e.get("is_starred").unwrap_or(false),
-            thread_id: e.get::<String>("thread_id").ok().and_then(|s| Uuid::parse_str(&s).ok()),
-            from,
-            to,
-            cc,
-            labels,
-            embedding: None,
-        });
+        return email_from_row(&row);
     }
 
     Err(anyhow!("Email not found"))
 }
 
-pub async fn create_email(graph: &Graph, req: CreateEmailRequest) -> Result<Email> {
+pub async fn create_email(graph: &Graph, mut req: CreateEmailRequest) -> Result<Email> {
+    (req.to, req.cc) = groups::expand_recipients(graph, &req.to, &req.cc).await?;
+    mime::validate_addresses(&req.to)?;
+    mime::validate_addresses(&req.cc)?;
+    // Composed HTML is held to the same rules as received HTML
+    req.html_body = req.html_body.as_deref().map(html::sanitize);
+    let mut outgoing = req
+        .attachments
+        .iter()
+        .map(OutgoingAttachment::decode)
+        .collect::<Result<Vec<_>>>()?;
+    let mut uploaded = Vec::new();
+    for attachment_id in &req.attachment_ids {
+        uploaded.push(attachments::get(graph, *attachment_id).await?);
+    }
+
     let id = Uuid::new_v4();
     let date = Utc::now();
-    let snippet = req.body.chars().take(100).collect::<String>();
-    let thread_id = req.reply_to.map(|_| Uuid::new_v4()).unwrap_or_else(Uuid::new_v4);
+    let snippet = match (req.body.trim().is_empty(), &req.html_body) {
+        (true, Some(html_body)) => html::snippet(&html::to_text(html_body)),
+        _ => html::snippet(&req.body),
+    };
+    let sender = crate::services::mail::sender();
+    let message_id = mime::new_message_id(&sender.email);
+
+    // Replies extend the parent's References chain, which threads them with it
+    let parent = match req.reply_to {
+        Some(parent_id) => Some(get_email(graph, parent_id).await?),
+        None => None,
+    };
+    let in_reply_to = parent.as_ref().and_then(|p| p.message_id.clone());
+    let mut references = parent.as_ref().map(|p| p.references.clone()).unwrap_or_default();
+    references.extend(in_reply_to.clone());
 
     // Create email node
     let cypher = r#"
@@ -170,14 +283,18 @@ pub async fn create_email(graph: &Graph, req: CreateEmailRequest) -> Result<Emai
             id: $id,
             subject: $subject,
             body: $body,
+            html_body: $html_body,
             snippet: $snippet,
             date: $date,
             is_read: false,
             is_starred: false,
-            thread_id: $thread_id
+            message_id: $message_id,
+            in_reply_to: $in_reply_to,
+            references: $references
         })
         WITH e
-        MERGE (from:Contact {email: 'me@example.com'})
+        MERGE (from:Contact {email: $from_email})
+        ON CREATE SET from.name = $from_name
         CREATE (e)-[:SENT_BY]->(from)
         WITH e
         MATCH (l:Label {name: 'SENT'})
@@ -185,15 +302,33 @@ pub async fn create_email(graph: &Graph, req: CreateEmailRequest) -> Result<Emai
         RETURN e
     "#;
 
-    graph.run(
+    // Not queued until fully built, so the outbox never sends a partial email
+    let mut queries = vec![
         query(cypher)
             .param("id", id.to_string())
             .param("subject", req.subject.clone())
             .param("body", req.body.clone())
+            .param("html_body", req.html_body.clone())
             .param("snippet", snippet.clone())
             .param("date", date.to_rfc3339())
-            .param("thread_id", thread_id.to_string())
-    ).await?;
+            .param("message_id", message_id)
+            .param("in_reply_to", in_reply_to)
+            .param("references", references)
+            .param("from_email", sender.email)
+            .param("from_name", sender.name),
+    ];
+
+    if let Some(parent_id) = req.reply_to {
+        let reply_cypher = r#"
+            MATCH (e:Email {id: $id}), (p:Email {id: $parent_id})
+            MERGE (e)-[:REPLIED_TO]->(p)
+        "#;
+        queries.push(
+            query(reply_cypher)
+                .param("id", id.to_string())
+                .param("parent_id", parent_id.to_string())
+        );
+    }
 
     // Create recipient contacts and relationships
     for to_email in &req.to {
@@ -202,14 +337,84 @@ pub async fn create_email(graph: &Graph, req: CreateEmailRequest) -> Result<Emai
             MERGE (c:Contact {email: $to_email})
             CREATE (e)-[:SENT_TO]->(c)
         "#;
-        graph.run(
+        queries.push(
             query(to_cypher)
                 .param("email_id", id.to_string())
                 .param("to_email", to_email.clone())
+        );
+    }
+
+    for cc_email in &req.cc {
+        let cc_cypher = r#"
+            MATCH (e:Email {id: $email_id})
+            MERGE (c:Contact {email: $cc_email})
+            CREATE (e)-[:CC]->(c)
+        "#;
+        queries.push(
+            query(cc_cypher)
+                .param("email_id", id.to_string())
+                .param("cc_email", cc_email.clone())
+        );
+    }
+    run_atomically(graph, queries).await?;
+
+    let built = async {
+        threading::attach(graph, id).await?;
+
+        for (upload, attachment) in req.attachments.iter().zip(&outgoing) {
+            let content_type = upload.content_type.as_deref().unwrap_or("application/octet-stream");
+            attachments::store(graph, Some(id), &attachment.filename, content_type, &attachment.data).await?;
+        }
+        attachments::link(graph, id, &req.attachment_ids).await?;
+        outgoing.extend(attachments::outgoing(&uploaded).await?);
+
+        // Keep the exact bytes that go on the wire for download and re-sending
+        let raw = mime::render(&get_email(graph, id).await?, &outgoing)?;
+        let cypher = r#"
+            MATCH (e:Email {id: $id})
+            SET e.raw_sha256 = $raw_sha256, e.delivery_status = 'queued', e.delivery_attempts = 0, e.next_attempt_at = $now
+        "#;
+        graph.run(
+            query(cypher)
+                .param("id", id.to_string())
+                .param("raw_sha256", blobs::put(&raw).await?)
+                .param("now", Utc::now().to_rfc3339())
         ).await?;
+        get_email(graph, id).await
     }
+    .await;
 
-    get_email(graph, id).await
+    if built.is_err() {
+        // Drop the half-built email rather than leave it in SENT unsent
+        let cleanup = match get_email(graph, id).await {
+            Ok(email) => purge_email(graph, &email).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = cleanup {
+            tracing::warn!("Could not clean up email {}: {}", id, e);
+        }
+    }
+    built
+}
+
+/// The source referenced by a row's `raw_sha256` column, falling back to a
+/// `raw` string stored inline by older versions.
+pub(crate) async fn raw_from_row(row: &neo4rs::Row) -> Result<Option<Vec<u8>>> {
+    if let Ok(hash) = row.get::<String>("raw_sha256") {
+        return Ok(Some(blobs::get(&hash).await?));
+    }
+    Ok(row.get::<String>("raw").ok().map(String::into_bytes))
+}
+
+/// The stored RFC 5322 source of an email.
+pub async fn get_raw(graph: &Graph, id: Uuid) -> Result<Vec<u8>> {
+    let cypher = "MATCH (e:Email {id: $id}) RETURN e.raw_sha256 as raw_sha256, e.raw as raw";
+    let mut result = graph.execute(query(cypher).param("id", id.to_string())).await?;
+
+    match result.next().await? {
+        Some(row) => raw_from_row(&row).await?.ok_or_else(|| anyhow!("Raw message not available")),
+        None => Err(anyhow!("Email not found")),
+    }
 }
 
 pub async fn update_email(graph: &Graph, id: Uuid, req: UpdateEmailRequest) -> Result<Email> {
@@ -237,15 +442,17 @@ pub async fn update_email(graph: &Graph, id: Uuid, req: UpdateEmailRequest) -> R
     }
 
     // Handle labels update
-    if let Some(labels) = req.labels {
-        // Remove existing labels
+    let this_email = "MATCH (e:Email {id: $id})";
+    let labels_changed = req.labels.is_some();
+    if let Some(names) = req.labels {
+        // Remove existing labels; edges to deleted ones stay for undo
         graph.run(
-            query("MATCH (e:Email {id: $id})-[r:HAS_LABEL]->() DELETE r")
+            query("MATCH (e:Email {id: $id})-[r:HAS_LABEL]->(:Label) DELETE r")
                 .param("id", id.to_string())
         ).await?;
         
         // Add new labels
-        for label in labels {
+        for label in &names {
             graph.run(
                 query(r#"
                     MATCH (e:Email {id: $id})
@@ -253,16 +460,93 @@ pub async fn update_email(graph: &Graph, id: Uuid, req: UpdateEmailRequest) -> R
                     CREATE (e)-[:HAS_LABEL]->(l)
                 "#)
                 .param("id", id.to_string())
-                .param("label", label)
+                .param("label", label.clone())
             ).await?;
         }
+        graph.run(labels::hierarchy_query(&names)).await?;
+        graph.run(labels::trashed_at_query(this_email).param("id", id.to_string())).await?;
+    }
+
+    // An explicit is_starred wins over a STARRED label in `labels`
+    if req.is_starred.is_some() {
+        graph.run(labels::starred_label_query(this_email).param("id", id.to_string())).await?;
+    } else if labels_changed {
+        graph.run(labels::starred_flag_query(this_email).param("id", id.to_string())).await?;
     }
 
     get_email(graph, id).await
 }
 
-pub async fn delete_email(graph: &Graph, id: Uuid) -> Result<()> {
-    let cypher = "MATCH (e:Email {id: $id}) DETACH DELETE e";
+/// Remove an email from the inbox without deleting it.
+pub async fn archive_email(graph: &Graph, id: Uuid) -> Result<Email> {
+    get_email(graph, id).await?;
+    let cypher = "MATCH (e:Email {id: $id})-[r:HAS_LABEL]->(:Label {name: 'INBOX'}) DELETE r";
     graph.run(query(cypher).param("id", id.to_string())).await?;
+    get_email(graph, id).await
+}
+
+/// Move an email to TRASH. An email already there is deleted, which can be
+/// undone with the returned token.
+pub async fn delete_email(graph: &Graph, id: Uuid) -> Result<Option<UndoToken>> {
+    let email = get_email(graph, id).await?;
+    if email.labels.iter().any(|l| l == "TRASH") {
+        let token = undo::new_token();
+        graph
+            .run(undo::tombstone_emails_query("MATCH (e:Email {id: $id})", &token).param("id", id.to_string()))
+            .await?;
+        return Ok(Some(token));
+    }
+
+    let cypher = r#"
+        MATCH (e:Email {id: $id}), (t:Label {name: 'TRASH'})
+        MERGE (e)-[:HAS_LABEL]->(t)
+        SET e.trashed_at = $now
+    "#;
+    graph
+        .run(query(cypher).param("id", id.to_string()).param("now", Utc::now().to_rfc3339()))
+        .await?;
+    Ok(None)
+}
+
+/// Delete an email permanently with attachments and thread it leaves unused.
+pub(crate) async fn purge_email(graph: &Graph, email: &Email) -> Result<()> {
+    let email_attachments: Vec<Uuid> = email.attachments.iter().map(|a| a.id).collect();
+
+    let cypher = r#"
+        MATCH (e:Email {id: $id})
+        OPTIONAL MATCH (e)-[:IN_THREAD]->(t:Thread)
+        DETACH DELETE e
+        WITH t
+        WHERE t IS NOT NULL AND NOT EXISTS { MATCH (t)<-[:IN_THREAD]-(:Email) }
+        DETACH DELETE t
+    "#;
+    graph.run(query(cypher).param("id", email.id.to_string())).await?;
+    attachments::delete_orphans(graph, &email_attachments).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_search_operators() {
+        assert_eq!(
+            SearchFilter::parse("invoice  has:attachment march"),
+            SearchFilter { text: Some("invoice march".into()), has_attachment: true, ..Default::default() }
+        );
+        assert_eq!(
+            SearchFilter::parse("has:attachment"),
+            SearchFilter { has_attachment: true, ..Default::default() }
+        );
+        assert_eq!(
+            SearchFilter::parse("From:group:Engineering launch from:group:"),
+            SearchFilter { text: Some("launch from:group:".into()), from_group: Some("Engineering".into()), ..Default::default() }
+        );
+        assert_eq!(
+            SearchFilter::parse("in:anywhere refund"),
+            SearchFilter { text: Some("refund".into()), anywhere: true, ..Default::default() }
+        );
+        assert_eq!(SearchFilter::parse(""), SearchFilter::default());
+    }
+}
@@ -0,0 +1,348 @@
+use anyhow::{anyhow, Result};
+use neo4rs::{query, Graph};
+use serde::Deserialize;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::models::{Email, EmailQuery};
+use crate::services::emails::{email_conditions, email_from_row, filter_params, raw_from_row};
+use crate::services::mime;
+
+/// Emails loaded per database round trip.
+const BATCH: i64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Mbox,
+    /// A zip archive with one `.eml` file per email.
+    Eml,
+    /// Newline-delimited JSON `Email` objects.
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "mbox" => Ok(ExportFormat::Mbox),
+            "eml" | "zip" => Ok(ExportFormat::Eml),
+            "json" | "ndjson" => Ok(ExportFormat::Ndjson),
+            other => Err(anyhow!("Unknown export format: {}", other)),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Mbox => "application/mbox",
+            ExportFormat::Eml => "application/zip",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Mbox => "export.mbox",
+            ExportFormat::Eml => "export.zip",
+            ExportFormat::Ndjson => "export.ndjson",
+        }
+    }
+}
+
+/// Which emails to export, filtered like the email list: no filters means the
+/// whole mailbox outside SPAM and TRASH.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExportScope {
+    pub label: Option<String>,
+    pub thread_id: Option<Uuid>,
+    pub search: Option<String>,
+}
+
+async fn load_batch(graph: &Graph, scope: &ExportScope, skip: i64) -> Result<Vec<(Email, Option<Vec<u8>>)>> {
+    let email_query = EmailQuery {
+        page: 1,
+        limit: BATCH as u32,
+        label: scope.label.clone(),
+        is_read: None,
+        is_starred: None,
+        search: scope.search.clone(),
+    };
+    let mut conditions = email_conditions(&email_query);
+    if scope.thread_id.is_some() {
+        conditions.push_str(" AND e.thread_id = $thread_id");
+    }
+
+    let cypher = format!(
+        r#"
+        MATCH (e:Email)
+        WHERE {}
+        WITH e ORDER BY e.date, e.id SKIP $skip LIMIT $limit
+        OPTIONAL MATCH (e)-[:SENT_BY]->(from:Contact)
+        OPTIONAL MATCH (e)-[:SENT_TO]->(to:Contact)
+        OPTIONAL MATCH (e)-[:CC]->(cc:Contact)
+        OPTIONAL MATCH (e)-[:HAS_LABEL]->(l:Label)
+        OPTIONAL MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+        WITH e, from, collect(DISTINCT to) as tos, collect(DISTINCT cc) as ccs, collect(DISTINCT l.name) as labels,
+             collect(DISTINCT a) as attachments
+        ORDER BY e.date, e.id
+        RETURN e, from, tos, ccs, labels, attachments, e.raw_sha256 as raw_sha256, e.raw as raw
+        "#,
+        conditions
+    );
+
+    let mut result = graph
+        .execute(
+            filter_params(query(&cypher), &email_query)
+                .param("thread_id", scope.thread_id.map(|t| t.to_string()).unwrap_or_default())
+                .param("skip", skip)
+                .param("limit", BATCH),
+        )
+        .await?;
+
+    let mut emails = Vec::new();
+    while let Some(row) = result.next().await? {
+        emails.push((email_from_row(&row)?, raw_from_row(&row).await?));
+    }
+    Ok(emails)
+}
+
+/// The stored source, or a freshly rendered one for emails that have none.
+fn raw_message(email: &Email, raw: Option<Vec<u8>>) -> Result<Vec<u8>> {
+    match raw {
+        Some(raw) => Ok(raw),
+        None => mime::render(email, &[]),
+    }
+}
+
+/// One mboxrd entry: a `From ` separator line, the message with `From `
+/// lines quoted, and a trailing blank line.
+pub fn mbox_entry(email: &Email, raw: &[u8]) -> Vec<u8> {
+    let mut out = format!(
+        "From {} {}\n",
+        email.from.email,
+        email.date.format("%a %b %e %H:%M:%S %Y")
+    )
+    .into_bytes();
+
+    for line in raw.split_inclusive(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let quoted = line.iter().take_while(|b| **b == b'>').count();
+        if line[quoted..].starts_with(b"From ") {
+            out.push(b'>');
+        }
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+    if !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+    out.push(b'\n');
+    out
+}
+
+/// Seekable buffer that lets a zip be streamed: the zip writer only seeks back
+/// into the entry it is currently writing, so bytes before that entry's header
+/// can be handed out while the archive is still being built.
+#[derive(Clone, Default)]
+struct ZipBuffer(Arc<Mutex<ZipBufferState>>);
+
+#[derive(Default)]
+struct ZipBufferState {
+    /// Bytes already handed out; `data` starts at this offset.
+    drained: u64,
+    data: Vec<u8>,
+    position: u64,
+}
+
+impl ZipBuffer {
+    fn state(&self) -> std::sync::MutexGuard<'_, ZipBufferState> {
+        self.0.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    fn end(&self) -> u64 {
+        let state = self.state();
+        state.drained + state.data.len() as u64
+    }
+
+    /// Take out everything before `offset`.
+    fn drain_to(&self, offset: u64) -> Vec<u8> {
+        let mut state = self.state();
+        let count = offset.saturating_sub(state.drained).min(state.data.len() as u64) as usize;
+        state.drained += count as u64;
+        state.data.drain(..count).collect()
+    }
+}
+
+impl Write for ZipBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.state();
+        let start = state
+            .position
+            .checked_sub(state.drained)
+            .ok_or_else(|| std::io::Error::other("write into already streamed zip data"))? as usize;
+        let overlap = state.data.len().saturating_sub(start).min(buf.len());
+        state.data[start..start + overlap].copy_from_slice(&buf[..overlap]);
+        state.data.extend_from_slice(&buf[overlap..]);
+        state.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ZipBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let mut state = self.state();
+        let end = state.drained + state.data.len() as u64;
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => end.checked_add_signed(delta),
+            SeekFrom::Current(delta) => state.position.checked_add_signed(delta),
+        };
+        match target {
+            Some(target) if target >= state.drained && target <= end => {
+                state.position = target;
+                Ok(target)
+            }
+            _ => Err(std::io::Error::other("seek into already streamed zip data")),
+        }
+    }
+}
+
+async fn write_export(
+    graph: &Graph,
+    scope: &ExportScope,
+    format: ExportFormat,
+    tx: &mpsc::Sender<Result<Vec<u8>>>,
+) -> Result<usize> {
+    let buffer = ZipBuffer::default();
+    let mut zip = (format == ExportFormat::Eml).then(|| zip::ZipWriter::new(buffer.clone()));
+    let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    let mut exported = 0;
+    loop {
+        let batch = load_batch(graph, scope, exported as i64).await?;
+        if batch.is_empty() {
+            break;
+        }
+        exported += batch.len();
+
+        let mut chunk = Vec::new();
+        for (email, raw) in batch {
+            match format {
+                ExportFormat::Ndjson => {
+                    serde_json::to_writer(&mut chunk, &email)?;
+                    chunk.push(b'\n');
+                }
+                ExportFormat::Mbox => chunk.extend(mbox_entry(&email, &raw_message(&email, raw)?)),
+                ExportFormat::Eml => {
+                    if let Some(zip) = zip.as_mut() {
+                        let name = format!("{}-{}.eml", email.date.format("%Y%m%dT%H%M%S"), email.id);
+                        // Starting an entry finalizes the previous one, after which
+                        // everything before the new header is safe to send
+                        let header_start = buffer.end();
+                        zip.start_file(name, options)?;
+                        zip.write_all(&raw_message(&email, raw)?)?;
+                        chunk.extend(buffer.drain_to(header_start));
+                    }
+                }
+            }
+        }
+
+        // The receiver went away (client disconnected); stop quietly
+        if tx.send(Ok(chunk)).await.is_err() {
+            return Ok(exported);
+        }
+    }
+
+    if let Some(zip) = zip {
+        zip.finish()?;
+        let _ = tx.send(Ok(buffer.drain_to(buffer.end()))).await;
+    }
+    Ok(exported)
+}
+
+/// Start exporting in the background. Chunks arrive on the receiver in order;
+/// a failure midway is sent as the last item.
+pub fn spawn_export(graph: Graph, scope: ExportScope, format: ExportFormat) -> mpsc::Receiver<Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        match write_export(&graph, &scope, format, &tx).await {
+            Ok(count) => tracing::info!(count, ?format, "Export finished"),
+            Err(e) => {
+                tracing::error!("Export failed: {}", e);
+                let _ = tx.send(Err(e)).await;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Contact;
+    use crate::services::import::split_mbox;
+
+    #[test]
+    fn mbox_entries_round_trip_through_import() {
+        let email = Email {
+            id: Uuid::new_v4(),
+            subject: "Hi".into(),
+            body: String::new(),
+            html_body: None,
+            snippet: String::new(),
+            date: chrono::Utc::now(),
+            is_read: true,
+            is_starred: false,
+            thread_id: None,
+            message_id: None,
+            in_reply_to: None,
+            references: vec![],
+            from: Contact { email: "alice@example.com".into(), name: None },
+            to: vec![],
+            cc: vec![],
+            labels: vec![],
+            attachments: vec![],
+            embedding: None,
+            delivery: None,
+        };
+        let first = b"Subject: One\r\n\r\nFrom the start.\r\n>From quoted.\r\n";
+        let second = b"Subject: Two\r\n\r\nBody";
+
+        let mut mbox = mbox_entry(&email, first);
+        mbox.extend(mbox_entry(&email, second));
+        assert!(mbox.starts_with(b"From alice@example.com "));
+
+        let messages = split_mbox("export.mbox", &mbox);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            String::from_utf8_lossy(&messages[0].raw),
+            "Subject: One\n\nFrom the start.\n>From quoted.\n"
+        );
+        assert_eq!(String::from_utf8_lossy(&messages[1].raw), "Subject: Two\n\nBody\n");
+    }
+
+    #[test]
+    fn streamed_zip_chunks_form_a_valid_archive() {
+        let buffer = ZipBuffer::default();
+        let mut zip = zip::ZipWriter::new(buffer.clone());
+        let mut streamed = Vec::new();
+
+        for i in 0..3 {
+            let header_start = buffer.end();
+            zip.start_file(format!("{}.eml", i), zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(format!("Subject: {}\r\n\r\nbody", i).as_bytes()).unwrap();
+            streamed.extend(buffer.drain_to(header_start));
+        }
+        zip.finish().unwrap();
+        streamed.extend(buffer.drain_to(buffer.end()));
+
+        let messages = crate::services::import::read_upload("export.zip", streamed).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(String::from_utf8_lossy(&messages[2].raw), "Subject: 2\r\n\r\nbody");
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{anyhow, Result};
+use neo4rs::{query, Graph};
+use regex::Regex;
+use std::io::Read;
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+
+use crate::services::{ai, blobs, html, mail, poller};
+
+/// Characters of extracted text kept per attachment.
+const MAX_TEXT_CHARS: usize = 200_000;
+
+/// Bytes of `word/document.xml` read from a Word document; the markup is
+/// several times larger than the text it holds.
+const MAX_DOCX_XML_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Attachments extracted per polling round.
+const BATCH: i64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Pdf,
+    Docx,
+    Text,
+    Html,
+}
+
+fn kind(content_type: &str, filename: &str) -> Option<Kind> {
+    let extension = filename.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+    match (content_type.to_lowercase().as_str(), extension.as_str()) {
+        ("application/pdf", _) | (_, "pdf") => Some(Kind::Pdf),
+        ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", _) | (_, "docx") => {
+            Some(Kind::Docx)
+        }
+        ("text/html", _) | (_, "html" | "htm") => Some(Kind::Html),
+        ("text/plain" | "text/csv", _) | (_, "txt" | "csv" | "md" | "log") => Some(Kind::Text),
+        _ => None,
+    }
+}
+
+/// Paragraph text of a Word document's `word/document.xml`.
+fn docx_text(data: &[u8]) -> Result<String> {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let tag = TAG.get_or_init(|| Regex::new(r"<[^>]*>").expect("valid regex"));
+
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    let mut xml = Vec::new();
+    archive
+        .by_name("word/document.xml")
+        .map_err(|_| anyhow!("Not a Word document"))?
+        .take(MAX_DOCX_XML_BYTES)
+        .read_to_end(&mut xml)?;
+    let xml = String::from_utf8_lossy(&xml);
+
+    let xml = xml.replace("</w:p>", "\n").replace("<w:tab/>", "\t").replace("<w:br/>", "\n");
+    let text = tag.replace_all(&xml, "");
+    Ok(text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&"))
+}
+
+/// Plain text of a supported attachment; `None` for formats we cannot read.
+pub fn extract_text(content_type: &str, filename: &str, data: &[u8]) -> Result<Option<String>> {
+    let text = match kind(content_type, filename) {
+        Some(Kind::Pdf) => pdf_extract::extract_text_from_mem(data).map_err(|e| anyhow!("Cannot read PDF: {}", e))?,
+        Some(Kind::Docx) => docx_text(data)?,
+        Some(Kind::Html) => html::to_text(&String::from_utf8_lossy(data)),
+        Some(Kind::Text) => String::from_utf8_lossy(data).into_owned(),
+        None => return Ok(None),
+    };
+
+    let text = text.lines().map(str::trim_end).collect::<Vec<_>>().join("\n");
+    Ok(Some(text.trim().chars().take(MAX_TEXT_CHARS).collect()))
+}
+
+/// Extract the text of one attachment and record the outcome in `text_status`
+/// (`extracted`, `unsupported` or `failed`). Returns whether text was extracted.
+async fn extract_attachment(graph: &Graph, id: Uuid, content_type: String, filename: String, sha256: String) -> Result<bool> {
+    let data = blobs::get(&sha256).await?;
+    // PDF parsing is CPU-bound and can panic on malformed files
+    let extracted = tokio::task::spawn_blocking(move || extract_text(&content_type, &filename, &data)).await;
+
+    let (status, text) = match extracted {
+        Ok(Ok(Some(text))) => ("extracted", Some(text)),
+        Ok(Ok(None)) => ("unsupported", None),
+        Ok(Err(e)) => {
+            tracing::warn!("Text extraction failed for attachment {}: {}", id, e);
+            ("failed", None)
+        }
+        Err(e) => {
+            tracing::warn!("Text extraction crashed for attachment {}: {}", id, e);
+            ("failed", None)
+        }
+    };
+
+    let cypher = r#"
+        MATCH (a:Attachment {id: $id})
+        SET a.text_status = $status, a.text = $text
+        REMOVE a.embedding
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("id", id.to_string())
+                .param("status", status)
+                .param("text", text),
+        )
+        .await?;
+    Ok(status == "extracted")
+}
+
+/// Extract text from attachments that have not been processed yet and embed
+/// what was extracted. Returns how many were processed.
+pub async fn process_pending(graph: &Graph, ai: &ai::AiEnv) -> Result<usize> {
+    let cypher = r#"
+        MATCH (a:Attachment)
+        WHERE a.text_status IS NULL
+        RETURN a.id as id, a.content_type as content_type, a.filename as filename, a.sha256 as sha256
+        LIMIT $limit
+    "#;
+    let mut result = graph.execute(query(cypher).param("limit", BATCH)).await?;
+
+    let mut pending = Vec::new();
+    while let Some(row) = result.next().await? {
+        let Ok(id) = Uuid::parse_str(&row.get::<String>("id")?) else {
+            continue;
+        };
+        pending.push((
+            id,
+            row.get::<String>("content_type").unwrap_or_default(),
+            row.get::<String>("filename").unwrap_or_default(),
+            row.get::<String>("sha256").unwrap_or_default(),
+        ));
+    }
+
+    let count = pending.len();
+    for (id, content_type, filename, sha256) in pending {
+        match extract_attachment(graph, id, content_type, filename, sha256).await {
+            Ok(true) => {
+                // Left for the batch indexer to retry when this fails
+                if let Err(e) = ai::index_attachment(graph, ai, &mail::sender().email, id).await {
+                    tracing::warn!("Could not index attachment {}: {}", id, e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => {
+                tracing::warn!("Could not extract attachment {}: {}", id, e);
+                graph
+                    .run(
+                        query("MATCH (a:Attachment {id: $id}) SET a.text_status = 'failed'")
+                            .param("id", id.to_string()),
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Extract attachment text in the background, checking every
+/// `EXTRACT_POLL_SECS` (default 30) and draining backlogs without waiting.
+pub fn spawn_worker(graph: Graph, ai: Arc<ai::AiEnv>) {
+    poller::spawn_poller("Attachment extraction", "EXTRACT_POLL_SECS", 30, Some(BATCH as usize), move || {
+        let (graph, ai) = (graph.clone(), ai.clone());
+        async move { process_pending(&graph, &ai).await }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A single-page PDF showing `text`, with a correct cross-reference table.
+    fn pdf(text: &str) -> Vec<u8> {
+        let stream = format!("BT /F1 12 Tf 72 720 Td ({}) Tj ET", text);
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Contents 4 0 R /Resources << /Font << /F1 5 0 R >> >> >>".to_string(),
+            format!("<< /Length {} >>\nstream\n{}\nendstream", stream.len(), stream),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_string(),
+        ];
+
+        let mut out = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).into_bytes());
+        }
+        let xref = out.len();
+        out.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).into_bytes());
+        for offset in offsets {
+            out.extend(format!("{:010} 00000 n \n", offset).into_bytes());
+        }
+        out.extend(
+            format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).into_bytes(),
+        );
+        out
+    }
+
+    fn docx(paragraphs: &[&str]) -> Vec<u8> {
+        let body: String = paragraphs
+            .iter()
+            .map(|p| format!("<w:p><w:r><w:t>{}</w:t></w:r></w:p>", p))
+            .collect();
+        let xml = format!(
+            r#"<?xml version="1.0"?><w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>{}</w:body></w:document>"#,
+            body
+        );
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("word/document.xml", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(xml.as_bytes()).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn extracts_supported_formats() {
+        let text = extract_text("application/pdf", "contract.pdf", &pdf("90-day termination clause")).unwrap();
+        assert!(text.unwrap().contains("90-day termination clause"));
+
+        let text = extract_text("application/octet-stream", "notes.docx", &docx(&["Terms &amp; conditions", "Second"]));
+        assert_eq!(text.unwrap().as_deref(), Some("Terms & conditions\nSecond"));
+
+        let text = extract_text("text/html", "page.html", b"<p>Hello <b>world</b></p>").unwrap();
+        assert_eq!(text.as_deref(), Some("Hello world"));
+
+        let text = extract_text("text/csv", "data.csv", b"name,amount\nacme,10\n").unwrap();
+        assert_eq!(text.as_deref(), Some("name,amount\nacme,10"));
+
+        assert!(extract_text("image/png", "logo.png", b"\x89PNG").unwrap().is_none());
+        assert!(extract_text("application/pdf", "broken.pdf", b"not a pdf").is_err());
+    }
+}
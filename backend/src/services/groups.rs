@@ -0,0 +1,224 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use neo4rs::{query, Graph};
+
+use crate::models::{Contact, CreateGroupRequest, Group, UpdateGroupRequest};
+use crate::services::{db::run_atomically, mime};
+
+/// Recipient prefix naming a group in `to`/`cc`.
+const RECIPIENT_PREFIX: &str = "group:";
+
+/// Group names appear in recipients and search operators, so they are
+/// limited to letters, digits, `.`, `_` and `-`.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        return Err(anyhow!("Invalid group name: {}", name));
+    }
+    Ok(())
+}
+
+/// Members are attached to the contact identity of each address.
+fn add_members_query(name: &str, emails: &[String]) -> neo4rs::Query {
+    query(
+        r#"
+        UNWIND $emails as email
+        MERGE (x:Contact {email: email})
+        WITH x
+        OPTIONAL MATCH (x)-[:ALIAS_OF]->(p:Contact)
+        WITH coalesce(p, x) as c
+        MATCH (g:Group {name: $name})
+        MERGE (c)-[:MEMBER_OF]->(g)
+        "#,
+    )
+    .param("name", name)
+    .param("emails", emails.to_vec())
+}
+
+fn remove_members_query(name: &str, emails: &[String]) -> neo4rs::Query {
+    query(
+        r#"
+        UNWIND $emails as email
+        MATCH (x:Contact {email: email})
+        OPTIONAL MATCH (x)-[:ALIAS_OF]->(p:Contact)
+        WITH coalesce(p, x) as c
+        MATCH (c)-[r:MEMBER_OF]->(:Group {name: $name})
+        DELETE r
+        "#,
+    )
+    .param("name", name)
+    .param("emails", emails.to_vec())
+}
+
+fn trimmed(emails: &[String]) -> Result<Vec<String>> {
+    let emails: Vec<String> = emails.iter().map(|e| e.trim().to_string()).collect();
+    mime::validate_addresses(&emails)?;
+    Ok(emails)
+}
+
+pub async fn list_groups(graph: &Graph) -> Result<Vec<Group>> {
+    let cypher = r#"
+        MATCH (g:Group)
+        OPTIONAL MATCH (c:Contact)-[:MEMBER_OF]->(g)
+        RETURN g.name as name, g.description as description, count(c) as member_count
+        ORDER BY g.name
+    "#;
+    let mut result = graph.execute(query(cypher)).await?;
+    let mut groups = Vec::new();
+    while let Some(row) = result.next().await? {
+        groups.push(Group {
+            name: row.get("name")?,
+            description: row.get("description").ok(),
+            member_count: row.get::<i64>("member_count").unwrap_or(0) as u64,
+            members: Vec::new(),
+        });
+    }
+    Ok(groups)
+}
+
+pub async fn get_group(graph: &Graph, name: &str) -> Result<Group> {
+    let cypher = r#"
+        MATCH (g:Group {name: $name})
+        OPTIONAL MATCH (c:Contact)-[:MEMBER_OF]->(g)
+        WITH g, c ORDER BY c.email
+        RETURN g.name as name, g.description as description, collect(c) as members
+    "#;
+    let mut result = graph.execute(query(cypher).param("name", name)).await?;
+    let row = result.next().await?.ok_or_else(|| anyhow!("Group not found"))?;
+    let members: Vec<Contact> = row
+        .get::<Vec<neo4rs::Node>>("members")
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|n| {
+            Some(Contact {
+                email: n.get("email").ok()?,
+                name: n.get("name").ok(),
+            })
+        })
+        .collect();
+
+    Ok(Group {
+        name: row.get("name")?,
+        description: row.get("description").ok(),
+        member_count: members.len() as u64,
+        members,
+    })
+}
+
+async fn group_exists(graph: &Graph, name: &str) -> Result<bool> {
+    let mut result = graph
+        .execute(query("MATCH (g:Group {name: $name}) RETURN count(g) > 0 as exists").param("name", name))
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get("exists").unwrap_or(false),
+        None => false,
+    })
+}
+
+pub async fn create_group(graph: &Graph, req: CreateGroupRequest) -> Result<Group> {
+    validate_name(&req.name)?;
+    let members = trimmed(&req.members)?;
+    if group_exists(graph, &req.name).await? {
+        return Err(anyhow!("Group already exists"));
+    }
+
+    run_atomically(
+        graph,
+        vec![
+            query("CREATE (:Group {name: $name, description: $description, created_at: $created_at})")
+                .param("name", req.name.clone())
+                .param("description", req.description)
+                .param("created_at", Utc::now().to_rfc3339()),
+            add_members_query(&req.name, &members),
+        ],
+    )
+    .await?;
+    get_group(graph, &req.name).await
+}
+
+pub async fn update_group(graph: &Graph, name: &str, req: UpdateGroupRequest) -> Result<Group> {
+    if !group_exists(graph, name).await? {
+        return Err(anyhow!("Group not found"));
+    }
+
+    let mut queries = Vec::new();
+    if let Some(description) = req.description {
+        queries.push(
+            query("MATCH (g:Group {name: $name}) SET g.description = $description")
+                .param("name", name)
+                .param("description", description),
+        );
+    }
+    if let Some(members) = req.members {
+        let members = trimmed(&members)?;
+        queries.push(query("MATCH (:Contact)-[r:MEMBER_OF]->(:Group {name: $name}) DELETE r").param("name", name));
+        queries.push(add_members_query(name, &members));
+    }
+    if !req.add_members.is_empty() {
+        queries.push(add_members_query(name, &trimmed(&req.add_members)?));
+    }
+    if !req.remove_members.is_empty() {
+        queries.push(remove_members_query(name, &req.remove_members));
+    }
+
+    run_atomically(graph, queries).await?;
+    get_group(graph, name).await
+}
+
+pub async fn delete_group(graph: &Graph, name: &str) -> Result<()> {
+    graph
+        .run(query("MATCH (g:Group {name: $name}) DETACH DELETE g").param("name", name))
+        .await?;
+    Ok(())
+}
+
+/// Replace `group:<name>` entries with the group's member addresses,
+/// dropping duplicates.
+async fn expand(graph: &Graph, recipients: &[String], seen: &mut Vec<String>) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for recipient in recipients {
+        let recipient = recipient.trim();
+        let addresses = match recipient.strip_prefix(RECIPIENT_PREFIX) {
+            Some(name) => match get_group(graph, name).await {
+                Ok(group) => group.members.into_iter().map(|c| c.email).collect(),
+                Err(e) if e.to_string() == "Group not found" => {
+                    return Err(anyhow!("Invalid recipient group: {}", name));
+                }
+                Err(e) => return Err(e),
+            },
+            None => vec![recipient.to_string()],
+        };
+        for address in addresses {
+            if !seen.contains(&address) {
+                seen.push(address.clone());
+                expanded.push(address);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// Expand groups in `to` and `cc` into member addresses, as of send time.
+/// Anyone already in `to` is left out of `cc`.
+pub async fn expand_recipients(graph: &Graph, to: &[String], cc: &[String]) -> Result<(Vec<String>, Vec<String>)> {
+    let mut seen = Vec::new();
+    let to = expand(graph, to, &mut seen).await?;
+    let cc = expand(graph, cc, &mut seen).await?;
+    Ok((to, cc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_group_names() {
+        assert!(validate_name("engineering").is_ok());
+        assert!(validate_name("team-2.eu_west").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("two words").is_err());
+        assert!(validate_name("group:nested").is_err());
+    }
+}
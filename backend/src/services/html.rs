@@ -0,0 +1,55 @@
+use std::collections::HashSet;
+
+/// Characters kept in an email's `snippet`.
+const SNIPPET_LEN: usize = 100;
+
+/// Strip scripts, event handlers, styles that can load resources and anything
+/// else unsafe from an HTML body. Inline images referenced by `cid:` are kept.
+pub fn sanitize(html: &str) -> String {
+    let mut schemes: HashSet<&str> = ammonia::Builder::default().clone_url_schemes();
+    schemes.insert("cid");
+    ammonia::Builder::default()
+        .url_schemes(schemes)
+        .clean(html)
+        .to_string()
+}
+
+/// Plain-text rendering of an HTML body.
+pub fn to_text(html: &str) -> String {
+    mail_parser::decoders::html::html_to_text(html)
+}
+
+/// Preview of the text a reader sees first: quoted replies and the signature
+/// are skipped and whitespace is collapsed.
+pub fn snippet(text: &str) -> String {
+    let visible = text
+        .lines()
+        .take_while(|line| line.trim_end() != "--")
+        .filter(|line| !line.trim_start().starts_with('>'))
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ");
+    visible.chars().take(SNIPPET_LEN).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizes_html() {
+        let html = r#"<p onclick="x()">Hi <img src="cid:logo"><script>alert(1)</script><a href="javascript:x()">link</a></p>"#;
+        let clean = sanitize(html);
+        assert!(clean.contains(r#"<img src="cid:logo">"#));
+        assert!(!clean.contains("script"));
+        assert!(!clean.contains("onclick"));
+        assert!(!clean.contains("javascript"));
+    }
+
+    #[test]
+    fn snippet_skips_quotes_and_signature() {
+        let text = "Sounds   good,\n\nsee you then.\n> Are you free?\n> Bob\n-- \nAlice\n";
+        assert_eq!(snippet(text), "Sounds good, see you then.");
+        assert_eq!(snippet(&"word ".repeat(50)).chars().count(), SNIPPET_LEN);
+    }
+}
@@ -0,0 +1,598 @@
+use anyhow::{anyhow, Context, Result};
+use async_imap::types::{Fetch, Flag, NameAttribute};
+use async_imap::{Client, Session};
+use futures::TryStreamExt;
+use neo4rs::{query, Graph};
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use uuid::Uuid;
+
+use crate::services::inbound::{self, ParsedMessage};
+use crate::services::labels;
+use crate::services::mail::TlsMode;
+
+/// Messages fetched per `UID FETCH` round trip during the initial sync.
+const FETCH_BATCH: usize = 50;
+
+trait ImapIo: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> ImapIo for T {}
+
+type ImapSession = Session<Box<dyn ImapIo>>;
+
+fn default_tls() -> TlsMode {
+    TlsMode::Implicit
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImapAccount {
+    /// Stable identifier used to key sync state in the graph.
+    pub name: String,
+    pub host: String,
+    pub port: Option<u16>,
+    #[serde(default = "default_tls")]
+    pub tls: TlsMode,
+    pub username: String,
+    pub password: String,
+    /// Folders to sync; all selectable folders when empty.
+    #[serde(default)]
+    pub folders: Vec<String>,
+}
+
+impl ImapAccount {
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.tls {
+            TlsMode::Implicit => 993,
+            TlsMode::StartTls | TlsMode::None => 143,
+        })
+    }
+}
+
+/// Accounts from the JSON array in `IMAP_ACCOUNTS_FILE`, or a single account
+/// from `IMAP_HOST`, `IMAP_PORT`, `IMAP_TLS`, `IMAP_USERNAME`, `IMAP_PASSWORD`
+/// and `IMAP_FOLDERS`.
+pub fn accounts_from_env() -> Result<Vec<ImapAccount>> {
+    if let Ok(path) = std::env::var("IMAP_ACCOUNTS_FILE") {
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Cannot read IMAP_ACCOUNTS_FILE {}", path))?;
+        return serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid IMAP_ACCOUNTS_FILE {}", path));
+    }
+
+    let Ok(host) = std::env::var("IMAP_HOST") else {
+        return Ok(vec![]);
+    };
+    let username = std::env::var("IMAP_USERNAME").context("IMAP_USERNAME is required with IMAP_HOST")?;
+    Ok(vec![ImapAccount {
+        name: username.clone(),
+        host,
+        port: match std::env::var("IMAP_PORT") {
+            Ok(port) => Some(port.parse().with_context(|| format!("Invalid IMAP_PORT {}", port))?),
+            Err(_) => None,
+        },
+        tls: TlsMode::parse(std::env::var("IMAP_TLS").as_deref().unwrap_or("implicit"))?,
+        username,
+        password: std::env::var("IMAP_PASSWORD").unwrap_or_default(),
+        folders: std::env::var("IMAP_FOLDERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|f| f.trim().to_string())
+            .filter(|f| !f.is_empty())
+            .collect(),
+    }])
+}
+
+async fn tls_wrap(host: &str, tcp: TcpStream) -> Result<Box<dyn ImapIo>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_string())?;
+    let stream = TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await?;
+    Ok(Box::new(stream))
+}
+
+async fn connect(account: &ImapAccount) -> Result<ImapSession> {
+    let tcp = TcpStream::connect((account.host.as_str(), account.port())).await?;
+
+    let stream: Box<dyn ImapIo> = match account.tls {
+        TlsMode::Implicit => tls_wrap(&account.host, tcp).await?,
+        TlsMode::None => Box::new(tcp),
+        TlsMode::StartTls => {
+            let mut client = Client::new(tcp);
+            client.read_response().await.ok_or_else(|| anyhow!("IMAP server closed the connection"))??;
+            client.run_command_and_check_ok("STARTTLS", None).await?;
+            tls_wrap(&account.host, client.into_inner()).await?
+        }
+    };
+
+    let mut client = Client::new(stream);
+    if account.tls != TlsMode::StartTls {
+        client.read_response().await.ok_or_else(|| anyhow!("IMAP server closed the connection"))??;
+    }
+    client
+        .login(&account.username, &account.password)
+        .await
+        .map_err(|(e, _)| anyhow!("IMAP login failed for {}: {}", account.name, e))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Folder {
+    pub name: String,
+    pub label: String,
+}
+
+/// Label for an IMAP folder: system labels for special-use folders (by
+/// attribute, or by common name when the server lacks SPECIAL-USE), otherwise
+/// the folder path with `/` separators. `None` for virtual folders that only
+/// duplicate other folders.
+fn folder_label(name: &str, delimiter: Option<&str>, attributes: &[NameAttribute]) -> Option<String> {
+    for attribute in attributes {
+        match attribute {
+            NameAttribute::NoSelect | NameAttribute::All | NameAttribute::Flagged => return None,
+            NameAttribute::Sent => return Some("SENT".into()),
+            NameAttribute::Drafts => return Some("DRAFTS".into()),
+            NameAttribute::Junk => return Some("SPAM".into()),
+            NameAttribute::Trash => return Some("TRASH".into()),
+            _ => {}
+        }
+    }
+
+    let label = match name.to_lowercase().as_str() {
+        "inbox" => "INBOX",
+        "sent" | "sent items" | "sent messages" | "sent mail" => "SENT",
+        "drafts" => "DRAFTS",
+        "junk" | "spam" | "junk e-mail" => "SPAM",
+        "trash" | "deleted items" | "deleted messages" => "TRASH",
+        _ => {
+            return Some(match delimiter {
+                Some(d) if d != "/" => name.replace(d, "/"),
+                _ => name.to_string(),
+            })
+        }
+    };
+    Some(label.into())
+}
+
+async fn list_folders(session: &mut ImapSession, account: &ImapAccount) -> Result<Vec<Folder>> {
+    let names: Vec<_> = session.list(Some(""), Some("*")).await?.try_collect().await?;
+
+    Ok(names
+        .iter()
+        .filter(|n| account.folders.is_empty() || account.folders.iter().any(|f| f == n.name()))
+        .filter_map(|n| {
+            folder_label(n.name(), n.delimiter(), n.attributes()).map(|label| Folder {
+                name: n.name().to_string(),
+                label,
+            })
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone)]
+struct FetchedMessage {
+    uid: u32,
+    seen: bool,
+    flagged: bool,
+    raw: Option<Vec<u8>>,
+}
+
+impl From<&Fetch> for FetchedMessage {
+    fn from(fetch: &Fetch) -> Self {
+        FetchedMessage {
+            uid: fetch.uid.unwrap_or(0),
+            seen: fetch.flags().any(|f| f == Flag::Seen),
+            flagged: fetch.flags().any(|f| f == Flag::Flagged),
+            raw: fetch.body().map(<[u8]>::to_vec),
+        }
+    }
+}
+
+async fn search_uids(session: &mut ImapSession, criteria: &str) -> Result<Vec<u32>> {
+    let mut uids: Vec<u32> = session.uid_search(criteria).await?.into_iter().collect();
+    uids.sort_unstable();
+    Ok(uids)
+}
+
+async fn fetch(session: &mut ImapSession, uid_set: &str, items: &str) -> Result<Vec<FetchedMessage>> {
+    let fetches: Vec<Fetch> = session.uid_fetch(uid_set, items).await?.try_collect().await?;
+    Ok(fetches.iter().map(FetchedMessage::from).filter(|m| m.uid > 0).collect())
+}
+
+/// Sync position stored on the `:ImapFolder` node.
+#[derive(Debug, Default)]
+struct FolderState {
+    uidvalidity: Option<i64>,
+    last_uid: u32,
+    highest_modseq: Option<u64>,
+}
+
+async fn load_state(graph: &Graph, account: &str, folder: &Folder) -> Result<FolderState> {
+    let cypher = r#"
+        MERGE (f:ImapFolder {account: $account, name: $name})
+        SET f.label = $label
+        RETURN f.uidvalidity as uidvalidity, coalesce(f.last_uid, 0) as last_uid, f.highest_modseq as modseq
+    "#;
+    let mut result = graph
+        .execute(
+            query(cypher)
+                .param("account", account)
+                .param("name", folder.name.clone())
+                .param("label", folder.label.clone()),
+        )
+        .await?;
+
+    Ok(match result.next().await? {
+        Some(row) => FolderState {
+            uidvalidity: row.get("uidvalidity").ok(),
+            last_uid: row.get::<i64>("last_uid").unwrap_or(0) as u32,
+            highest_modseq: row.get::<i64>("modseq").ok().map(|m| m as u64),
+        },
+        None => FolderState::default(),
+    })
+}
+
+async fn save_state(graph: &Graph, account: &str, folder: &Folder, state: &FolderState) -> Result<()> {
+    let cypher = r#"
+        MATCH (f:ImapFolder {account: $account, name: $name})
+        SET f.uidvalidity = $uidvalidity, f.last_uid = $last_uid, f.highest_modseq = $modseq, f.synced_at = $now
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("account", account)
+                .param("name", folder.name.clone())
+                .param("uidvalidity", state.uidvalidity)
+                .param("last_uid", state.last_uid as i64)
+                .param("modseq", state.highest_modseq.map(|m| m as i64))
+                .param("now", chrono::Utc::now().to_rfc3339()),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Drop folder membership for UIDs no longer on the server, and the folder's
+/// label from emails that are not in another folder with that label.
+async fn unlink_missing(graph: &Graph, account: &str, folder: &Folder, present: &[u32]) -> Result<()> {
+    let cypher = r#"
+        MATCH (e:Email)-[r:IN_FOLDER]->(:ImapFolder {account: $account, name: $name})
+        WHERE NOT r.uid IN $present
+        DELETE r
+        WITH DISTINCT e
+        WHERE NOT EXISTS { MATCH (e)-[:IN_FOLDER]->(:ImapFolder {label: $label}) }
+        MATCH (e)-[h:HAS_LABEL]->(:Label {name: $label})
+        DELETE h
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("account", account)
+                .param("name", folder.name.clone())
+                .param("label", folder.label.clone())
+                .param("present", present.iter().map(|u| *u as i64).collect::<Vec<_>>()),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn link(graph: &Graph, account: &str, folder: &Folder, uid: u32, id: Uuid) -> Result<()> {
+    let cypher = r#"
+        MATCH (e:Email {id: $id}), (f:ImapFolder {account: $account, name: $name})
+        MERGE (e)-[r:IN_FOLDER {uid: $uid}]->(f)
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("id", id.to_string())
+                .param("account", account)
+                .param("name", folder.name.clone())
+                .param("uid", uid as i64),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn update_flags(graph: &Graph, account: &str, folder: &Folder, message: &FetchedMessage) -> Result<()> {
+    let cypher = r#"
+        MATCH (e:Email)-[:IN_FOLDER {uid: $uid}]->(:ImapFolder {account: $account, name: $name})
+        SET e.is_read = $seen, e.is_starred = $flagged
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("account", account)
+                .param("name", folder.name.clone())
+                .param("uid", message.uid as i64)
+                .param("seen", message.seen)
+                .param("flagged", message.flagged),
+        )
+        .await?;
+
+    let flagged_email = "MATCH (e:Email)-[:IN_FOLDER {uid: $uid}]->(:ImapFolder {account: $account, name: $name})";
+    graph
+        .run(
+            labels::starred_label_query(flagged_email)
+                .param("account", account)
+                .param("name", folder.name.clone())
+                .param("uid", message.uid as i64),
+        )
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct SyncStats {
+    fetched: usize,
+    created: usize,
+}
+
+/// Bring one folder up to date: handle a UIDVALIDITY change, refresh flags
+/// (only changed ones with CONDSTORE), drop expunged messages, then fetch
+/// everything above the last seen UID.
+async fn sync_folder(
+    graph: &Graph,
+    session: &mut ImapSession,
+    account: &ImapAccount,
+    folder: &Folder,
+    condstore: bool,
+) -> Result<SyncStats> {
+    let mailbox = if condstore {
+        session.select_condstore(&folder.name).await?
+    } else {
+        session.select(&folder.name).await?
+    };
+    let uidvalidity = mailbox.uid_validity.map(i64::from);
+    let mut state = load_state(graph, &account.name, folder).await?;
+
+    if state.uidvalidity.is_some() && state.uidvalidity != uidvalidity {
+        tracing::warn!(account = %account.name, folder = %folder.name, "UIDVALIDITY changed, resyncing folder");
+        unlink_missing(graph, &account.name, folder, &[]).await?;
+        state = FolderState::default();
+    }
+    state.uidvalidity = uidvalidity;
+
+    if state.last_uid > 0 {
+        let known = format!("1:{}", state.last_uid);
+        let present = search_uids(session, &format!("UID {}", known)).await?;
+        unlink_missing(graph, &account.name, folder, &present).await?;
+
+        let items = match (condstore, state.highest_modseq) {
+            (true, Some(modseq)) => format!("(UID FLAGS) (CHANGEDSINCE {})", modseq),
+            _ => "(UID FLAGS)".to_string(),
+        };
+        for message in fetch(session, &known, &items).await? {
+            update_flags(graph, &account.name, folder, &message).await?;
+        }
+    }
+
+    let mut stats = SyncStats::default();
+    let new_uids: Vec<u32> = search_uids(session, &format!("UID {}:*", state.last_uid + 1))
+        .await?
+        .into_iter()
+        .filter(|uid| *uid > state.last_uid)
+        .collect();
+
+    for batch in new_uids.chunks(FETCH_BATCH) {
+        let set = batch.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        for message in fetch(session, &set, "(UID FLAGS BODY.PEEK[])").await? {
+            stats.fetched += 1;
+            let stored = match message.raw.as_deref().map(|raw| (raw, ParsedMessage::parse(raw))) {
+                Some((raw, Ok(parsed))) => Some(
+                    inbound::store_message(
+                        graph,
+                        raw,
+                        &parsed,
+                        std::slice::from_ref(&folder.label),
+                        message.seen,
+                        message.flagged,
+                    )
+                    .await?,
+                ),
+                Some((_, Err(e))) => {
+                    tracing::warn!(account = %account.name, folder = %folder.name, uid = message.uid, "Skipping message: {}", e);
+                    None
+                }
+                None => None,
+            };
+            if let Some(stored) = stored {
+                link(graph, &account.name, folder, message.uid, stored.id).await?;
+                if stored.created {
+                    stats.created += 1;
+                } else {
+                    update_flags(graph, &account.name, folder, &message).await?;
+                }
+            }
+            state.last_uid = state.last_uid.max(message.uid);
+        }
+        save_state(graph, &account.name, folder, &state).await?;
+    }
+
+    state.highest_modseq = mailbox.highest_modseq;
+    save_state(graph, &account.name, folder, &state).await?;
+
+    // Unilateral responses are covered by the next sync; don't let them pile up
+    while session.unsolicited_responses.try_recv().is_ok() {}
+    Ok(stats)
+}
+
+fn poll_interval() -> Duration {
+    Duration::from_secs(
+        std::env::var("IMAP_POLL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(300),
+    )
+}
+
+/// Sync every folder, then wait in IDLE on the inbox (or sleep when the
+/// server lacks IDLE) and repeat. Returns only on error; `established` is set
+/// once a full round of folders has synced.
+async fn sync_forever(graph: &Graph, account: &ImapAccount, established: &mut bool) -> Result<()> {
+    let mut session = connect(account).await?;
+    let capabilities = session.capabilities().await?;
+    let condstore = capabilities.has_str("CONDSTORE");
+    let idle = capabilities.has_str("IDLE");
+
+    loop {
+        for folder in list_folders(&mut session, account).await? {
+            let stats = sync_folder(graph, &mut session, account, &folder, condstore).await?;
+            if stats.fetched > 0 {
+                tracing::info!(
+                    account = %account.name,
+                    folder = %folder.name,
+                    fetched = stats.fetched,
+                    created = stats.created,
+                    "IMAP folder synced"
+                );
+            }
+        }
+        *established = true;
+
+        if idle {
+            session.select("INBOX").await?;
+            let mut handle = session.idle();
+            handle.init().await?;
+            {
+                let (wait, _stop) = handle.wait_with_timeout(poll_interval());
+                wait.await?;
+            }
+            session = handle.done().await?;
+        } else {
+            tokio::time::sleep(poll_interval()).await;
+        }
+    }
+}
+
+/// Start a background sync task per configured account, reconnecting with
+/// backoff after failures.
+pub fn spawn_all(graph: Graph) -> Result<()> {
+    for account in accounts_from_env()? {
+        let graph = graph.clone();
+        tokio::spawn(async move {
+            const FIRST_DELAY: Duration = Duration::from_secs(5);
+            let mut delay = FIRST_DELAY;
+            loop {
+                let mut established = false;
+                if let Err(e) = sync_forever(&graph, &account, &mut established).await {
+                    tracing::error!(account = %account.name, "IMAP sync failed: {:#}", e);
+                }
+                // Back off only while reconnecting keeps failing
+                if established {
+                    delay = FIRST_DELAY;
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(300));
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const MESSAGE: &str = "From: Alice <alice@example.com>\r\nTo: me@example.com\r\nSubject: Hi\r\nMessage-ID: <hi@example.com>\r\n\r\nHello\r\n";
+
+    /// Minimal IMAP server with an INBOX holding UIDs 3 and 7 and a Sent folder.
+    async fn imap_stand_in() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut lines = BufReader::new(read).lines();
+
+            write.write_all(b"* OK IMAP4rev1 stand-in ready\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let (tag, command) = line.split_once(' ').unwrap();
+                let upper = command.to_uppercase();
+                let untagged = if upper.starts_with("CAPABILITY") {
+                    "* CAPABILITY IMAP4rev1 IDLE CONDSTORE\r\n".to_string()
+                } else if upper.starts_with("LIST") {
+                    "* LIST () \"/\" INBOX\r\n* LIST (\\Sent) \"/\" \"Sent Items\"\r\n* LIST (\\Noselect) \"/\" Archive\r\n* LIST () \"/\" Work/Clients\r\n".to_string()
+                } else if upper.starts_with("SELECT") {
+                    "* 2 EXISTS\r\n* OK [UIDVALIDITY 42] UIDs valid\r\n* OK [UIDNEXT 8] Predicted next UID\r\n* OK [HIGHESTMODSEQ 100] Highest\r\n".to_string()
+                } else if upper.starts_with("UID SEARCH") {
+                    "* SEARCH 3 7\r\n".to_string()
+                } else if upper.starts_with("UID FETCH") {
+                    format!(
+                        "* 1 FETCH (UID 3 FLAGS (\\Seen) BODY[] {{{}}}\r\n{})\r\n* 2 FETCH (UID 7 FLAGS (\\Flagged) BODY[] {{{}}}\r\n{})\r\n",
+                        MESSAGE.len(),
+                        MESSAGE,
+                        MESSAGE.len(),
+                        MESSAGE
+                    )
+                } else if upper.starts_with("LOGOUT") {
+                    "* BYE\r\n".to_string()
+                } else {
+                    String::new()
+                };
+                write.write_all(untagged.as_bytes()).await.unwrap();
+                write.write_all(format!("{} OK done\r\n", tag).as_bytes()).await.unwrap();
+            }
+        });
+
+        port
+    }
+
+    fn account(port: u16) -> ImapAccount {
+        ImapAccount {
+            name: "test".into(),
+            host: "127.0.0.1".into(),
+            port: Some(port),
+            tls: TlsMode::None,
+            username: "me".into(),
+            password: "secret".into(),
+            folders: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_folders_and_fetches_new_messages() {
+        let account = account(imap_stand_in().await);
+        let mut session = connect(&account).await.unwrap();
+
+        assert!(session.capabilities().await.unwrap().has_str("CONDSTORE"));
+
+        let folders = list_folders(&mut session, &account).await.unwrap();
+        let labels: Vec<_> = folders.iter().map(|f| f.label.as_str()).collect();
+        assert_eq!(labels, vec!["INBOX", "SENT", "Work/Clients"]);
+
+        let mailbox = session.select_condstore("INBOX").await.unwrap();
+        assert_eq!(mailbox.uid_validity, Some(42));
+        assert_eq!(mailbox.highest_modseq, Some(100));
+
+        assert_eq!(search_uids(&mut session, "UID 1:*").await.unwrap(), vec![3, 7]);
+
+        let messages = fetch(&mut session, "3,7", "(UID FLAGS BODY.PEEK[])").await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].seen && !messages[0].flagged);
+        assert!(!messages[1].seen && messages[1].flagged);
+
+        let parsed = ParsedMessage::parse(messages[0].raw.as_deref().unwrap()).unwrap();
+        assert_eq!(parsed.message_id, "<hi@example.com>");
+        assert_eq!(parsed.from.email, "alice@example.com");
+
+        session.logout().await.unwrap();
+    }
+
+    #[test]
+    fn maps_folders_to_labels() {
+        assert_eq!(folder_label("INBOX", Some("/"), &[]).as_deref(), Some("INBOX"));
+        assert_eq!(folder_label("[Gmail]/All Mail", Some("/"), &[NameAttribute::All]), None);
+        assert_eq!(folder_label("Papierkorb", Some("/"), &[NameAttribute::Trash]).as_deref(), Some("TRASH"));
+        assert_eq!(folder_label("Junk E-mail", Some("/"), &[]).as_deref(), Some("SPAM"));
+        assert_eq!(folder_label("Work.Clients", Some("."), &[]).as_deref(), Some("Work/Clients"));
+    }
+}
@@ -0,0 +1,529 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use neo4rs::Graph;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use uuid::Uuid;
+
+use crate::services::inbound::{self, ParsedMessage};
+
+/// Per-message failures kept in a report; the counters keep counting past this.
+const MAX_REPORTED_ERRORS: usize = 1000;
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// How much a zip may expand to. An oversized entry fails on its own; too
+/// many entries or too much data in total fails the whole archive.
+#[derive(Debug, Clone, Copy)]
+struct ZipLimits {
+    entry_bytes: u64,
+    total_bytes: u64,
+    entries: usize,
+}
+
+impl ZipLimits {
+    /// `IMPORT_MAX_ENTRY_MB` (default 50), `IMPORT_MAX_TOTAL_MB` (default 1024)
+    /// and `IMPORT_MAX_ENTRIES` (default 100000).
+    fn from_env() -> Self {
+        ZipLimits {
+            entry_bytes: env_u64("IMPORT_MAX_ENTRY_MB", 50) * 1024 * 1024,
+            total_bytes: env_u64("IMPORT_MAX_TOTAL_MB", 1024) * 1024 * 1024,
+            entries: env_u64("IMPORT_MAX_ENTRIES", 100_000) as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Mbox,
+    Maildir,
+    Eml,
+    Zip,
+}
+
+impl ImportFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "mbox" => Ok(ImportFormat::Mbox),
+            "maildir" => Ok(ImportFormat::Maildir),
+            "eml" => Ok(ImportFormat::Eml),
+            "zip" => Ok(ImportFormat::Zip),
+            other => Err(anyhow!("Unknown import format: {}", other)),
+        }
+    }
+
+    /// Guess from the path: directories with `cur`/`new` are Maildirs, other
+    /// directories hold `.eml` files, files go by extension and default to mbox.
+    pub fn detect(path: &Path) -> Self {
+        if path.is_dir() {
+            return if path.join("cur").is_dir() || path.join("new").is_dir() {
+                ImportFormat::Maildir
+            } else {
+                ImportFormat::Eml
+            };
+        }
+        match path.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+            Some("eml") => ImportFormat::Eml,
+            Some("zip") => ImportFormat::Zip,
+            _ => ImportFormat::Mbox,
+        }
+    }
+}
+
+/// One message read from an archive, before parsing.
+#[derive(Debug, Clone)]
+pub struct ImportMessage {
+    /// Where the message came from, e.g. `archive.mbox#12`, for error reports.
+    pub source: String,
+    pub raw: Vec<u8>,
+    pub seen: bool,
+    pub flagged: bool,
+    /// Why the message could not be read; it is reported as failed.
+    pub error: Option<String>,
+}
+
+impl ImportMessage {
+    fn new(source: String, raw: Vec<u8>) -> Self {
+        ImportMessage { source, raw, seen: true, flagged: false, error: None }
+    }
+
+    fn unreadable(source: String, error: String) -> Self {
+        ImportMessage { error: Some(error), ..ImportMessage::new(source, Vec::new()) }
+    }
+}
+
+fn is_from_line(line: &[u8]) -> bool {
+    line.starts_with(b"From ")
+}
+
+/// Split an mbox into messages. A `From ` line after a blank line (or at the
+/// start) begins a message; `>From ` quoting is undone (mboxrd).
+pub fn split_mbox(name: &str, data: &[u8]) -> Vec<ImportMessage> {
+    let mut messages = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut previous_blank = true;
+
+    let finish = |mut raw: Vec<u8>, messages: &mut Vec<ImportMessage>| {
+        // The blank line before the next separator belongs to the mbox, not the message
+        if raw.ends_with(b"\r\n\r\n") {
+            raw.truncate(raw.len() - 2);
+        } else if raw.ends_with(b"\n\n") {
+            raw.truncate(raw.len() - 1);
+        }
+        if !raw.iter().all(u8::is_ascii_whitespace) {
+            messages.push(ImportMessage::new(format!("{}#{}", name, messages.len() + 1), raw));
+        }
+    };
+
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        if previous_blank && is_from_line(line) {
+            if let Some(raw) = current.take() {
+                finish(raw, &mut messages);
+            }
+            current = Some(Vec::new());
+            previous_blank = false;
+            continue;
+        }
+
+        let raw = current.get_or_insert_with(Vec::new);
+        let quoted = line.iter().take_while(|b| **b == b'>').count();
+        if quoted > 0 && is_from_line(&line[quoted..]) {
+            raw.extend_from_slice(&line[1..]);
+        } else {
+            raw.extend_from_slice(line);
+        }
+        previous_blank = line == b"\n" || line == b"\r\n";
+    }
+    if let Some(raw) = current {
+        finish(raw, &mut messages);
+    }
+
+    messages
+}
+
+/// Seen/flagged state from a Maildir file name such as `123.abc:2,FS`.
+fn maildir_flags(file_name: &str) -> (bool, bool) {
+    match file_name.rsplit_once(":2,") {
+        Some((_, flags)) => (flags.contains('S'), flags.contains('F')),
+        None => (false, false),
+    }
+}
+
+fn read_sorted_dir(dir: &Path) -> Result<Vec<std::path::PathBuf>> {
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("Cannot read {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Read every message under `path` for the CLI.
+pub fn read_path(path: &Path, format: ImportFormat) -> Result<Vec<ImportMessage>> {
+    let read = |path: &Path| std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()));
+    let name = path.display().to_string();
+
+    match format {
+        ImportFormat::Mbox => Ok(split_mbox(&name, &read(path)?)),
+        ImportFormat::Zip => read_zip(&name, read(path)?, ZipLimits::from_env()),
+        ImportFormat::Eml if path.is_file() => Ok(vec![ImportMessage::new(name, read(path)?)]),
+        ImportFormat::Eml => {
+            let mut messages = Vec::new();
+            for file in read_sorted_dir(path)? {
+                if file.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("eml")) {
+                    messages.push(ImportMessage::new(file.display().to_string(), read(&file)?));
+                }
+            }
+            Ok(messages)
+        }
+        ImportFormat::Maildir => {
+            let mut messages = Vec::new();
+            for sub in ["cur", "new"] {
+                let dir = path.join(sub);
+                if !dir.is_dir() {
+                    continue;
+                }
+                for file in read_sorted_dir(&dir)? {
+                    let file_name = file.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+                    if file_name.starts_with('.') || !file.is_file() {
+                        continue;
+                    }
+                    let (seen, flagged) = maildir_flags(file_name);
+                    messages.push(ImportMessage {
+                        seen,
+                        flagged,
+                        ..ImportMessage::new(file.display().to_string(), read(&file)?)
+                    });
+                }
+            }
+            Ok(messages)
+        }
+    }
+}
+
+/// A zip may hold `.eml` files, `.mbox` files and Maildir `cur/`/`new/` folders.
+fn read_zip(name: &str, data: Vec<u8>, limits: ZipLimits) -> Result<Vec<ImportMessage>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).with_context(|| format!("Invalid zip file {}", name))?;
+    if archive.len() > limits.entries {
+        return Err(anyhow!("Zip file {} has more than {} entries", name, limits.entries));
+    }
+    let mut messages = Vec::new();
+    let mut remaining = limits.total_bytes;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let path = entry.name().to_string();
+        let source = format!("{}:{}", name, path);
+        let file_name = path.rsplit('/').next().unwrap_or_default().to_string();
+        let in_maildir = path.contains("cur/") || path.contains("new/");
+        let lower = file_name.to_lowercase();
+        if file_name.starts_with('.') || !(lower.ends_with(".eml") || lower.ends_with(".mbox") || in_maildir) {
+            continue;
+        }
+
+        let mut raw = Vec::new();
+        (&mut entry)
+            .take(limits.entry_bytes.min(remaining) + 1)
+            .read_to_end(&mut raw)?;
+        let size = raw.len() as u64;
+        if size > limits.entry_bytes {
+            messages.push(ImportMessage::unreadable(
+                source,
+                format!("Entry exceeds the {} byte limit", limits.entry_bytes),
+            ));
+            continue;
+        }
+        if size > remaining {
+            return Err(anyhow!("Zip file {} expands to more than {} bytes", name, limits.total_bytes));
+        }
+        remaining -= size;
+        if lower.ends_with(".mbox") {
+            messages.extend(split_mbox(&source, &raw));
+        } else if lower.ends_with(".eml") {
+            messages.push(ImportMessage::new(source, raw));
+        } else {
+            let (seen, flagged) = maildir_flags(&file_name);
+            messages.push(ImportMessage { seen, flagged, ..ImportMessage::new(source, raw) });
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Messages from an uploaded file: `.eml`, `.zip`, or an mbox.
+pub fn read_upload(file_name: &str, data: Vec<u8>) -> Result<Vec<ImportMessage>> {
+    match ImportFormat::detect(Path::new(file_name)) {
+        ImportFormat::Eml => Ok(vec![ImportMessage::new(file_name.to_string(), data)]),
+        ImportFormat::Zip => read_zip(file_name, data, ZipLimits::from_env()),
+        _ => Ok(split_mbox(file_name, &data)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Running,
+    Completed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportFailure {
+    pub source: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgress {
+    pub id: Uuid,
+    pub status: ImportStatus,
+    pub total: usize,
+    pub processed: usize,
+    pub imported: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+    pub errors: Vec<ImportFailure>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl ImportProgress {
+    fn new(total: usize) -> Self {
+        ImportProgress {
+            id: Uuid::new_v4(),
+            status: ImportStatus::Running,
+            total,
+            processed: 0,
+            imported: 0,
+            duplicates: 0,
+            failed: 0,
+            errors: Vec::new(),
+            started_at: Utc::now(),
+            finished_at: None,
+        }
+    }
+}
+
+/// Store each message like any received mail, skipping `Message-ID`s that are
+/// already present. Messages without flags are imported as read.
+/// `on_progress` is called after every message.
+async fn import_messages(
+    graph: &Graph,
+    messages: Vec<ImportMessage>,
+    labels: &[String],
+    mut progress: ImportProgress,
+    mut on_progress: impl FnMut(&ImportProgress),
+) -> ImportProgress {
+    for message in messages {
+        let outcome = match message.error {
+            Some(error) => Err(anyhow!(error)),
+            None => match ParsedMessage::parse(&message.raw) {
+                Ok(parsed) => {
+                    inbound::store_message(graph, &message.raw, &parsed, labels, message.seen, message.flagged)
+                        .await
+                }
+                Err(e) => Err(e),
+            },
+        };
+
+        progress.processed += 1;
+        match outcome {
+            Ok(stored) if stored.created => progress.imported += 1,
+            Ok(_) => progress.duplicates += 1,
+            Err(e) => {
+                progress.failed += 1;
+                if progress.errors.len() < MAX_REPORTED_ERRORS {
+                    progress.errors.push(ImportFailure { source: message.source, error: e.to_string() });
+                }
+            }
+        }
+        on_progress(&progress);
+    }
+
+    progress.status = ImportStatus::Completed;
+    progress.finished_at = Some(Utc::now());
+    on_progress(&progress);
+    progress
+}
+
+pub async fn run_import(
+    graph: &Graph,
+    messages: Vec<ImportMessage>,
+    labels: &[String],
+    on_progress: impl FnMut(&ImportProgress),
+) -> ImportProgress {
+    let progress = ImportProgress::new(messages.len());
+    import_messages(graph, messages, labels, progress, on_progress).await
+}
+
+static JOBS: OnceLock<Mutex<HashMap<Uuid, ImportProgress>>> = OnceLock::new();
+
+fn jobs() -> &'static Mutex<HashMap<Uuid, ImportProgress>> {
+    JOBS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// How long a finished job stays pollable, from `IMPORT_JOB_RETENTION_SECS`
+/// (default 3600).
+fn job_retention() -> chrono::Duration {
+    chrono::Duration::seconds(
+        std::env::var("IMPORT_JOB_RETENTION_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(3600),
+    )
+}
+
+/// Drop jobs that finished more than `retention` before `now`.
+fn expire_jobs(jobs: &mut HashMap<Uuid, ImportProgress>, now: DateTime<Utc>, retention: chrono::Duration) {
+    jobs.retain(|_, job| job.finished_at.is_none_or(|finished| finished + retention > now));
+}
+
+/// Run an import in the background; poll it with [`job`].
+pub fn start_job(graph: Graph, messages: Vec<ImportMessage>, labels: Vec<String>) -> ImportProgress {
+    let progress = ImportProgress::new(messages.len());
+    let id = progress.id;
+    if let Ok(mut jobs) = jobs().lock() {
+        expire_jobs(&mut jobs, Utc::now(), job_retention());
+        jobs.insert(id, progress.clone());
+    }
+
+    let initial = progress.clone();
+    tokio::spawn(async move {
+        let report = import_messages(&graph, messages, &labels, progress, |p| {
+            if let Ok(mut jobs) = jobs().lock() {
+                jobs.insert(id, p.clone());
+            }
+        })
+        .await;
+        tracing::info!(
+            import = %id,
+            imported = report.imported,
+            duplicates = report.duplicates,
+            failed = report.failed,
+            "Import finished"
+        );
+    });
+
+    initial
+}
+
+pub fn job(id: Uuid) -> Option<ImportProgress> {
+    let mut jobs = jobs().lock().ok()?;
+    expire_jobs(&mut jobs, Utc::now(), job_retention());
+    jobs.get(&id).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn splits_mbox_and_unquotes_from_lines() {
+        let mbox = b"From alice@example.com Tue Oct  1 10:00:00 2024\n\
+Subject: One\n\
+\n\
+>From the start.\n\
+>>From quoted twice.\n\
+\n\
+From bob@example.com Tue Oct  1 11:00:00 2024\n\
+Subject: Two\n\
+\n\
+Body.\n\
+From here on, not a separator.\n";
+
+        let messages = split_mbox("test.mbox", mbox);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].source, "test.mbox#1");
+        assert_eq!(
+            String::from_utf8_lossy(&messages[0].raw),
+            "Subject: One\n\nFrom the start.\n>From quoted twice.\n"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&messages[1].raw),
+            "Subject: Two\n\nBody.\nFrom here on, not a separator.\n"
+        );
+    }
+
+    #[test]
+    fn reads_maildir_flags_and_zip_archives() {
+        assert_eq!(maildir_flags("1700000000.abc.host:2,FS"), (true, true));
+        assert_eq!(maildir_flags("1700000000.abc.host"), (false, false));
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("Mail/cur/1.host:2,S", options).unwrap();
+        zip.write_all(b"Subject: Read\r\n\r\nbody").unwrap();
+        zip.start_file("one.eml", options).unwrap();
+        zip.write_all(b"Subject: Eml\r\n\r\nbody").unwrap();
+        zip.start_file("notes.txt", options).unwrap();
+        zip.write_all(b"ignored").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        let messages = read_upload("archive.zip", data).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].seen && !messages[0].flagged);
+        assert_eq!(messages[1].source, "archive.zip:one.eml");
+    }
+
+    #[test]
+    fn expires_finished_jobs_after_retention() {
+        let now = Utc::now();
+        let running = ImportProgress::new(1);
+        let mut recent = ImportProgress::new(1);
+        recent.finished_at = Some(now - chrono::Duration::minutes(5));
+        let mut old = ImportProgress::new(1);
+        old.finished_at = Some(now - chrono::Duration::hours(2));
+
+        let mut jobs: HashMap<Uuid, ImportProgress> =
+            [&running, &recent, &old].into_iter().map(|j| (j.id, j.clone())).collect();
+        expire_jobs(&mut jobs, now, chrono::Duration::hours(1));
+        assert!(jobs.contains_key(&running.id));
+        assert!(jobs.contains_key(&recent.id));
+        assert!(!jobs.contains_key(&old.id));
+    }
+
+    #[test]
+    fn oversized_zip_entries_are_not_unpacked() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("small.eml", options).unwrap();
+        zip.write_all(b"Subject: Small\r\n\r\nbody").unwrap();
+        zip.start_file("large.eml", options).unwrap();
+        zip.write_all(&vec![b'x'; 1024]).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        let limits = ZipLimits { entry_bytes: 64, total_bytes: 1024, entries: 10 };
+        let messages = read_zip("archive.zip", data.clone(), limits).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].error.is_none());
+        assert!(messages[1].raw.is_empty());
+        assert_eq!(messages[1].error.as_deref(), Some("Entry exceeds the 64 byte limit"));
+
+        let err = read_zip("archive.zip", data.clone(), ZipLimits { entries: 1, ..limits }).unwrap_err();
+        assert_eq!(err.to_string(), "Zip file archive.zip has more than 1 entries");
+    }
+
+    #[test]
+    fn zips_expanding_past_the_total_budget_fail() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        for i in 0..4 {
+            zip.start_file(format!("{}.eml", i), options).unwrap();
+            zip.write_all(&[b'x'; 40]).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        let limits = ZipLimits { entry_bytes: 64, total_bytes: 160, entries: 10 };
+        assert_eq!(read_zip("archive.zip", data.clone(), limits).unwrap().len(), 4);
+        let err = read_zip("archive.zip", data, ZipLimits { total_bytes: 100, ..limits }).unwrap_err();
+        assert_eq!(err.to_string(), "Zip file archive.zip expands to more than 100 bytes");
+    }
+}
@@ -0,0 +1,359 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use mail_parser::{Address, Message, MessageParser, MimeHeaders};
+use neo4rs::{query, Graph};
+use uuid::Uuid;
+
+use crate::models::Contact;
+use crate::services::{attachments, blobs, html, threading};
+
+/// The parts of a received message that are mapped onto the graph.
+#[derive(Debug, Clone)]
+pub struct ParsedMessage {
+    /// `Message-ID` with angle brackets; derived from a hash of the raw message
+    /// when the header is missing, so re-importing it is still deduplicated.
+    pub message_id: String,
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    pub subject: String,
+    pub from: Contact,
+    pub to: Vec<Contact>,
+    pub cc: Vec<Contact>,
+    pub date: DateTime<Utc>,
+    /// Plain-text rendering of every inline body part.
+    pub body: String,
+    /// Sanitized HTML, present only when the message has real HTML parts.
+    pub html_body: Option<String>,
+    pub attachments: Vec<ParsedAttachment>,
+}
+
+/// A decoded attachment part.
+#[derive(Debug, Clone)]
+pub struct ParsedAttachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+fn bracketed(id: &str) -> String {
+    format!("<{}>", id.trim().trim_start_matches('<').trim_end_matches('>'))
+}
+
+fn contacts(address: Option<&Address>) -> Vec<Contact> {
+    address
+        .map(|a| {
+            a.iter()
+                .filter_map(|addr| {
+                    Some(Contact {
+                        email: addr.address()?.to_lowercase(),
+                        name: addr.name().map(str::to_string),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Inline bodies in order, joined. mail-parser has already undone the transfer
+/// encoding and converted the charset to UTF-8.
+fn bodies(message: &Message, text: bool) -> Option<String> {
+    let count = if text { message.text_body_count() } else { message.html_body_count() };
+    let parts = (0..count)
+        .filter_map(|i| {
+            if text {
+                message.body_text(i)
+            } else {
+                // mail-parser synthesizes HTML for text-only parts; keep real HTML only
+                message
+                    .html_part(i)
+                    .filter(|part| part.is_text_html())
+                    .and_then(|_| message.body_html(i))
+            }
+        })
+        .map(|body| body.trim_end().to_string())
+        .filter(|body| !body.is_empty())
+        .collect::<Vec<_>>();
+    (!parts.is_empty()).then(|| parts.join("\n\n"))
+}
+
+fn parsed_attachments(message: &Message) -> Vec<ParsedAttachment> {
+    message
+        .attachments()
+        .enumerate()
+        .map(|(i, part)| {
+            let content_type = part
+                .content_type()
+                .map(|ct| match ct.subtype() {
+                    Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                    None => ct.ctype().to_string(),
+                })
+                .unwrap_or_else(|| "application/octet-stream".into())
+                .to_lowercase();
+            let filename = part.attachment_name().map(str::to_string).unwrap_or_else(|| {
+                let extension = if part.is_message() { "eml" } else { "bin" };
+                format!("attachment-{}.{}", i + 1, extension)
+            });
+            ParsedAttachment { filename, content_type, data: part.contents().to_vec() }
+        })
+        .collect()
+}
+
+impl ParsedMessage {
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let message = MessageParser::default()
+            .parse(raw)
+            .ok_or_else(|| anyhow!("Invalid message: cannot parse headers"))?;
+
+        let from = contacts(message.from())
+            .into_iter()
+            .next()
+            .unwrap_or(Contact { email: "unknown@example.com".into(), name: None });
+        let date = message
+            .date()
+            .and_then(|d| Utc.timestamp_opt(d.to_timestamp(), 0).single())
+            .unwrap_or_else(Utc::now);
+
+        Ok(ParsedMessage {
+            message_id: message
+                .message_id()
+                .map(bracketed)
+                .unwrap_or_else(|| format!("<{}@generated.local>", blobs::sha256(raw))),
+            in_reply_to: message
+                .in_reply_to()
+                .as_text_list()
+                .and_then(|ids| ids.last().map(|id| bracketed(id))),
+            references: message
+                .references()
+                .as_text_list()
+                .map(|ids| ids.into_iter().map(bracketed).collect())
+                .unwrap_or_default(),
+            subject: message.subject().unwrap_or_default().to_string(),
+            from,
+            to: contacts(message.to()),
+            cc: contacts(message.cc()),
+            date,
+            body: bodies(&message, true).unwrap_or_default(),
+            html_body: bodies(&message, false).map(|html| html::sanitize(&html)),
+            attachments: parsed_attachments(&message),
+        })
+    }
+}
+
+/// Result of storing a received message.
+#[derive(Debug, Clone, Copy)]
+pub struct Stored {
+    pub id: Uuid,
+    /// `false` when an email with the same `Message-ID` already existed.
+    pub created: bool,
+}
+
+/// Store a received message, or add the labels to the existing email with the
+/// same `Message-ID`. New emails are threaded with the messages they refer to.
+pub async fn store_message(
+    graph: &Graph,
+    raw: &[u8],
+    parsed: &ParsedMessage,
+    labels: &[String],
+    is_read: bool,
+    is_starred: bool,
+) -> Result<Stored> {
+    let id = Uuid::new_v4();
+    let snippet = html::snippet(&parsed.body);
+    let raw_sha256 = blobs::put(raw).await?;
+
+    // One statement, backed by the unique Message-ID constraint, so concurrent
+    // syncs and imports of the same message store it once
+    let cypher = r#"
+        MERGE (e:Email {message_id: $message_id})
+        ON CREATE SET
+            e.id = $id,
+            e.subject = $subject,
+            e.body = $body,
+            e.html_body = $html_body,
+            e.snippet = $snippet,
+            e.date = $date,
+            e.is_read = $is_read,
+            e.is_starred = $is_starred,
+            e.in_reply_to = $in_reply_to,
+            e.references = $references,
+            e.raw_sha256 = $raw_sha256
+        WITH e, e.id = $id as created
+        FOREACH (_ IN CASE WHEN created THEN [1] ELSE [] END |
+            MERGE (from:Contact {email: $from_email})
+            ON CREATE SET from.name = $from_name
+            CREATE (e)-[:SENT_BY]->(from)
+        )
+        RETURN e.id as id, created
+    "#;
+    let mut result = graph
+        .execute(
+            query(cypher)
+                .param("id", id.to_string())
+                .param("subject", parsed.subject.clone())
+                .param("body", parsed.body.clone())
+                .param("html_body", parsed.html_body.clone())
+                .param("snippet", snippet)
+                .param("date", parsed.date.to_rfc3339())
+                .param("is_read", is_read)
+                .param("is_starred", is_starred || labels.iter().any(|l| l == "STARRED"))
+                .param("message_id", parsed.message_id.clone())
+                .param("in_reply_to", parsed.in_reply_to.clone())
+                .param("references", parsed.references.clone())
+                .param("raw_sha256", raw_sha256)
+                .param("from_email", parsed.from.email.clone())
+                .param("from_name", parsed.from.name.clone()),
+        )
+        .await?;
+    let row = result.next().await?.ok_or_else(|| anyhow!("Failed to store message"))?;
+    if !row.get::<bool>("created").unwrap_or(false) {
+        let id = Uuid::parse_str(&row.get::<String>("id")?)?;
+        add_labels(graph, id, labels).await?;
+        return Ok(Stored { id, created: false });
+    }
+
+    for (rel, recipients) in [("SENT_TO", &parsed.to), ("CC", &parsed.cc)] {
+        for contact in recipients {
+            let cypher = format!(
+                r#"
+                MATCH (e:Email {{id: $id}})
+                MERGE (c:Contact {{email: $email}})
+                ON CREATE SET c.name = $name
+                MERGE (e)-[:{}]->(c)
+                "#,
+                rel
+            );
+            graph
+                .run(
+                    query(&cypher)
+                        .param("id", id.to_string())
+                        .param("email", contact.email.clone())
+                        .param("name", contact.name.clone()),
+                )
+                .await?;
+        }
+    }
+
+    let thread_id = threading::attach(graph, id).await?;
+
+    for attachment in &parsed.attachments {
+        attachments::store(graph, Some(id), &attachment.filename, &attachment.content_type, &attachment.data).await?;
+    }
+
+    graph
+        .run(crate::services::labels::starred_label_query("MATCH (e:Email {id: $id})").param("id", id.to_string()))
+        .await?;
+
+    if threading::is_muted(graph, thread_id).await? {
+        let labels: Vec<String> = labels.iter().filter(|l| *l != "INBOX").cloned().collect();
+        add_labels(graph, id, &labels).await?;
+    } else {
+        add_labels(graph, id, labels).await?;
+    }
+    Ok(Stored { id, created: true })
+}
+
+/// Attach labels to an email, creating missing labels. Starring and trashing
+/// through labels is reflected on the email.
+pub async fn add_labels(graph: &Graph, id: Uuid, labels: &[String]) -> Result<()> {
+    let cypher = r#"
+        MATCH (e:Email {id: $id})
+        UNWIND $labels as name
+        MERGE (l:Label {name: name})
+        ON CREATE SET l.color = '#9e9e9e', l.ai_excluded = false
+        MERGE (e)-[:HAS_LABEL]->(l)
+    "#;
+    graph
+        .run(query(cypher).param("id", id.to_string()).param("labels", labels.to_vec()))
+        .await?;
+    graph.run(crate::services::labels::hierarchy_query(labels)).await?;
+
+    let this_email = "MATCH (e:Email {id: $id})";
+    graph
+        .run(crate::services::labels::trashed_at_query(this_email).param("id", id.to_string()))
+        .await?;
+    if labels.iter().any(|l| l == "STARRED") {
+        graph.run(query("MATCH (e:Email {id: $id}) SET e.is_starred = true").param("id", id.to_string())).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headers_and_bodies() {
+        let raw = b"From: =?utf-8?q?Zo=C3=AB?= <Zoe@Example.com>\r\n\
+To: alice@example.com, Bob <bob@example.com>\r\n\
+Cc: carol@example.com\r\n\
+Subject: =?utf-8?b?Q2Fmw6k=?=\r\n\
+Date: Tue, 1 Oct 2024 10:00:00 +0200\r\n\
+Message-ID: <child@example.com>\r\n\
+In-Reply-To: <parent@example.com>\r\n\
+References: <root@example.com> <parent@example.com>\r\n\
+\r\n\
+Hello there.\r\n";
+
+        let parsed = ParsedMessage::parse(raw).unwrap();
+        assert_eq!(parsed.message_id, "<child@example.com>");
+        assert_eq!(parsed.in_reply_to.as_deref(), Some("<parent@example.com>"));
+        assert_eq!(parsed.references, vec!["<root@example.com>", "<parent@example.com>"]);
+        assert_eq!(parsed.subject, "Café");
+        assert_eq!(parsed.from.email, "zoe@example.com");
+        assert_eq!(parsed.from.name.as_deref(), Some("Zoë"));
+        assert_eq!(parsed.to.len(), 2);
+        assert_eq!(parsed.cc[0].email, "carol@example.com");
+        assert_eq!(parsed.date.to_rfc3339(), "2024-10-01T08:00:00+00:00");
+        assert_eq!(parsed.body.trim(), "Hello there.");
+        assert!(parsed.html_body.is_none());
+    }
+
+    #[test]
+    fn synthesizes_stable_message_ids() {
+        let raw = b"From: alice@example.com\r\nSubject: No id\r\n\r\nBody.\r\n";
+        let first = ParsedMessage::parse(raw).unwrap().message_id;
+        assert_eq!(first, ParsedMessage::parse(raw).unwrap().message_id);
+        assert!(first.ends_with("@generated.local>"));
+
+        let other = b"From: alice@example.com\r\nSubject: No id\r\n\r\nOther.\r\n";
+        assert_ne!(first, ParsedMessage::parse(other).unwrap().message_id);
+    }
+
+    #[test]
+    fn decodes_multipart_bodies() {
+        let raw = b"From: alice@example.com\r\n\
+Subject: =?iso-8859-1?q?R=E9sum=E9?=\r\n\
+Content-Type: multipart/mixed; boundary=outer\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=inner\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain; charset=iso-8859-1\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Voil=E0 mon r=E9sum=E9.\r\n\
+--inner\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+PHA+Vm9pbMOgPHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0PjwvcD4=\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=cv.pdf\r\n\
+Content-Disposition: attachment; filename=cv.pdf\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0=\r\n\
+--outer--\r\n";
+
+        let parsed = ParsedMessage::parse(raw).unwrap();
+        assert_eq!(parsed.subject, "Résumé");
+        assert_eq!(parsed.body, "Voilà mon résumé.");
+        assert_eq!(parsed.html_body.as_deref(), Some("<p>Voilà</p>"));
+        assert_eq!(parsed.attachments.len(), 1);
+        assert_eq!(parsed.attachments[0].filename, "cv.pdf");
+        assert_eq!(parsed.attachments[0].content_type, "application/pdf");
+        assert_eq!(parsed.attachments[0].data, b"%PDF-");
+    }
+}
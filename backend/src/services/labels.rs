@@ -1,13 +1,130 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
 use neo4rs::{query, Graph};
+use std::collections::HashMap;
 
-use crate::models::{CreateLabelRequest, Label};
+use crate::models::{
+    CreateLabelRequest, Label, LabelListVisibility, MessageListVisibility, UndoToken, UpdateLabelRequest,
+};
+use crate::services::{db::run_atomically, undo};
+
+const SYSTEM_LABELS: [&str; 7] = ["INBOX", "SENT", "DRAFTS", "SPAM", "TRASH", "STARRED", "IMPORTANT"];
+
+const DEFAULT_COLOR: &str = "#9e9e9e";
+
+/// Labels left out of listings and search unless asked for explicitly.
+pub(crate) const HIDDEN_LABELS: [&str; 2] = ["SPAM", "TRASH"];
+
+/// Nested label names are `/`-separated paths without empty segments.
+fn validate_name(name: &str) -> Result<()> {
+    if name.split('/').any(|segment| segment.is_empty() || segment.trim() != segment) {
+        return Err(anyhow!("Invalid label name: {}", name));
+    }
+    Ok(())
+}
+
+/// `#rgb` or `#rrggbb`.
+fn validate_color(color: &str) -> Result<()> {
+    let valid = color
+        .strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()));
+    if !valid {
+        return Err(anyhow!("Invalid color: {}", color));
+    }
+    Ok(())
+}
+
+/// Every (label, enclosing label) pair implied by nested `names`.
+fn parent_pairs(names: &[String]) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    for name in names {
+        let mut child = name.as_str();
+        while let Some((parent, _)) = child.rsplit_once('/') {
+            let pair = (child.to_string(), parent.to_string());
+            if !pairs.contains(&pair) {
+                pairs.push(pair);
+            }
+            child = parent;
+        }
+    }
+    pairs
+}
+
+/// Create the missing ancestors of nested labels and link each label to its
+/// parent with `CHILD_OF`. Used wherever labels are created by name.
+pub(crate) fn hierarchy_query(names: &[String]) -> neo4rs::Query {
+    let pairs: Vec<HashMap<String, String>> = parent_pairs(names)
+        .into_iter()
+        .map(|(child, parent)| HashMap::from([("child".to_string(), child), ("parent".to_string(), parent)]))
+        .collect();
+    query(
+        r#"
+        UNWIND $pairs as pair
+        MERGE (c:Label {name: pair.child})
+        ON CREATE SET c.color = $color, c.ai_excluded = false
+        MERGE (p:Label {name: pair.parent})
+        ON CREATE SET p.color = $color, p.ai_excluded = false
+        MERGE (c)-[:CHILD_OF]->(p)
+        "#,
+    )
+    .param("pairs", pairs)
+    .param("color", DEFAULT_COLOR)
+}
+
+/// Give the emails bound to `e` by `match_clause` the `STARRED` label exactly
+/// when `is_starred` is set.
+pub(crate) fn starred_label_query(match_clause: &str) -> neo4rs::Query {
+    query(&format!(
+        r#"
+        {}
+        MATCH (s:Label {{name: 'STARRED'}})
+        FOREACH (_ IN CASE WHEN e.is_starred THEN [1] ELSE [] END | MERGE (e)-[:HAS_LABEL]->(s))
+        WITH e, s
+        OPTIONAL MATCH (e)-[r:HAS_LABEL]->(s)
+        WHERE NOT coalesce(e.is_starred, false)
+        DELETE r
+        "#,
+        match_clause
+    ))
+}
+
+/// Set `is_starred` from the `STARRED` label after labels were replaced.
+pub(crate) fn starred_flag_query(match_clause: &str) -> neo4rs::Query {
+    query(&format!(
+        "{} SET e.is_starred = EXISTS {{ MATCH (e)-[:HAS_LABEL]->(:Label {{name: 'STARRED'}}) }}",
+        match_clause
+    ))
+}
+
+/// Record when emails entered `TRASH`, which starts their retention period,
+/// and clear it for emails taken out again.
+pub(crate) fn trashed_at_query(match_clause: &str) -> neo4rs::Query {
+    query(&format!(
+        r#"
+        {}
+        SET e.trashed_at = CASE
+            WHEN EXISTS {{ MATCH (e)-[:HAS_LABEL]->(:Label {{name: 'TRASH'}}) }} THEN coalesce(e.trashed_at, $now)
+        END
+        "#,
+        match_clause
+    ))
+    .param("now", Utc::now().to_rfc3339())
+}
 
 pub async fn list_labels(graph: &Graph) -> Result<Vec<Label>> {
     let cypher = r#"
         MATCH (l:Label)
         OPTIONAL MATCH (e:Email)-[:HAS_LABEL]->(l)
-        RETURN l.name as name, l.color as color, count(e) as email_count
+        WITH l, count(e) as email_count,
+             count(CASE WHEN e.is_read = false THEN e END) as unread_count,
+             count(DISTINCT e.thread_id) as thread_count
+        OPTIONAL MATCH (d:Label)-[:CHILD_OF*0..]->(l)
+        OPTIONAL MATCH (x:Email)-[:HAS_LABEL]->(d)
+        WITH l, email_count, unread_count, thread_count, count(DISTINCT x) as total_count
+        OPTIONAL MATCH (l)-[:CHILD_OF]->(p:Label)
+        RETURN l.name as name, l.color as color, email_count, total_count, unread_count, thread_count,
+               p.name as parent, coalesce(l.ai_excluded, false) as ai_excluded,
+               l.list_visibility as list_visibility, l.message_list_visibility as message_list_visibility
         ORDER BY l.name
     "#;
 
@@ -19,47 +136,210 @@ pub async fn list_labels(graph: &Graph) -> Result<Vec<Label>> {
             name: row.get("name")?,
             color: row.get("color").ok(),
             email_count: row.get::<i64>("email_count").unwrap_or(0) as u64,
+            total_count: row.get::<i64>("total_count").unwrap_or(0) as u64,
+            parent: row.get("parent").ok(),
+            unread_count: row.get::<i64>("unread_count").unwrap_or(0) as u64,
+            thread_count: row.get::<i64>("thread_count").unwrap_or(0) as u64,
+            ai_excluded: row.get("ai_excluded").unwrap_or(false),
+            list_visibility: LabelListVisibility::from_stored(row.get("list_visibility").ok()),
+            message_list_visibility: MessageListVisibility::from_stored(row.get("message_list_visibility").ok()),
         });
     }
 
     Ok(labels)
 }
 
+async fn find_label(graph: &Graph, name: &str) -> Result<Label> {
+    list_labels(graph)
+        .await?
+        .into_iter()
+        .find(|l| l.name == name)
+        .ok_or_else(|| anyhow!("Label not found"))
+}
+
+async fn label_exists(graph: &Graph, name: &str) -> Result<bool> {
+    let mut result = graph
+        .execute(query("MATCH (l:Label {name: $name}) RETURN count(l) > 0 as exists").param("name", name))
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get("exists").unwrap_or(false),
+        None => false,
+    })
+}
+
+/// Create a label; nested names create their missing parents too.
 pub async fn create_label(graph: &Graph, req: CreateLabelRequest) -> Result<Label> {
+    validate_name(&req.name)?;
+    if let Some(color) = &req.color {
+        validate_color(color)?;
+    }
+    if label_exists(graph, &req.name).await? {
+        return Err(anyhow!("Label already exists"));
+    }
+
     let cypher = r#"
-        CREATE (l:Label {name: $name, color: $color})
-        RETURN l.name as name, l.color as color, 0 as email_count
+        CREATE (l:Label {
+            name: $name,
+            color: $color,
+            ai_excluded: $ai_excluded,
+            list_visibility: $list_visibility,
+            message_list_visibility: $message_list_visibility
+        })
     "#;
+    run_atomically(
+        graph,
+        vec![
+            query(cypher)
+                .param("name", req.name.clone())
+                .param("color", req.color.unwrap_or_else(|| DEFAULT_COLOR.into()))
+                .param("ai_excluded", req.ai_excluded)
+                .param("list_visibility", req.list_visibility.as_str())
+                .param("message_list_visibility", req.message_list_visibility.as_str()),
+            hierarchy_query(std::slice::from_ref(&req.name)),
+        ],
+    )
+    .await?;
 
-    let mut result = graph.execute(
-        query(cypher)
-            .param("name", req.name.clone())
-            .param("color", req.color.clone().unwrap_or_else(|| "#9e9e9e".into()))
-    ).await?;
+    find_label(graph, &req.name).await
+}
+
+/// Queries renaming `old` and everything nested under it to live under `new`.
+async fn rename_queries(graph: &Graph, old: &str, new: &str) -> Result<Vec<neo4rs::Query>> {
+    if SYSTEM_LABELS.contains(&old) {
+        return Err(anyhow!("Cannot rename system label"));
+    }
+    validate_name(new)?;
+    if new.starts_with(&format!("{}/", old)) {
+        return Err(anyhow!("Invalid label name: cannot nest a label under itself"));
+    }
 
+    let conflict_cypher = r#"
+        MATCH (d:Label)-[:CHILD_OF*0..]->(:Label {name: $old})
+        WITH collect(d) as subtree, collect($new + substring(d.name, size($old))) as targets
+        MATCH (x:Label)
+        WHERE x.name IN targets AND NOT x IN subtree
+        RETURN count(x) > 0 as conflict
+    "#;
+    let mut result = graph
+        .execute(query(conflict_cypher).param("old", old).param("new", new))
+        .await?;
     if let Some(row) = result.next().await? {
-        return Ok(Label {
-            name: row.get("name")?,
-            color: row.get("color").ok(),
-            email_count: 0,
-        });
+        if row.get("conflict").unwrap_or(false) {
+            return Err(anyhow!("Label already exists"));
+        }
     }
 
-    Ok(Label {
-        name: req.name,
-        color: req.color,
-        email_count: 0,
-    })
+    Ok(vec![
+        query(
+            r#"
+            MATCH (d:Label)-[:CHILD_OF*0..]->(:Label {name: $old})
+            WITH collect(d) as subtree
+            UNWIND subtree as d
+            SET d.name = $new + substring(d.name, size($old))
+            "#,
+        )
+        .param("old", old)
+        .param("new", new),
+        query("MATCH (:Label {name: $new})-[r:CHILD_OF]->() DELETE r").param("new", new),
+        hierarchy_query(&[new.to_string()]),
+    ])
 }
 
-pub async fn delete_label(graph: &Graph, name: &str) -> Result<()> {
+/// Rename, recolor or change AI exclusion and visibility of a label. Emails
+/// keep their `HAS_LABEL` edges since the node itself is renamed.
+pub async fn update_label(graph: &Graph, name: &str, req: UpdateLabelRequest) -> Result<Label> {
+    if !label_exists(graph, name).await? {
+        return Err(anyhow!("Label not found"));
+    }
+
+    let mut queries = Vec::new();
+    if let Some(color) = req.color {
+        validate_color(&color)?;
+        queries.push(
+            query("MATCH (l:Label {name: $name}) SET l.color = $color")
+                .param("name", name)
+                .param("color", color),
+        );
+    }
+    if let Some(ai_excluded) = req.ai_excluded {
+        queries.push(
+            query("MATCH (l:Label {name: $name}) SET l.ai_excluded = $ai_excluded")
+                .param("name", name)
+                .param("ai_excluded", ai_excluded),
+        );
+    }
+
+    if let Some(visibility) = req.list_visibility {
+        queries.push(
+            query("MATCH (l:Label {name: $name}) SET l.list_visibility = $visibility")
+                .param("name", name)
+                .param("visibility", visibility.as_str()),
+        );
+    }
+    if let Some(visibility) = req.message_list_visibility {
+        queries.push(
+            query("MATCH (l:Label {name: $name}) SET l.message_list_visibility = $visibility")
+                .param("name", name)
+                .param("visibility", visibility.as_str()),
+        );
+    }
+
+    let mut current = name.to_string();
+    if let Some(new_name) = req.name.filter(|n| n != name) {
+        queries.extend(rename_queries(graph, name, &new_name).await?);
+        current = new_name;
+    }
+
+    run_atomically(graph, queries).await?;
+    find_label(graph, &current).await
+}
+
+/// Delete a label and every label nested under it, which can be undone with
+/// the returned token.
+pub async fn delete_label(graph: &Graph, name: &str) -> Result<UndoToken> {
     // Don't allow deleting system labels
-    let system_labels = ["INBOX", "SENT", "DRAFTS", "SPAM", "TRASH", "STARRED", "IMPORTANT"];
-    if system_labels.contains(&name) {
-        return Err(anyhow::anyhow!("Cannot delete system label"));
+    if SYSTEM_LABELS.contains(&name) {
+        return Err(anyhow!("Cannot delete system label"));
+    }
+    if !label_exists(graph, name).await? {
+        return Err(anyhow!("Label not found"));
     }
 
-    let cypher = "MATCH (l:Label {name: $name}) DETACH DELETE l";
-    graph.run(query(cypher).param("name", name)).await?;
-    Ok(())
+    let token = undo::new_token();
+    let subtree = r#"
+        MATCH (d:Label)-[:CHILD_OF*0..]->(:Label {name: $name})
+        WITH collect(d) as subtree
+        UNWIND subtree as l
+    "#;
+    graph
+        .run(undo::tombstone_labels_query(subtree, &token).param("name", name))
+        .await?;
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_names_imply_parents() {
+        let names = vec!["Work/Clients/Acme".to_string(), "Work/Clients/Globex".to_string(), "Personal".to_string()];
+        let pair = |c: &str, p: &str| (c.to_string(), p.to_string());
+        assert_eq!(
+            parent_pairs(&names),
+            vec![
+                pair("Work/Clients/Acme", "Work/Clients"),
+                pair("Work/Clients", "Work"),
+                pair("Work/Clients/Globex", "Work/Clients"),
+            ]
+        );
+
+        assert!(validate_name("Work/Clients/Acme").is_ok());
+        assert!(validate_name("Work//Acme").is_err());
+        assert!(validate_name("/Work").is_err());
+        assert!(validate_name("Work /Acme").is_err());
+        assert!(validate_color("#34a853").is_ok());
+        assert!(validate_color("#fff").is_ok());
+        assert!(validate_color("red").is_err());
+    }
 }
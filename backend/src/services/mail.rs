@@ -0,0 +1,353 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::models::Contact;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeliveryError {
+    /// Worth retrying later: connection problems, timeouts, 4xx replies.
+    #[error("Temporary delivery failure: {0}")]
+    Transient(String),
+    /// Retrying will not help: 5xx replies, invalid addresses.
+    #[error("Permanent delivery failure: {0}")]
+    Permanent(String),
+}
+
+impl DeliveryError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, DeliveryError::Transient(_))
+    }
+}
+
+/// Hands a fully formatted message to something that delivers it.
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    fn name(&self) -> &str;
+    async fn send(&self, envelope: &Envelope, message: &[u8]) -> Result<(), DeliveryError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Plain connection upgraded with STARTTLS (usually port 587).
+    StartTls,
+    /// TLS from the first byte (usually port 465).
+    #[serde(alias = "tls")]
+    Implicit,
+    /// No encryption, for local relays and test servers only.
+    None,
+}
+
+impl TlsMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "starttls" => Ok(TlsMode::StartTls),
+            "implicit" | "tls" => Ok(TlsMode::Implicit),
+            "none" => Ok(TlsMode::None),
+            other => Err(anyhow!("Unknown TLS mode: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub timeout: Duration,
+}
+
+pub struct SmtpTransport {
+    inner: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(config: SmtpConfig) -> Result<Self> {
+        let tls = match config.tls {
+            TlsMode::StartTls => Tls::Required(TlsParameters::new(config.host.clone())?),
+            TlsMode::Implicit => Tls::Wrapper(TlsParameters::new(config.host.clone())?),
+            TlsMode::None => Tls::None,
+        };
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(config.host)
+            .port(config.port)
+            .tls(tls)
+            .timeout(Some(config.timeout));
+        if let Some(username) = config.username {
+            builder = builder
+                .credentials(Credentials::new(username, config.password.unwrap_or_default()))
+                .authentication(vec![Mechanism::Plain, Mechanism::Login]);
+        }
+
+        Ok(SmtpTransport { inner: builder.build() })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpTransport {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+
+    async fn send(&self, envelope: &Envelope, message: &[u8]) -> Result<(), DeliveryError> {
+        self.inner.send_raw(envelope, message).await.map(|_| ()).map_err(|e| {
+            if e.is_permanent() {
+                DeliveryError::Permanent(e.to_string())
+            } else {
+                DeliveryError::Transient(e.to_string())
+            }
+        })
+    }
+}
+
+/// Development sink that drops each message into a Maildir `new/` folder
+/// instead of delivering it.
+pub struct MaildirTransport {
+    root: PathBuf,
+}
+
+impl MaildirTransport {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        for sub in ["tmp", "new", "cur"] {
+            std::fs::create_dir_all(root.join(sub))
+                .with_context(|| format!("Cannot create maildir {}", root.display()))?;
+        }
+        Ok(MaildirTransport { root })
+    }
+}
+
+#[async_trait]
+impl MailTransport for MaildirTransport {
+    fn name(&self) -> &str {
+        "maildir"
+    }
+
+    async fn send(&self, envelope: &Envelope, message: &[u8]) -> Result<(), DeliveryError> {
+        let file_name = format!(
+            "{}.{}.localhost",
+            chrono::Utc::now().timestamp(),
+            Uuid::new_v4().simple()
+        );
+        let recipients: Vec<String> = envelope.to().iter().map(|a| a.to_string()).collect();
+        let mut contents = format!("X-Envelope-To: {}\r\n", recipients.join(", ")).into_bytes();
+        contents.extend_from_slice(message);
+
+        // Write to tmp/ first so readers never see a partial file in new/
+        let tmp = self.root.join("tmp").join(&file_name);
+        tokio::fs::write(&tmp, contents)
+            .await
+            .map_err(|e| DeliveryError::Transient(e.to_string()))?;
+        tokio::fs::rename(&tmp, self.root.join("new").join(&file_name))
+            .await
+            .map_err(|e| DeliveryError::Transient(e.to_string()))
+    }
+}
+
+/// Build the configured transport:
+/// - `MAIL_TRANSPORT=smtp` with `SMTP_HOST`, `SMTP_PORT`, `SMTP_TLS` (`starttls`,
+///   `implicit` or `none`), `SMTP_USERNAME`, `SMTP_PASSWORD`, `SMTP_TIMEOUT_SECS`
+/// - `MAIL_TRANSPORT=maildir` writes to `MAIL_SINK_DIR` (default `mail-sink`)
+///
+/// Without `MAIL_TRANSPORT`, SMTP is used when `SMTP_HOST` is set.
+pub fn transport_from_env() -> Result<Arc<dyn MailTransport>> {
+    let kind = std::env::var("MAIL_TRANSPORT").unwrap_or_else(|_| {
+        if std::env::var("SMTP_HOST").is_ok() { "smtp".into() } else { "maildir".into() }
+    });
+
+    match kind.as_str() {
+        "smtp" => {
+            let host = std::env::var("SMTP_HOST").context("SMTP_HOST is required for the smtp transport")?;
+            let tls = TlsMode::parse(std::env::var("SMTP_TLS").as_deref().unwrap_or("starttls"))?;
+            let default_port = match tls {
+                TlsMode::StartTls => 587,
+                TlsMode::Implicit => 465,
+                TlsMode::None => 25,
+            };
+            let port = match std::env::var("SMTP_PORT") {
+                Ok(port) => port.parse().with_context(|| format!("Invalid SMTP_PORT {}", port))?,
+                Err(_) => default_port,
+            };
+            let timeout = std::env::var("SMTP_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30);
+
+            Ok(Arc::new(SmtpTransport::new(SmtpConfig {
+                host,
+                port,
+                tls,
+                username: std::env::var("SMTP_USERNAME").ok(),
+                password: std::env::var("SMTP_PASSWORD").ok(),
+                timeout: Duration::from_secs(timeout),
+            })?))
+        }
+        "maildir" => {
+            let dir = std::env::var("MAIL_SINK_DIR").unwrap_or_else(|_| "mail-sink".into());
+            Ok(Arc::new(MaildirTransport::new(dir)?))
+        }
+        other => Err(anyhow!("Unknown MAIL_TRANSPORT: {}", other)),
+    }
+}
+
+/// The account mail is sent as, from `MAIL_FROM` and `MAIL_FROM_NAME`.
+pub fn sender() -> Contact {
+    Contact {
+        email: std::env::var("MAIL_FROM").unwrap_or_else(|_| "me@example.com".into()),
+        name: std::env::var("MAIL_FROM_NAME").ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Email;
+    use crate::services::mime;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Minimal SMTP server for one session. Replies to RCPT with `rcpt_reply`
+    /// and returns the transcript of client commands and message data.
+    async fn smtp_stand_in(rcpt_reply: &'static str) -> (u16, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let mut transcript = Vec::new();
+
+            write.write_all(b"220 localhost ESMTP stand-in\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                transcript.push(line.clone());
+                let command = line.to_uppercase();
+                let reply = if command.starts_with("EHLO") {
+                    "250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+                } else if command.starts_with("AUTH") {
+                    "235 2.7.0 Authenticated\r\n"
+                } else if command.starts_with("MAIL") {
+                    "250 2.1.0 OK\r\n"
+                } else if command.starts_with("RCPT") {
+                    rcpt_reply
+                } else if command == "DATA" {
+                    write.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await.unwrap();
+                    while let Ok(Some(data)) = lines.next_line().await {
+                        if data == "." {
+                            break;
+                        }
+                        transcript.push(data);
+                    }
+                    "250 2.0.0 Queued\r\n"
+                } else if command == "QUIT" {
+                    write.write_all(b"221 2.0.0 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    "250 OK\r\n"
+                };
+                write.write_all(reply.as_bytes()).await.unwrap();
+            }
+            transcript
+        });
+
+        (port, handle)
+    }
+
+    fn transport(port: u16) -> SmtpTransport {
+        SmtpTransport::new(SmtpConfig {
+            host: "127.0.0.1".into(),
+            port,
+            tls: TlsMode::None,
+            username: Some("me".into()),
+            password: Some("secret".into()),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap()
+    }
+
+    fn message() -> (Envelope, Vec<u8>) {
+        let email = email();
+        (mime::envelope(&email).unwrap(), mime::render(&email, &[]).unwrap())
+    }
+
+    fn email() -> Email {
+        Email {
+            id: Uuid::new_v4(),
+            subject: "Quarterly report".into(),
+            body: "Numbers attached.".into(),
+            html_body: None,
+            snippet: String::new(),
+            date: chrono::Utc::now(),
+            is_read: false,
+            is_starred: false,
+            thread_id: None,
+            message_id: Some(mime::new_message_id("me@example.com")),
+            in_reply_to: None,
+            references: vec![],
+            from: Contact { email: "me@example.com".into(), name: Some("Me".into()) },
+            to: vec![Contact { email: "alice@example.com".into(), name: None }],
+            cc: vec![Contact { email: "bob@example.com".into(), name: None }],
+            labels: vec![],
+            attachments: vec![],
+            embedding: None,
+            delivery: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_over_smtp_with_auth() {
+        let (port, server) = smtp_stand_in("250 2.1.5 OK\r\n").await;
+        let (envelope, message) = message();
+
+        let transport = transport(port);
+        transport.send(&envelope, &message).await.unwrap();
+        drop(transport);
+
+        let transcript = server.await.unwrap();
+        assert!(transcript.iter().any(|l| l.starts_with("AUTH PLAIN")));
+        assert!(transcript.iter().any(|l| l.contains("RCPT TO:<alice@example.com>")));
+        assert!(transcript.iter().any(|l| l.contains("RCPT TO:<bob@example.com>")));
+        assert!(transcript.iter().any(|l| l == "Subject: Quarterly report"));
+    }
+
+    #[tokio::test]
+    async fn classifies_smtp_replies() {
+        let (envelope, message) = message();
+
+        let (port, _server) = smtp_stand_in("550 5.1.1 No such user\r\n").await;
+        let err = transport(port).send(&envelope, &message).await.unwrap_err();
+        assert!(!err.is_transient(), "{}", err);
+
+        let (port, _server) = smtp_stand_in("451 4.3.0 Try again later\r\n").await;
+        let err = transport(port).send(&envelope, &message).await.unwrap_err();
+        assert!(err.is_transient(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn maildir_sink_writes_complete_files() {
+        let root = std::env::temp_dir().join(format!("mail-sink-{}", Uuid::new_v4()));
+        let sink = MaildirTransport::new(&root).unwrap();
+        let (envelope, message) = message();
+
+        sink.send(&envelope, &message).await.unwrap();
+
+        let delivered: Vec<_> = std::fs::read_dir(root.join("new")).unwrap().collect();
+        assert_eq!(delivered.len(), 1);
+        let contents = std::fs::read_to_string(delivered[0].as_ref().unwrap().path()).unwrap();
+        assert!(contents.starts_with("X-Envelope-To: alice@example.com, bob@example.com"));
+        assert!(contents.contains("Numbers attached."));
+        assert_eq!(std::fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use lettre::address::Envelope;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::Message;
+use uuid::Uuid;
+
+use crate::models::{AttachmentUpload, Contact, Email};
+
+/// A decoded attachment ready to be placed in a message.
+#[derive(Debug, Clone)]
+pub struct OutgoingAttachment {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+}
+
+impl OutgoingAttachment {
+    pub fn decode(upload: &AttachmentUpload) -> Result<Self> {
+        let content_type = upload.content_type.as_deref().unwrap_or("application/octet-stream");
+        Ok(OutgoingAttachment {
+            filename: upload.filename.clone(),
+            content_type: ContentType::parse(content_type)
+                .map_err(|_| anyhow!("Invalid attachment content type: {}", content_type))?,
+            data: base64::engine::general_purpose::STANDARD
+                .decode(upload.content.trim())
+                .map_err(|_| anyhow!("Invalid attachment encoding: {}", upload.filename))?,
+        })
+    }
+}
+
+/// A new globally unique `Message-ID` in the sender's domain.
+pub fn new_message_id(from: &str) -> String {
+    let domain = from.rsplit_once('@').map(|(_, d)| d).unwrap_or("localhost");
+    format!("<{}@{}>", Uuid::new_v4().simple(), domain)
+}
+
+fn mailbox(contact: &Contact) -> Result<Mailbox> {
+    let address = contact
+        .email
+        .parse()
+        .map_err(|_| anyhow!("Invalid address: {}", contact.email))?;
+    Ok(Mailbox::new(contact.name.clone(), address))
+}
+
+/// Check recipient addresses before anything is stored.
+pub fn validate_addresses(addresses: &[String]) -> Result<()> {
+    for address in addresses {
+        mailbox(&Contact { email: address.clone(), name: None })?;
+    }
+    Ok(())
+}
+
+/// SMTP envelope for a stored email: its sender and every To/Cc recipient.
+pub fn envelope(email: &Email) -> Result<Envelope> {
+    let from = mailbox(&email.from)?.email;
+    let to = email
+        .to
+        .iter()
+        .chain(&email.cc)
+        .map(|c| mailbox(c).map(|m| m.email))
+        .collect::<Result<Vec<_>>>()?;
+    Envelope::new(Some(from), to).map_err(|e| anyhow!("Invalid envelope: {}", e))
+}
+
+/// Render an email as an RFC 5322 message. Non-ASCII headers are RFC 2047
+/// encoded; an HTML body becomes a multipart/alternative with the text, and
+/// attachments wrap everything in multipart/mixed.
+pub fn render(email: &Email, attachments: &[OutgoingAttachment]) -> Result<Vec<u8>> {
+    let mut builder = Message::builder()
+        .from(mailbox(&email.from)?)
+        .subject(email.subject.clone())
+        .date(email.date.into())
+        .message_id(email.message_id.clone());
+    for to in &email.to {
+        builder = builder.to(mailbox(to)?);
+    }
+    for cc in &email.cc {
+        builder = builder.cc(mailbox(cc)?);
+    }
+    if let Some(parent) = &email.in_reply_to {
+        builder = builder.in_reply_to(parent.clone());
+    }
+    if !email.references.is_empty() {
+        builder = builder.references(email.references.join(" "));
+    }
+
+    let text = SinglePart::plain(email.body.clone());
+    let alternative = |html: &String| {
+        MultiPart::alternative()
+            .singlepart(text.clone())
+            .singlepart(SinglePart::html(html.clone()))
+    };
+    let message = match (&email.html_body, attachments.is_empty()) {
+        (None, true) => builder.singlepart(text.clone()),
+        (Some(html), true) => builder.multipart(alternative(html)),
+        (html, false) => {
+            let mixed = match html {
+                Some(html) => MultiPart::mixed().multipart(alternative(html)),
+                None => MultiPart::mixed().singlepart(text.clone()),
+            };
+            builder.multipart(attachments.iter().fold(mixed, |mixed, a| {
+                mixed.singlepart(Attachment::new(a.filename.clone()).body(a.data.clone(), a.content_type.clone()))
+            }))
+        }
+    }
+    .map_err(|e| anyhow!("Cannot build message: {}", e))?;
+
+    Ok(message.formatted())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email() -> Email {
+        Email {
+            id: Uuid::new_v4(),
+            subject: "Café meeting".into(),
+            body: "See you there.".into(),
+            html_body: None,
+            snippet: String::new(),
+            date: chrono::Utc::now(),
+            is_read: false,
+            is_starred: false,
+            thread_id: None,
+            message_id: Some("<abc@example.com>".into()),
+            in_reply_to: Some("<parent@example.com>".into()),
+            references: vec!["<root@example.com>".into(), "<parent@example.com>".into()],
+            from: Contact { email: "me@example.com".into(), name: Some("Zoë".into()) },
+            to: vec![Contact { email: "alice@example.com".into(), name: None }],
+            cc: vec![],
+            labels: vec![],
+            attachments: vec![],
+            embedding: None,
+            delivery: None,
+        }
+    }
+
+    fn headers(raw: &[u8]) -> String {
+        let raw = String::from_utf8_lossy(raw);
+        raw.split("\r\n\r\n").next().unwrap().to_string()
+    }
+
+    #[test]
+    fn writes_threading_and_encoded_headers() {
+        let raw = render(&email(), &[]).unwrap();
+        let headers = headers(&raw);
+
+        assert!(headers.contains("Message-ID: <abc@example.com>"));
+        assert!(headers.contains("In-Reply-To: <parent@example.com>"));
+        assert!(headers.contains("References: <root@example.com> <parent@example.com>"));
+        assert!(headers.contains("Date: "));
+        assert!(headers.contains("Subject: =?utf-8?"));
+        assert!(headers.contains("From: =?utf-8?"));
+        assert!(headers.is_ascii());
+    }
+
+    #[test]
+    fn nests_alternative_inside_mixed_with_attachments() {
+        let mut email = email();
+        email.html_body = Some("<p>See you <b>there</b>.</p>".into());
+        let attachment = OutgoingAttachment::decode(&AttachmentUpload {
+            filename: "agenda.txt".into(),
+            content_type: Some("text/plain".into()),
+            content: "MS4gQ29mZmVl".into(),
+        })
+        .unwrap();
+
+        let raw = String::from_utf8(render(&email, &[attachment]).unwrap()).unwrap();
+        let mixed = raw.find("multipart/mixed").unwrap();
+        let alternative = raw.find("multipart/alternative").unwrap();
+        assert!(mixed < alternative);
+        assert!(raw.contains("text/html"));
+        assert!(raw.contains("filename=\"agenda.txt\""));
+        assert!(raw.contains("1. Coffee"));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(validate_addresses(&["not an address".into()]).is_err());
+        let upload = AttachmentUpload { filename: "x".into(), content_type: None, content: "%%%".into() };
+        assert!(OutgoingAttachment::decode(&upload).is_err());
+    }
+}
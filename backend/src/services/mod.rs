@@ -1,5 +1,28 @@
+pub mod address_book;
+pub mod attachments;
+pub mod blobs;
+pub mod contacts;
 pub mod db;
 pub mod emails;
+pub mod export;
+pub mod extract;
+pub mod groups;
+pub mod html;
+pub mod imap;
+pub mod import;
+pub mod inbound;
 pub mod labels;
+pub mod mail;
+pub mod mime;
+pub mod outbox;
+pub mod poller;
+pub mod relationships;
 pub mod threads;
+pub mod threading;
+pub mod trash;
+pub mod undo;
 pub mod ai;
+pub mod prompts;
+pub mod providers;
+pub mod redaction;
+pub mod usage;
@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use neo4rs::{query, Graph};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::models::{DeliveryStatus, Email};
+use crate::services::emails::{email_from_row, get_email, get_raw};
+use crate::services::mail::{DeliveryError, MailTransport};
+use crate::services::{mime, poller};
+
+fn env_u32(name: &str, default: u32) -> u32 {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// What to do after a failed attempt: retry after a delay, or give up.
+/// Transient failures back off exponentially from `OUTBOX_RETRY_BASE_SECS`
+/// (default 60, capped at an hour) until `OUTBOX_MAX_ATTEMPTS` (default 5).
+fn after_failure(attempts: u32, error: &DeliveryError) -> (DeliveryStatus, Option<Duration>) {
+    let max_attempts = env_u32("OUTBOX_MAX_ATTEMPTS", 5);
+    if !error.is_transient() || attempts >= max_attempts {
+        return (DeliveryStatus::Failed, None);
+    }
+    let base = env_u32("OUTBOX_RETRY_BASE_SECS", 60) as i64;
+    let delay = base.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(3600);
+    (DeliveryStatus::Retrying, Some(Duration::seconds(delay)))
+}
+
+/// Try to send one queued email and record the outcome on it. Emails that are
+/// already sent, failed or being sent by another worker are returned unchanged.
+pub async fn deliver(graph: &Graph, transport: &dyn MailTransport, id: Uuid) -> Result<Email> {
+    let claim = r#"
+        MATCH (e:Email {id: $id})
+        WHERE e.delivery_status IN ['queued', 'retrying']
+        SET e.delivery_status = 'sending', e.delivery_attempts = coalesce(e.delivery_attempts, 0) + 1
+        RETURN e.delivery_attempts as attempts
+    "#;
+    let mut result = graph.execute(query(claim).param("id", id.to_string())).await?;
+    let Some(row) = result.next().await? else {
+        return get_email(graph, id).await;
+    };
+    let attempts = row.get::<i64>("attempts").unwrap_or(1) as u32;
+
+    let email = get_email(graph, id).await?;
+    // Rendering again here would drop the attachments
+    let message = get_raw(graph, id).await;
+    let outcome = match (mime::envelope(&email), message) {
+        (Ok(envelope), Ok(message)) => transport.send(&envelope, &message).await,
+        (Err(e), _) | (_, Err(e)) => Err(DeliveryError::Permanent(e.to_string())),
+    };
+
+    match outcome {
+        Ok(()) => {
+            tracing::info!(email = %id, transport = transport.name(), "Email delivered");
+            let cypher = r#"
+                MATCH (e:Email {id: $id})
+                SET e.delivery_status = 'sent', e.sent_at = $now, e.next_attempt_at = null, e.delivery_error = null
+            "#;
+            graph
+                .run(query(cypher).param("id", id.to_string()).param("now", Utc::now().to_rfc3339()))
+                .await?;
+        }
+        Err(error) => {
+            let (status, delay) = after_failure(attempts, &error);
+            tracing::warn!(email = %id, attempts, status = status.as_str(), "Email delivery failed: {}", error);
+            let cypher = r#"
+                MATCH (e:Email {id: $id})
+                SET e.delivery_status = $status, e.delivery_error = $error, e.next_attempt_at = $next
+            "#;
+            graph
+                .run(
+                    query(cypher)
+                        .param("id", id.to_string())
+                        .param("status", status.as_str())
+                        .param("error", error.to_string())
+                        .param("next", delay.map(|d| (Utc::now() + d).to_rfc3339())),
+                )
+                .await?;
+        }
+    }
+
+    get_email(graph, id).await
+}
+
+/// Deliver every queued or retrying email whose next attempt is due.
+pub async fn process_due(graph: &Graph, transport: &dyn MailTransport) -> Result<usize> {
+    let cypher = r#"
+        MATCH (e:Email)
+        WHERE e.delivery_status IN ['queued', 'retrying'] AND e.next_attempt_at <= $now
+        RETURN e.id as id
+        ORDER BY e.next_attempt_at
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("now", Utc::now().to_rfc3339()))
+        .await?;
+
+    let mut ids = Vec::new();
+    while let Some(row) = result.next().await? {
+        if let Ok(id) = Uuid::parse_str(&row.get::<String>("id")?) {
+            ids.push(id);
+        }
+    }
+
+    // One email failing must not hold up the rest of the batch
+    for id in &ids {
+        if let Err(e) = deliver(graph, transport, *id).await {
+            tracing::error!("Delivery of email {} failed: {}", id, e);
+        }
+    }
+    Ok(ids.len())
+}
+
+/// Put an email back in the outbox for another round of attempts.
+pub async fn requeue(graph: &Graph, id: Uuid) -> Result<Email> {
+    let email = get_email(graph, id).await?;
+    match email.delivery.as_ref().map(|d| d.status) {
+        None => return Err(anyhow!("Email was not sent from this mailbox")),
+        Some(DeliveryStatus::Sending) => return Err(anyhow!("Email is being sent")),
+        Some(_) => {}
+    }
+
+    let cypher = r#"
+        MATCH (e:Email {id: $id})
+        SET e.delivery_status = 'queued', e.delivery_attempts = 0, e.delivery_error = null,
+            e.next_attempt_at = $now
+    "#;
+    graph
+        .run(query(cypher).param("id", id.to_string()).param("now", Utc::now().to_rfc3339()))
+        .await?;
+
+    get_email(graph, id).await
+}
+
+/// Emails that have not been delivered yet, including permanent failures.
+pub async fn list_outbox(graph: &Graph) -> Result<Vec<Email>> {
+    let cypher = r#"
+        MATCH (e:Email)
+        WHERE e.delivery_status IS NOT NULL AND e.delivery_status <> 'sent'
+        OPTIONAL MATCH (e)-[:SENT_BY]->(from:Contact)
+        OPTIONAL MATCH (e)-[:SENT_TO]->(to:Contact)
+        OPTIONAL MATCH (e)-[:CC]->(cc:Contact)
+        OPTIONAL MATCH (e)-[:HAS_LABEL]->(l:Label)
+        WITH e, from, collect(DISTINCT to) as tos, collect(DISTINCT cc) as ccs, collect(DISTINCT l.name) as labels
+        ORDER BY e.date DESC
+        RETURN e, from, tos, ccs, labels
+    "#;
+    let mut result = graph.execute(query(cypher)).await?;
+
+    let mut emails = Vec::new();
+    while let Some(row) = result.next().await? {
+        emails.push(email_from_row(&row)?);
+    }
+    Ok(emails)
+}
+
+/// Poll the outbox every `OUTBOX_POLL_SECS` (default 30). Emails left in
+/// `sending` by a previous process are retried.
+pub fn spawn_worker(graph: Graph, transport: Arc<dyn MailTransport>) {
+    tokio::spawn(async move {
+        let reset = r#"
+            MATCH (e:Email {delivery_status: 'sending'})
+            SET e.delivery_status = 'retrying', e.next_attempt_at = $now
+        "#;
+        if let Err(e) = graph.run(query(reset).param("now", Utc::now().to_rfc3339())).await {
+            tracing::error!("Failed to reset interrupted deliveries: {}", e);
+        }
+
+        poller::poll("Outbox run", "OUTBOX_POLL_SECS", 30, None, || process_due(&graph, transport.as_ref())).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_transient_failures_then_gives_up() {
+        let transient = DeliveryError::Transient("421 busy".into());
+        assert_eq!(after_failure(1, &transient), (DeliveryStatus::Retrying, Some(Duration::seconds(60))));
+        assert_eq!(after_failure(3, &transient), (DeliveryStatus::Retrying, Some(Duration::seconds(240))));
+        assert_eq!(after_failure(5, &transient), (DeliveryStatus::Failed, None));
+
+        let permanent = DeliveryError::Permanent("550 no such user".into());
+        assert_eq!(after_failure(1, &permanent), (DeliveryStatus::Failed, None));
+    }
+}
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+
+/// Run `run` forever, sleeping `env_var` seconds (default `default_secs`)
+/// between runs. A run that handled a full `batch` is followed straight away
+/// by the next one, so backlogs drain without waiting out the poll interval.
+pub async fn poll<F, Fut>(name: &'static str, env_var: &str, default_secs: u64, batch: Option<usize>, mut run: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<usize>>,
+{
+    let secs = std::env::var(env_var).ok().and_then(|v| v.parse().ok()).unwrap_or(default_secs);
+    let interval = Duration::from_secs(secs.max(1));
+    loop {
+        match run().await {
+            Ok(count) if batch == Some(count) => continue,
+            Ok(_) => {}
+            Err(e) => tracing::error!("{} failed: {}", name, e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// [`poll`] on a background task.
+pub fn spawn_poller<F, Fut>(name: &'static str, env_var: &'static str, default_secs: u64, batch: Option<usize>, run: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<usize>> + Send + 'static,
+{
+    tokio::spawn(poll(name, env_var, default_secs, batch, run));
+}
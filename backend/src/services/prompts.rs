@@ -0,0 +1,327 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+/// Templates compiled into the binary. Files in `PROMPTS_DIR` with the same
+/// name and version replace these; files with new versions are added alongside.
+const BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+    ("summarize.v1.prompt", include_str!("../../prompts/summarize.v1.prompt")),
+    ("compose.v1.prompt", include_str!("../../prompts/compose.v1.prompt")),
+    ("categorize.v1.prompt", include_str!("../../prompts/categorize.v1.prompt")),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub version: u32,
+    pub model: String,
+    pub source: String,
+    pub body: String,
+}
+
+/// A template rendered with its variables, ready to send to a model.
+#[derive(Debug, Clone)]
+pub struct RenderedPrompt {
+    /// `name@vN` identifier of the template that produced this prompt.
+    pub template: String,
+    pub model: String,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptInfo {
+    pub name: String,
+    pub active_version: u32,
+    pub versions: Vec<PromptTemplate>,
+}
+
+impl PromptTemplate {
+    /// Parse a `<name>.v<version>.prompt` file. An optional front matter block
+    /// delimited by `---` lines may set `model`.
+    pub fn parse(file_name: &str, contents: &str, source: &str) -> Result<Self> {
+        let stem = file_name
+            .strip_suffix(".prompt")
+            .ok_or_else(|| anyhow!("Prompt file {} must end in .prompt", file_name))?;
+        let (name, version) = stem
+            .rsplit_once(".v")
+            .ok_or_else(|| anyhow!("Prompt file {} must be named <name>.v<version>.prompt", file_name))?;
+        let version: u32 = version
+            .parse()
+            .with_context(|| format!("Invalid version in prompt file {}", file_name))?;
+
+        let mut model = DEFAULT_MODEL.to_string();
+        let mut body = contents;
+
+        if let Some(rest) = contents.strip_prefix("---\n") {
+            let (front, after) = rest
+                .split_once("\n---\n")
+                .ok_or_else(|| anyhow!("Unterminated front matter in {}", file_name))?;
+            for line in front.lines() {
+                match line.split_once(':') {
+                    Some(("model", value)) => model = value.trim().to_string(),
+                    Some((key, _)) => tracing::warn!("Ignoring unknown key {} in {}", key.trim(), file_name),
+                    None if line.trim().is_empty() => {}
+                    None => return Err(anyhow!("Malformed front matter line in {}: {}", file_name, line)),
+                }
+            }
+            body = after;
+        }
+
+        Ok(PromptTemplate {
+            name: name.to_string(),
+            version,
+            model,
+            source: source.to_string(),
+            body: body.trim_end().to_string(),
+        })
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}@v{}", self.name, self.version)
+    }
+
+    /// Substitute `{{variable}}` placeholders. Every placeholder in the template
+    /// must be supplied; extra variables are ignored.
+    pub fn render(&self, vars: &[(&str, &str)]) -> Result<RenderedPrompt> {
+        let mut text = String::with_capacity(self.body.len());
+        let mut rest = self.body.as_str();
+
+        while let Some(start) = rest.find("{{") {
+            text.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after
+                .find("}}")
+                .ok_or_else(|| anyhow!("Unclosed placeholder in prompt {}", self.id()))?;
+            let key = after[..end].trim();
+            let value = vars
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| *v)
+                .ok_or_else(|| anyhow!("Missing variable '{}' for prompt {}", key, self.id()))?;
+            text.push_str(value);
+            rest = &after[end + 2..];
+        }
+        text.push_str(rest);
+
+        Ok(RenderedPrompt {
+            template: self.id(),
+            model: self.model.clone(),
+            text,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PromptRegistry {
+    templates: HashMap<String, BTreeMap<u32, PromptTemplate>>,
+    pinned: HashMap<String, u32>,
+}
+
+impl PromptRegistry {
+    /// Load the built-in templates, then overrides from `PROMPTS_DIR`, then
+    /// version pins from `PROMPT_VERSIONS` (e.g. `summarize=2,compose=1`).
+    pub fn load() -> Result<Self> {
+        Self::load_from(
+            std::env::var("PROMPTS_DIR").ok().as_deref(),
+            std::env::var("PROMPT_VERSIONS").ok().as_deref(),
+        )
+    }
+
+    /// Load the built-in templates, then overrides from `dir`, then `pins`.
+    pub(crate) fn load_from(dir: Option<&str>, pins: Option<&str>) -> Result<Self> {
+        let mut registry = PromptRegistry::default();
+
+        for (file_name, contents) in BUILTIN_TEMPLATES {
+            registry.insert(PromptTemplate::parse(file_name, contents, "builtin")?);
+        }
+
+        if let Some(dir) = dir {
+            let entries = std::fs::read_dir(dir)
+                .with_context(|| format!("Cannot read PROMPTS_DIR {}", dir))?;
+            for entry in entries {
+                let path = entry?.path();
+                let Some(file_name) = path.file_name().and_then(|f| f.to_str()) else {
+                    continue;
+                };
+                if !file_name.ends_with(".prompt") {
+                    continue;
+                }
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Cannot read prompt file {}", path.display()))?;
+                registry.insert(PromptTemplate::parse(file_name, &contents, &path.display().to_string())?);
+            }
+        }
+
+        if let Some(pins) = pins {
+            for pin in pins.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                let (name, version) = pin
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid PROMPT_VERSIONS entry: {}", pin))?;
+                let version: u32 = version
+                    .trim()
+                    .trim_start_matches('v')
+                    .parse()
+                    .with_context(|| format!("Invalid PROMPT_VERSIONS entry: {}", pin))?;
+                registry.get_version(name.trim(), version)?;
+                registry.pinned.insert(name.trim().to_string(), version);
+            }
+        }
+
+        Ok(registry)
+    }
+
+    fn insert(&mut self, template: PromptTemplate) {
+        self.templates
+            .entry(template.name.clone())
+            .or_default()
+            .insert(template.version, template);
+    }
+
+    /// The active version of a template: the pinned version if any, otherwise the latest.
+    pub fn get(&self, name: &str) -> Result<&PromptTemplate> {
+        if let Some(version) = self.pinned.get(name) {
+            return self.get_version(name, *version);
+        }
+        self.templates
+            .get(name)
+            .and_then(|versions| versions.values().next_back())
+            .ok_or_else(|| anyhow!("Prompt template not found: {}", name))
+    }
+
+    pub fn get_version(&self, name: &str, version: u32) -> Result<&PromptTemplate> {
+        self.templates
+            .get(name)
+            .and_then(|versions| versions.get(&version))
+            .ok_or_else(|| anyhow!("Prompt template not found: {}@v{}", name, version))
+    }
+
+    pub fn list(&self) -> Vec<PromptInfo> {
+        let mut infos: Vec<PromptInfo> = self
+            .templates
+            .iter()
+            .filter_map(|(name, versions)| {
+                Some(PromptInfo {
+                    name: name.clone(),
+                    active_version: self.get(name).ok()?.version,
+                    versions: versions.values().cloned().collect(),
+                })
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+}
+
+/// The loaded templates, swapped as a whole by [`Prompts::reload`].
+#[derive(Debug, Default)]
+pub struct Prompts(RwLock<PromptRegistry>);
+
+impl Prompts {
+    pub fn new(registry: PromptRegistry) -> Self {
+        Prompts(RwLock::new(registry))
+    }
+
+    /// Load like [`PromptRegistry::load`], falling back to the built-ins on error.
+    pub fn from_env() -> Self {
+        let registry = PromptRegistry::load().unwrap_or_else(|e| {
+            tracing::error!("Failed to load prompt templates, using built-ins only: {}", e);
+            let mut registry = PromptRegistry::default();
+            for (file_name, contents) in BUILTIN_TEMPLATES {
+                if let Ok(template) = PromptTemplate::parse(file_name, contents, "builtin") {
+                    registry.insert(template);
+                }
+            }
+            registry
+        });
+        Prompts::new(registry)
+    }
+
+    /// Render the active (or explicitly requested) version of a template.
+    pub fn render(&self, name: &str, version: Option<u32>, vars: &[(&str, &str)]) -> Result<RenderedPrompt> {
+        let registry = self.0.read().map_err(|_| anyhow!("Prompt registry lock poisoned"))?;
+        let template = match version {
+            Some(version) => registry.get_version(name, version)?,
+            None => registry.get(name)?,
+        };
+        template.render(vars)
+    }
+
+    pub fn list(&self) -> Result<Vec<PromptInfo>> {
+        let registry = self.0.read().map_err(|_| anyhow!("Prompt registry lock poisoned"))?;
+        Ok(registry.list())
+    }
+
+    /// Re-read templates from disk. On error the currently loaded templates stay active.
+    pub fn reload(&self) -> Result<Vec<PromptInfo>> {
+        let fresh = PromptRegistry::load()?;
+        let infos = fresh.list();
+        *self.0.write().map_err(|_| anyhow!("Prompt registry lock poisoned"))? = fresh;
+        tracing::info!("Reloaded {} prompt templates", infos.len());
+        Ok(infos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// A fresh directory holding the given prompt files.
+    fn prompts_dir(files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("prompts-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        dir.display().to_string()
+    }
+
+    #[test]
+    fn overrides_replace_builtins_and_add_versions() {
+        let dir = prompts_dir(&[
+            ("summarize.v1.prompt", "Shorten: {{text}}"),
+            ("summarize.v2.prompt", "---\nmodel: gpt-4o\n---\nTL;DR {{text}}"),
+            ("notes.txt", "ignored"),
+        ]);
+        let registry = PromptRegistry::load_from(Some(&dir), None).unwrap();
+
+        let v1 = registry.get_version("summarize", 1).unwrap();
+        assert_eq!(v1.body, "Shorten: {{text}}");
+        assert!(v1.source.ends_with("summarize.v1.prompt"));
+
+        let active = registry.get("summarize").unwrap();
+        assert_eq!(active.id(), "summarize@v2");
+        let rendered = active.render(&[("text", "hello")]).unwrap();
+        assert_eq!(rendered.text, "TL;DR hello");
+        assert_eq!(rendered.model, "gpt-4o");
+
+        assert_eq!(registry.get("compose").unwrap().source, "builtin");
+    }
+
+    #[test]
+    fn pins_select_the_active_version() {
+        let dir = prompts_dir(&[("summarize.v2.prompt", "v2 {{text}}")]);
+        let registry = PromptRegistry::load_from(Some(&dir), Some("summarize=v1, compose=1")).unwrap();
+        assert_eq!(registry.get("summarize").unwrap().id(), "summarize@v1");
+        assert_eq!(registry.get_version("summarize", 2).unwrap().id(), "summarize@v2");
+
+        let info = registry.list();
+        let summarize = info.iter().find(|i| i.name == "summarize").unwrap();
+        assert_eq!(summarize.active_version, 1);
+        assert_eq!(summarize.versions.len(), 2);
+
+        assert!(PromptRegistry::load_from(None, Some("summarize=9")).is_err());
+        assert!(PromptRegistry::load_from(None, Some("summarize")).is_err());
+    }
+
+    #[test]
+    fn render_requires_every_placeholder() {
+        let template = PromptTemplate::parse("t.v1.prompt", "{{ a }} and {{b}}", "test").unwrap();
+        assert_eq!(template.render(&[("a", "x"), ("b", "y"), ("c", "z")]).unwrap().text, "x and y");
+        assert!(template.render(&[("a", "x")]).is_err());
+        assert!(PromptTemplate::parse("t.prompt", "", "test").is_err());
+    }
+}
@@ -0,0 +1,656 @@
+use async_openai::{
+    config::OpenAIConfig,
+    error::OpenAIError,
+    types::{
+        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+        CreateEmbeddingRequestArgs, EmbeddingInput,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::services::usage::Usage;
+
+pub struct Completion {
+    pub text: String,
+    pub model: String,
+    pub usage: Usage,
+    /// Produced by a fallback provider rather than the primary one.
+    pub degraded: bool,
+}
+
+pub struct Embedding {
+    pub vector: Vec<f32>,
+    pub model: String,
+    pub usage: Usage,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProviderError {
+    #[error("AI provider timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("AI provider rate limited the request: {0}")]
+    RateLimited(String),
+    #[error("AI provider unavailable: {0}")]
+    Unavailable(String),
+    #[error("AI provider rejected the request: {0}")]
+    Rejected(String),
+    #[error("AI provider {0} is failing, circuit breaker open")]
+    CircuitOpen(String),
+}
+
+impl ProviderError {
+    /// Transient failures worth retrying; they also count against the circuit breaker.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ProviderError::Timeout(_) | ProviderError::RateLimited(_) | ProviderError::Unavailable(_)
+        )
+    }
+}
+
+#[async_trait]
+pub trait AiProvider: Send + Sync {
+    fn name(&self) -> &str;
+    async fn chat(&self, model: &str, prompt: &str) -> Result<Completion, ProviderError>;
+    async fn embed(&self, model: &str, input: &str) -> Result<Embedding, ProviderError>;
+}
+
+/// OpenAI or any OpenAI-compatible endpoint.
+pub struct OpenAiProvider {
+    name: String,
+    client: Client<OpenAIConfig>,
+    /// Replaces the template's model, for endpoints that serve different models.
+    chat_model: Option<String>,
+}
+
+impl OpenAiProvider {
+    pub fn new(name: &str, api_key: &str, api_base: Option<&str>, chat_model: Option<String>) -> Self {
+        let mut config = OpenAIConfig::new().with_api_key(api_key);
+        if let Some(api_base) = api_base {
+            config = config.with_api_base(api_base);
+        }
+        // Retries are handled by `AiClient` so they share its timeout and breaker
+        let no_retry = backoff::ExponentialBackoff {
+            max_elapsed_time: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        OpenAiProvider {
+            name: name.to_string(),
+            client: Client::with_config(config).with_backoff(no_retry),
+            chat_model,
+        }
+    }
+
+    /// Primary provider from `OPENAI_API_KEY` and optional `OPENAI_API_BASE`.
+    pub fn primary_from_env() -> Option<Self> {
+        let api_key = std::env::var("OPENAI_API_KEY").ok()?;
+        let api_base = std::env::var("OPENAI_API_BASE").ok();
+        Some(OpenAiProvider::new("openai", &api_key, api_base.as_deref(), None))
+    }
+
+    /// Secondary provider from `AI_FALLBACK_API_BASE`, `AI_FALLBACK_API_KEY`
+    /// and `AI_FALLBACK_CHAT_MODEL`.
+    pub fn fallback_from_env() -> Option<Self> {
+        let api_base = std::env::var("AI_FALLBACK_API_BASE").ok()?;
+        let api_key = std::env::var("AI_FALLBACK_API_KEY").unwrap_or_default();
+        let chat_model = std::env::var("AI_FALLBACK_CHAT_MODEL").ok();
+        Some(OpenAiProvider::new("fallback", &api_key, Some(&api_base), chat_model))
+    }
+}
+
+fn classify(e: OpenAIError) -> ProviderError {
+    match e {
+        OpenAIError::Reqwest(e) => {
+            if e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error()) {
+                ProviderError::Unavailable(e.to_string())
+            } else {
+                ProviderError::Rejected(e.to_string())
+            }
+        }
+        OpenAIError::ApiError(api) => {
+            let kind = api.r#type.as_deref().unwrap_or_default();
+            let code = api.code.as_deref().unwrap_or_default();
+            if kind == "insufficient_quota" {
+                ProviderError::Rejected(api.to_string())
+            } else if code == "rate_limit_exceeded" || kind == "requests" || kind == "tokens" {
+                ProviderError::RateLimited(api.to_string())
+            } else if kind == "server_error" || code == "server_error" {
+                ProviderError::Unavailable(api.to_string())
+            } else {
+                ProviderError::Rejected(api.to_string())
+            }
+        }
+        // A body that isn't an API error object usually means a gateway 5xx page
+        OpenAIError::JSONDeserialize(e) => ProviderError::Unavailable(e.to_string()),
+        other => ProviderError::Rejected(other.to_string()),
+    }
+}
+
+#[async_trait]
+impl AiProvider for OpenAiProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn chat(&self, model: &str, prompt: &str) -> Result<Completion, ProviderError> {
+        let model = self.chat_model.as_deref().unwrap_or(model);
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages([
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(prompt)
+                    .build()
+                    .map_err(classify)?
+                    .into(),
+            ])
+            .build()
+            .map_err(classify)?;
+
+        let response = self.client.chat().create(request).await.map_err(classify)?;
+        let text = response
+            .choices
+            .first()
+            .and_then(|c| c.message.content.clone())
+            .unwrap_or_default();
+        let usage = response
+            .usage
+            .map(|u| Usage { prompt_tokens: u.prompt_tokens, completion_tokens: u.completion_tokens })
+            .unwrap_or_default();
+
+        Ok(Completion { text, model: model.to_string(), usage, degraded: false })
+    }
+
+    async fn embed(&self, model: &str, input: &str) -> Result<Embedding, ProviderError> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(model)
+            .input(EmbeddingInput::String(input.to_string()))
+            .build()
+            .map_err(classify)?;
+
+        let response = self.client.embeddings().create(request).await.map_err(classify)?;
+        let vector = response
+            .data
+            .into_iter()
+            .next()
+            .map(|d| d.embedding)
+            .ok_or_else(|| ProviderError::Unavailable("Empty embedding response".into()))?;
+
+        Ok(Embedding {
+            vector,
+            model: model.to_string(),
+            usage: Usage { prompt_tokens: response.usage.prompt_tokens, completion_tokens: 0 },
+        })
+    }
+}
+
+/// One recorded provider exchange, stored as `<kind>-<hash>.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Fixture {
+    kind: String,
+    model: String,
+    input: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    vector: Vec<f32>,
+    #[serde(default)]
+    prompt_tokens: u32,
+    #[serde(default)]
+    completion_tokens: u32,
+}
+
+/// Serves responses from fixture files instead of the network. With an inner
+/// provider it records: misses are forwarded and the response is saved.
+pub struct ReplayProvider {
+    dir: PathBuf,
+    recorder: Option<Arc<dyn AiProvider>>,
+}
+
+impl ReplayProvider {
+    pub fn replay(dir: impl Into<PathBuf>) -> Self {
+        ReplayProvider { dir: dir.into(), recorder: None }
+    }
+
+    pub fn record(dir: impl Into<PathBuf>, inner: Arc<dyn AiProvider>) -> Self {
+        ReplayProvider { dir: dir.into(), recorder: Some(inner) }
+    }
+
+    /// FNV-1a, so fixture names stay stable across Rust releases.
+    fn fixture_path(&self, kind: &str, model: &str, input: &str) -> PathBuf {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in model.bytes().chain(std::iter::once(0)).chain(input.bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        self.dir.join(format!("{}-{:016x}.json", kind, hash))
+    }
+
+    fn load(&self, kind: &str, model: &str, input: &str) -> Result<Option<Fixture>, ProviderError> {
+        let path = self.fixture_path(kind, model, input);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ProviderError::Rejected(format!("Cannot read {}: {}", path.display(), e))),
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| ProviderError::Rejected(format!("Invalid fixture {}: {}", path.display(), e)))
+    }
+
+    fn save(&self, fixture: &Fixture) -> Result<(), ProviderError> {
+        let path = self.fixture_path(&fixture.kind, &fixture.model, &fixture.input);
+        let json = serde_json::to_string_pretty(fixture)
+            .map_err(|e| ProviderError::Rejected(e.to_string()))?;
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&path, json + "\n"))
+            .map_err(|e| ProviderError::Rejected(format!("Cannot write {}: {}", path.display(), e)))?;
+        tracing::info!("Recorded AI fixture {}", path.display());
+        Ok(())
+    }
+
+    fn missing(&self, kind: &str, model: &str, input: &str) -> ProviderError {
+        ProviderError::Rejected(format!(
+            "No AI fixture {} (record it with AI_PROVIDER=record)",
+            self.fixture_path(kind, model, input).display()
+        ))
+    }
+}
+
+#[async_trait]
+impl AiProvider for ReplayProvider {
+    fn name(&self) -> &str {
+        if self.recorder.is_some() { "record" } else { "replay" }
+    }
+
+    async fn chat(&self, model: &str, prompt: &str) -> Result<Completion, ProviderError> {
+        if let Some(fixture) = self.load("chat", model, prompt)? {
+            return Ok(Completion {
+                text: fixture.text,
+                model: fixture.model,
+                usage: Usage { prompt_tokens: fixture.prompt_tokens, completion_tokens: fixture.completion_tokens },
+                degraded: false,
+            });
+        }
+        let Some(inner) = &self.recorder else {
+            return Err(self.missing("chat", model, prompt));
+        };
+
+        let completion = inner.chat(model, prompt).await?;
+        self.save(&Fixture {
+            kind: "chat".into(),
+            model: model.to_string(),
+            input: prompt.to_string(),
+            text: completion.text.clone(),
+            vector: vec![],
+            prompt_tokens: completion.usage.prompt_tokens,
+            completion_tokens: completion.usage.completion_tokens,
+        })?;
+        Ok(completion)
+    }
+
+    async fn embed(&self, model: &str, input: &str) -> Result<Embedding, ProviderError> {
+        if let Some(fixture) = self.load("embed", model, input)? {
+            return Ok(Embedding {
+                vector: fixture.vector,
+                model: fixture.model,
+                usage: Usage { prompt_tokens: fixture.prompt_tokens, completion_tokens: 0 },
+            });
+        }
+        let Some(inner) = &self.recorder else {
+            return Err(self.missing("embed", model, input));
+        };
+
+        let embedding = inner.embed(model, input).await?;
+        self.save(&Fixture {
+            kind: "embed".into(),
+            model: model.to_string(),
+            input: input.to_string(),
+            text: String::new(),
+            vector: embedding.vector.clone(),
+            prompt_tokens: embedding.usage.prompt_tokens,
+            completion_tokens: 0,
+        })?;
+        Ok(embedding)
+    }
+}
+
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// Set while the single trial call after a cooldown is running.
+    half_open_in_flight: bool,
+}
+
+/// Stops calling a provider after `threshold` consecutive transient failures,
+/// then lets a single trial call through once `cooldown` has passed.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState { consecutive_failures: 0, open_until: None, half_open_in_flight: false }),
+        }
+    }
+
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        match state.open_until {
+            Some(until) if now < until => false,
+            // A trial that never reported back (its caller went away) stops
+            // blocking after another cooldown
+            Some(until) if state.half_open_in_flight && now < until + self.cooldown => false,
+            Some(_) => {
+                // Half-open: one trial call; a failure re-opens for another cooldown
+                state.half_open_in_flight = true;
+                state.consecutive_failures = self.threshold.saturating_sub(1);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.consecutive_failures = 0;
+        state.open_until = None;
+        state.half_open_in_flight = false;
+    }
+
+    fn record_failure(&self, provider: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.consecutive_failures += 1;
+        let closed_or_trial = state.open_until.is_none() || state.half_open_in_flight;
+        if state.consecutive_failures >= self.threshold && closed_or_trial {
+            tracing::warn!("Opening circuit breaker for AI provider {} for {:?}", provider, self.cooldown);
+            state.open_until = Some(Instant::now() + self.cooldown);
+            state.half_open_in_flight = false;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_secs(20),
+            max_retries: 2,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_millis(4000),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The defaults, overridden by `AI_TIMEOUT_SECS`, `AI_MAX_RETRIES`,
+    /// `AI_RETRY_BASE_MS`, `AI_RETRY_MAX_MS`, `AI_BREAKER_THRESHOLD` and
+    /// `AI_BREAKER_COOLDOWN_SECS`.
+    pub fn from_env() -> Self {
+        let defaults = RetryPolicy::default();
+        RetryPolicy {
+            timeout: Duration::from_secs(env_parse("AI_TIMEOUT_SECS", defaults.timeout.as_secs())),
+            max_retries: env_parse("AI_MAX_RETRIES", defaults.max_retries),
+            base_delay: Duration::from_millis(env_parse("AI_RETRY_BASE_MS", defaults.base_delay.as_millis() as u64)),
+            max_delay: Duration::from_millis(env_parse("AI_RETRY_MAX_MS", defaults.max_delay.as_millis() as u64)),
+            breaker_threshold: env_parse("AI_BREAKER_THRESHOLD", defaults.breaker_threshold),
+            breaker_cooldown: Duration::from_secs(env_parse("AI_BREAKER_COOLDOWN_SECS", defaults.breaker_cooldown.as_secs())),
+        }
+    }
+
+    /// Exponential backoff with full jitter.
+    fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+struct Backend {
+    provider: Arc<dyn AiProvider>,
+    breaker: CircuitBreaker,
+}
+
+/// Calls providers in priority order with per-call timeouts, retries and a
+/// circuit breaker per provider.
+pub struct AiClient {
+    backends: Vec<Backend>,
+    policy: RetryPolicy,
+}
+
+impl AiClient {
+    pub fn new(providers: Vec<Arc<dyn AiProvider>>, policy: RetryPolicy) -> Self {
+        let backends = providers
+            .into_iter()
+            .map(|provider| Backend {
+                provider,
+                breaker: CircuitBreaker::new(policy.breaker_threshold.max(1), policy.breaker_cooldown),
+            })
+            .collect();
+        AiClient { backends, policy }
+    }
+
+    /// `None` when no provider is configured and callers should use heuristics.
+    ///
+    /// `AI_PROVIDER=replay` serves only fixtures from `AI_FIXTURES_DIR`;
+    /// `AI_PROVIDER=record` also forwards misses to the primary provider
+    /// (typically a local stand-in via `OPENAI_API_BASE`) and saves them.
+    pub fn from_env() -> Option<Self> {
+        let fixtures_dir = std::env::var("AI_FIXTURES_DIR").unwrap_or_else(|_| "tests/fixtures/ai".into());
+        let mut providers: Vec<Arc<dyn AiProvider>> = Vec::new();
+        match std::env::var("AI_PROVIDER").as_deref() {
+            Ok("replay") => providers.push(Arc::new(ReplayProvider::replay(fixtures_dir))),
+            Ok("record") => {
+                let Some(primary) = OpenAiProvider::primary_from_env() else {
+                    tracing::error!("AI_PROVIDER=record needs OPENAI_API_KEY (and usually OPENAI_API_BASE)");
+                    return None;
+                };
+                providers.push(Arc::new(ReplayProvider::record(fixtures_dir, Arc::new(primary))));
+            }
+            _ => {
+                if let Some(primary) = OpenAiProvider::primary_from_env() {
+                    providers.push(Arc::new(primary));
+                }
+            }
+        }
+        if let Some(fallback) = OpenAiProvider::fallback_from_env() {
+            providers.push(Arc::new(fallback));
+        }
+        if providers.is_empty() {
+            return None;
+        }
+        Some(AiClient::new(providers, RetryPolicy::from_env()))
+    }
+
+    async fn with_retries<T, F, Fut>(&self, backend: &Backend, op: F) -> Result<T, ProviderError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ProviderError>>,
+    {
+        let name = backend.provider.name();
+        let mut attempt = 0;
+        loop {
+            if !backend.breaker.allow() {
+                return Err(ProviderError::CircuitOpen(name.to_string()));
+            }
+
+            let result = match tokio::time::timeout(self.policy.timeout, op()).await {
+                Ok(result) => result,
+                Err(_) => Err(ProviderError::Timeout(self.policy.timeout)),
+            };
+
+            match result {
+                Ok(value) => {
+                    backend.breaker.record_success();
+                    return Ok(value);
+                }
+                Err(e) if e.is_transient() => {
+                    backend.breaker.record_failure(name);
+                    if attempt >= self.policy.max_retries {
+                        return Err(e);
+                    }
+                    let delay = self.policy.delay(attempt);
+                    tracing::warn!("AI provider {} failed ({}), retrying in {:?}", name, e, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    // The provider answered, so it is up even though it refused the request
+                    backend.breaker.record_success();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Try each provider in turn; anything but the primary marks the result degraded.
+    pub async fn chat(&self, model: &str, prompt: &str) -> Result<Completion, ProviderError> {
+        let mut last_error = ProviderError::Unavailable("No AI provider configured".into());
+        for (i, backend) in self.backends.iter().enumerate() {
+            match self.with_retries(backend, || backend.provider.chat(model, prompt)).await {
+                Ok(mut completion) => {
+                    completion.degraded = i > 0;
+                    return Ok(completion);
+                }
+                Err(e) => {
+                    tracing::warn!("AI provider {} gave up: {}", backend.provider.name(), e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Embeddings only come from the primary provider: vectors from different
+    /// models are not comparable with the ones already stored.
+    pub async fn embed(&self, model: &str, input: &str) -> Result<Embedding, ProviderError> {
+        let backend = self
+            .backends
+            .first()
+            .ok_or_else(|| ProviderError::Unavailable("No AI provider configured".into()))?;
+        self.with_retries(backend, || backend.provider.embed(model, input)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails with a transient error for the first `failures` calls.
+    struct Flaky {
+        name: &'static str,
+        failures: u32,
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl AiProvider for Flaky {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn chat(&self, model: &str, _prompt: &str) -> Result<Completion, ProviderError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(ProviderError::Unavailable("502 Bad Gateway".into()));
+            }
+            Ok(Completion { text: self.name.to_string(), model: model.to_string(), usage: Usage::default(), degraded: false })
+        }
+
+        async fn embed(&self, _model: &str, _input: &str) -> Result<Embedding, ProviderError> {
+            Err(ProviderError::Rejected("not supported".into()))
+        }
+    }
+
+    fn flaky(name: &'static str, failures: u32) -> Arc<Flaky> {
+        Arc::new(Flaky { name, failures, calls: AtomicU32::new(0) })
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_secs(1),
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            breaker_threshold: 3,
+            breaker_cooldown: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_on_the_primary() {
+        let primary = flaky("primary", 2);
+        let client = AiClient::new(vec![primary.clone()], policy());
+
+        let completion = client.chat("m", "p").await.unwrap();
+
+        assert_eq!(completion.text, "primary");
+        assert!(!completion.degraded);
+        assert_eq!(primary.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_opens_the_breaker() {
+        let primary = flaky("primary", u32::MAX);
+        let client = AiClient::new(vec![primary.clone(), flaky("secondary", 0)], policy());
+
+        let completion = client.chat("m", "p").await.unwrap();
+        assert_eq!(completion.text, "secondary");
+        assert!(completion.degraded);
+
+        // Three failures opened the breaker, so the primary is skipped entirely
+        client.chat("m", "p").await.unwrap();
+        assert_eq!(primary.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn half_open_breaker_lets_one_trial_through() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        breaker.record_failure("p");
+        assert!(breaker.allow());
+        breaker.record_failure("p");
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        assert!(!breaker.allow(), "only one trial call while half-open");
+
+        breaker.record_failure("p");
+        assert!(!breaker.allow(), "a failed trial re-opens the breaker");
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,352 @@
+use anyhow::{anyhow, Context, Result};
+use neo4rs::{query, Graph};
+use regex::Regex;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Extra check on a regex match, e.g. a checksum, to cut false positives.
+type Validator = fn(&str) -> bool;
+
+/// A pattern whose matches are replaced by `[KIND_n]` placeholders.
+struct Rule {
+    kind: String,
+    regex: Regex,
+    validate: Option<Validator>,
+}
+
+pub struct Redactor {
+    enabled: bool,
+    rules: Vec<Rule>,
+}
+
+/// Placeholders issued while preparing one AI request. The same value always
+/// maps to the same placeholder so the model can still refer to it consistently.
+pub struct RedactionSession<'a> {
+    redactor: &'a Redactor,
+    by_value: HashMap<String, String>,
+    counters: HashMap<String, usize>,
+}
+
+const BUILTIN_KINDS: &[&str] = &["email", "iban", "card", "phone"];
+
+fn builtin_rule(kind: &str) -> Option<Rule> {
+    let (pattern, validate): (&str, Option<Validator>) = match kind {
+        "email" => (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}", None),
+        // `\d` would also match non-ASCII digits
+        "iban" => (r"\b[A-Z]{2}[0-9]{2}(?: ?[A-Z0-9]){11,30}\b", Some(iban_valid)),
+        "card" => (r"\b[0-9](?:[ -]?[0-9]){12,18}\b", Some(luhn_valid)),
+        "phone" => (
+            r"(?:\+[0-9]{1,3}[ .-]?)?(?:\([0-9]{1,4}\)[ .-]?)?[0-9]{2,4}(?:[ .-]?[0-9]{2,4}){2,4}",
+            Some(phone_valid),
+        ),
+        _ => return None,
+    };
+    Some(Rule {
+        kind: kind.to_uppercase(),
+        regex: Regex::new(pattern).expect("built-in redaction pattern is valid"),
+        validate,
+    })
+}
+
+fn digits(value: &str) -> Vec<u32> {
+    value.chars().filter_map(|c| c.to_digit(10)).collect()
+}
+
+fn luhn_valid(value: &str) -> bool {
+    let digits = digits(value);
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| if i % 2 == 1 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { d })
+        .sum();
+    sum.rem_euclid(10) == 0
+}
+
+fn iban_valid(value: &str) -> bool {
+    let compact: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    if !compact.is_ascii() || !(15..=34).contains(&compact.len()) {
+        return false;
+    }
+    let rearranged = format!("{}{}", &compact[4..], &compact[..4]);
+    let mut remainder = 0u32;
+    for c in rearranged.chars() {
+        let value = match c.to_digit(36) {
+            Some(v) => v,
+            None => return false,
+        };
+        remainder = if value >= 10 {
+            (remainder * 100 + value) % 97
+        } else {
+            (remainder * 10 + value) % 97
+        };
+    }
+    remainder == 1
+}
+
+fn phone_valid(value: &str) -> bool {
+    // Dates like 2024-05-01 have the right shape but are not phone numbers
+    let is_date = value.len() == 10
+        && value.chars().enumerate().all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() });
+    !is_date && (7..=15).contains(&digits(value).len())
+}
+
+impl Redactor {
+    /// Build from the environment:
+    /// - `AI_REDACTION=off` disables redaction entirely
+    /// - `AI_REDACT_KINDS` limits the built-in detectors (default `email,iban,card,phone`)
+    /// - `AI_REDACT_PATTERNS_FILE` adds custom `NAME = regex` lines
+    pub fn from_env() -> Result<Self> {
+        let enabled = !matches!(
+            std::env::var("AI_REDACTION").as_deref(),
+            Ok("off") | Ok("false") | Ok("0")
+        );
+        let kinds = std::env::var("AI_REDACT_KINDS").unwrap_or_else(|_| BUILTIN_KINDS.join(","));
+        let patterns = match std::env::var("AI_REDACT_PATTERNS_FILE") {
+            Ok(path) => Some(
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("Cannot read AI_REDACT_PATTERNS_FILE {}", path))?,
+            ),
+            Err(_) => None,
+        };
+        Self::from_config(enabled, &kinds, patterns.as_deref())
+    }
+
+    /// Build from comma-separated built-in `kinds` and the contents of a
+    /// custom patterns file.
+    pub(crate) fn from_config(enabled: bool, kinds: &str, patterns: Option<&str>) -> Result<Self> {
+        let mut rules = Vec::new();
+        for kind in kinds.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+            rules.push(builtin_rule(kind).ok_or_else(|| anyhow!("Unknown redaction kind: {}", kind))?);
+        }
+
+        if let Some(contents) = patterns {
+            // Custom patterns run first so they win over the generic detectors
+            let mut custom = Vec::new();
+            for line in contents.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (name, pattern) = line
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid redaction pattern line: {}", line))?;
+                custom.push(Rule {
+                    kind: name.trim().to_uppercase(),
+                    regex: Regex::new(pattern.trim())
+                        .with_context(|| format!("Invalid redaction regex for {}", name.trim()))?,
+                    validate: None,
+                });
+            }
+            custom.append(&mut rules);
+            rules = custom;
+        }
+
+        Ok(Redactor { enabled, rules })
+    }
+
+    /// [`Redactor::from_env`], failing closed: a broken custom config still
+    /// masks the built-in kinds.
+    pub fn load() -> Self {
+        Self::from_env().unwrap_or_else(|e| {
+            tracing::error!("Invalid redaction config, using built-in detectors: {}", e);
+            Redactor {
+                enabled: true,
+                rules: BUILTIN_KINDS.iter().filter_map(|k| builtin_rule(k)).collect(),
+            }
+        })
+    }
+
+    pub fn session(&self) -> RedactionSession<'_> {
+        RedactionSession {
+            redactor: self,
+            by_value: HashMap::new(),
+            counters: HashMap::new(),
+        }
+    }
+}
+
+impl RedactionSession<'_> {
+    /// Replace sensitive values in `text` with placeholders.
+    pub fn redact(&mut self, text: &str) -> String {
+        if !self.redactor.enabled {
+            return text.to_string();
+        }
+
+        let mut text = text.to_string();
+        for rule in &self.redactor.rules {
+            let mut out = String::with_capacity(text.len());
+            let mut last = 0;
+            for m in rule.regex.find_iter(&text) {
+                if rule.validate.is_some_and(|valid| !valid(m.as_str())) {
+                    continue;
+                }
+                out.push_str(&text[last..m.start()]);
+                out.push_str(&self.placeholder(&rule.kind, m.as_str()));
+                last = m.end();
+            }
+            out.push_str(&text[last..]);
+            text = out;
+        }
+        text
+    }
+
+    fn placeholder(&mut self, kind: &str, value: &str) -> String {
+        if let Some(existing) = self.by_value.get(value) {
+            return existing.clone();
+        }
+        let counter = self.counters.entry(kind.to_string()).or_insert(0);
+        *counter += 1;
+        let placeholder = format!("[{}_{}]", kind, counter);
+        self.by_value.insert(value.to_string(), placeholder.clone());
+        placeholder
+    }
+
+    /// Put the original values back into model output.
+    pub fn restore(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (value, placeholder) in &self.by_value {
+            text = text.replace(placeholder.as_str(), value);
+        }
+        text
+    }
+}
+
+/// Labels that always block AI processing, from `AI_EXCLUDED_LABELS`, in
+/// addition to labels with `ai_excluded` set on the node.
+pub fn env_excluded_labels() -> Vec<String> {
+    parse_label_list(&std::env::var("AI_EXCLUDED_LABELS").unwrap_or_default())
+}
+
+fn parse_label_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Whether a label forbids sending its emails to an external model.
+fn label_blocks_ai(name: &str, ai_excluded: bool, excluded: &[String]) -> bool {
+    ai_excluded || excluded.iter().any(|l| l == name)
+}
+
+/// Whether any of these emails carries a label whose policy forbids sending
+/// its content to an external model.
+pub async fn is_ai_blocked(graph: &Graph, email_ids: &[Uuid]) -> Result<bool> {
+    if email_ids.is_empty() {
+        return Ok(false);
+    }
+
+    let cypher = r#"
+        MATCH (e:Email)-[:HAS_LABEL]->(l:Label)
+        WHERE e.id IN $ids
+        RETURN DISTINCT l.name as name, coalesce(l.ai_excluded, false) as ai_excluded
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("ids", email_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>()))
+        .await?;
+
+    let excluded = env_excluded_labels();
+    while let Some(row) = result.next().await? {
+        let name: String = row.get("name").unwrap_or_default();
+        if label_blocks_ai(&name, row.get("ai_excluded").unwrap_or(false), &excluded) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor() -> Redactor {
+        Redactor::from_config(true, &BUILTIN_KINDS.join(","), None).unwrap()
+    }
+
+    #[test]
+    fn masks_emails_ibans_cards_and_phones() {
+        let redactor = redactor();
+        let mut session = redactor.session();
+        let text = "Mail bob@example.com, pay GB82 WEST 1234 5698 7654 32 with 4111 1111 1111 1111 or call +1 415 555 2671";
+        assert_eq!(
+            session.redact(text),
+            "Mail [EMAIL_1], pay [IBAN_1] with [CARD_1] or call [PHONE_1]"
+        );
+    }
+
+    #[test]
+    fn validators_reject_lookalikes() {
+        assert!(luhn_valid("4111 1111 1111 1111"));
+        assert!(!luhn_valid("4111 1111 1111 1112"));
+        assert!(!luhn_valid("4111 1111 1111"));
+        assert!(iban_valid("GB82 WEST 1234 5698 7654 32"));
+        assert!(!iban_valid("GB83 WEST 1234 5698 7654 32"));
+        assert!(phone_valid("+1 415 555 2671"));
+        assert!(!phone_valid("12-34-56"));
+        assert!(!phone_valid("2024-05-01"));
+        assert!(!phone_valid("1234 5678 9012 3456"));
+
+        let redactor = redactor();
+        let mut session = redactor.session();
+        let text = "Order 4111 1111 1111 1112 shipped 2024-05-01, ref GB83 WEST 1234 5698 7654 32";
+        assert!(!session.redact(text).contains("[CARD_"));
+        assert!(!session.redact(text).contains("[IBAN_"));
+        assert!(session.redact("on 2024-05-01").ends_with("2024-05-01"));
+    }
+
+    #[test]
+    fn ignores_non_ascii_digits() {
+        assert!(!iban_valid("GB٨٢ WEST 1234 5698 7654 32"));
+        assert!(!iban_valid("GB８２WEST１２３４５６９８７６５４３２"));
+
+        let redactor = redactor();
+        let mut session = redactor.session();
+        for text in ["ref GB٨٢WEST١٢٣٤٥٦٩٨٧٦٥٤٣٢", "ref GB８２WEST１２３４５６９８７６５４３２ ok", "call ٤١٥ ٥٥٥ ٢٦٧١"] {
+            assert_eq!(session.redact(text), text);
+        }
+    }
+
+    #[test]
+    fn custom_patterns_run_before_builtins() {
+        let patterns = "# internal identifiers\n\nEMPLOYEE = EMP-\\d{6}\nproject=\\bPRJ-[A-Z]+\\b\n";
+        let redactor = Redactor::from_config(true, "email", Some(patterns)).unwrap();
+        let mut session = redactor.session();
+        assert_eq!(
+            session.redact("EMP-123456 on PRJ-ATLAS, cc EMP-123456 and ann@example.com"),
+            "[EMPLOYEE_1] on [PROJECT_1], cc [EMPLOYEE_1] and [EMAIL_1]"
+        );
+
+        assert!(Redactor::from_config(true, "email", Some("no equals sign")).is_err());
+        assert!(Redactor::from_config(true, "email", Some("BAD = (")).is_err());
+        assert!(Redactor::from_config(true, "passport", None).is_err());
+    }
+
+    #[test]
+    fn placeholders_round_trip() {
+        let redactor = redactor();
+        let mut session = redactor.session();
+        let redacted = session.redact("Ask ann@example.com and bob@example.com, then ann@example.com again");
+        assert_eq!(redacted, "Ask [EMAIL_1] and [EMAIL_2], then [EMAIL_1] again");
+        assert_eq!(
+            session.restore("Reply to [EMAIL_2] and [EMAIL_1]"),
+            "Reply to bob@example.com and ann@example.com"
+        );
+
+        let disabled = Redactor::from_config(false, "email", None).unwrap();
+        assert_eq!(disabled.session().redact("ann@example.com"), "ann@example.com");
+    }
+
+    #[test]
+    fn excluded_labels_block_ai() {
+        let excluded = parse_label_list(" Legal, HR ,,");
+        assert_eq!(excluded, vec!["Legal".to_string(), "HR".to_string()]);
+        assert!(label_blocks_ai("HR", false, &excluded));
+        assert!(label_blocks_ai("Medical", true, &excluded));
+        assert!(!label_blocks_ai("Work", false, &excluded));
+        assert!(!label_blocks_ai("hr", false, &excluded));
+    }
+}
@@ -0,0 +1,274 @@
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use neo4rs::{query, Graph};
+use std::collections::HashSet;
+
+use crate::models::{
+    Contact, ContactInfo, Correspondent, DormantQuery, Introduction, Introductions, ResponseTimeStats, ResponseTimes,
+    SharedThread,
+};
+use crate::services::{contacts, emails::parse_date, mail};
+
+async fn ensure_contact(graph: &Graph, email: &str) -> Result<()> {
+    let mut result = graph
+        .execute(query("MATCH (c:Contact {email: $email}) RETURN count(c) > 0 as exists").param("email", email))
+        .await?;
+    match result.next().await? {
+        Some(row) if row.get("exists").unwrap_or(false) => Ok(()),
+        _ => Err(anyhow!("Contact not found")),
+    }
+}
+
+fn contact(row: &neo4rs::Row, prefix: &str) -> Result<Contact> {
+    Ok(Contact {
+        email: row.get(&format!("{}_email", prefix))?,
+        name: row.get(&format!("{}_name", prefix)).ok(),
+    })
+}
+
+/// People the contact exchanged the most email with, in either direction.
+pub async fn top_correspondents(graph: &Graph, email: &str, limit: usize) -> Result<Vec<Correspondent>> {
+    ensure_contact(graph, email).await?;
+
+    let cypher = r#"
+        MATCH (c:Contact {email: $email})
+        CALL {
+            WITH c
+            MATCH (c)<-[:SENT_BY]-(e:Email)-[:SENT_TO|CC]->(o:Contact)
+            WHERE o <> c
+            RETURN o, e, true as outgoing
+            UNION
+            WITH c
+            MATCH (c)<-[:SENT_TO|CC]-(e:Email)-[:SENT_BY]->(o:Contact)
+            WHERE o <> c
+            RETURN o, e, false as outgoing
+        }
+        WITH o,
+             count(DISTINCT CASE WHEN outgoing THEN e END) as sent,
+             count(DISTINCT CASE WHEN NOT outgoing THEN e END) as received,
+             max(e.date) as last_contacted
+        RETURN o.email as email, o.name as name, sent, received, last_contacted
+        ORDER BY sent + received DESC, last_contacted DESC
+        LIMIT $limit
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("email", email).param("limit", limit.clamp(1, 100) as i64))
+        .await?;
+
+    let mut correspondents = Vec::new();
+    while let Some(row) = result.next().await? {
+        correspondents.push(Correspondent {
+            email: row.get("email")?,
+            name: row.get("name").ok(),
+            sent: row.get::<i64>("sent").unwrap_or(0) as u64,
+            received: row.get::<i64>("received").unwrap_or(0) as u64,
+            last_contacted: row.get("last_contacted").ok(),
+        });
+    }
+    Ok(correspondents)
+}
+
+async fn collect_introductions(graph: &Graph, q: neo4rs::Query) -> Result<Vec<Introduction>> {
+    let mut result = graph.execute(q).await?;
+    let mut introductions = Vec::new();
+    let mut seen = HashSet::new();
+    while let Some(row) = result.next().await? {
+        let mut introduced = vec![contact(&row, "a")?, contact(&row, "b")?];
+        introduced.sort_by(|x, y| x.email.cmp(&y.email));
+        let key = (introduced[0].email.clone(), introduced[1].email.clone());
+        if !seen.insert(key) {
+            continue;
+        }
+        introductions.push(Introduction {
+            introducer: contact(&row, "introducer")?,
+            introduced,
+            email_id: row.get("email_id")?,
+            subject: row.get("subject").unwrap_or_default(),
+            date: row.get("date").ok(),
+        });
+    }
+    Ok(introductions)
+}
+
+/// Introductions involving the contact: the first email where two people
+/// appeared together, sent by a third who copied at least one of them.
+pub async fn introductions(graph: &Graph, email: &str) -> Result<Introductions> {
+    ensure_contact(graph, email).await?;
+
+    let received = r#"
+        MATCH (c:Contact {email: $email})<-[:SENT_BY|SENT_TO|CC]-(e:Email)-[:SENT_BY|SENT_TO|CC]->(y:Contact)
+        WHERE y <> c
+        WITH c, y, e ORDER BY e.date
+        WITH c, y, collect(DISTINCT e)[0] as first
+        MATCH (first)-[:SENT_BY]->(introducer:Contact)
+        WHERE introducer <> c AND introducer <> y
+          AND (EXISTS { MATCH (first)-[:CC]->(c) } OR EXISTS { MATCH (first)-[:CC]->(y) })
+        RETURN introducer.email as introducer_email, introducer.name as introducer_name,
+               c.email as a_email, c.name as a_name, y.email as b_email, y.name as b_name,
+               first.id as email_id, first.subject as subject, first.date as date
+        ORDER BY first.date DESC
+    "#;
+    let made = r#"
+        MATCH (c:Contact {email: $email})<-[:SENT_BY]-(e:Email)-[:CC]->(y:Contact),
+              (e)-[:SENT_TO|CC]->(z:Contact)
+        WHERE y <> c AND z <> c AND y <> z
+          AND NOT EXISTS {
+              MATCH (y)<-[:SENT_BY|SENT_TO|CC]-(earlier:Email)-[:SENT_BY|SENT_TO|CC]->(z)
+              WHERE earlier.date < e.date
+          }
+        RETURN c.email as introducer_email, c.name as introducer_name,
+               y.email as a_email, y.name as a_name, z.email as b_email, z.name as b_name,
+               e.id as email_id, e.subject as subject, e.date as date
+        ORDER BY e.date DESC
+    "#;
+
+    Ok(Introductions {
+        received: collect_introductions(graph, query(received).param("email", email)).await?,
+        made: collect_introductions(graph, query(made).param("email", email)).await?,
+    })
+}
+
+/// Threads both contacts took part in, most recent first.
+pub async fn shared_threads(graph: &Graph, email: &str, other: &str) -> Result<Vec<SharedThread>> {
+    ensure_contact(graph, email).await?;
+    ensure_contact(graph, other).await?;
+
+    let cypher = r#"
+        MATCH (:Contact {email: $email})<-[:SENT_BY|SENT_TO|CC]-(:Email)-[:IN_THREAD]->(t:Thread)
+        WITH DISTINCT t
+        WHERE EXISTS {
+            MATCH (:Contact {email: $other})<-[:SENT_BY|SENT_TO|CC]-(:Email)-[:IN_THREAD]->(t)
+        }
+        MATCH (m:Email)-[:IN_THREAD]->(t)
+        WITH t, count(m) as message_count, max(m.date) as last_date
+        RETURN t.id as thread_id, t.subject as subject, message_count, last_date
+        ORDER BY last_date DESC
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("email", email).param("other", other))
+        .await?;
+
+    let mut threads = Vec::new();
+    while let Some(row) = result.next().await? {
+        threads.push(SharedThread {
+            thread_id: row.get("thread_id")?,
+            subject: row.get("subject").unwrap_or_default(),
+            message_count: row.get::<i64>("message_count").unwrap_or(0) as u64,
+            last_date: row.get("last_date").ok(),
+        });
+    }
+    Ok(threads)
+}
+
+fn percentile(sorted: &[i64], p: f64) -> i64 {
+    let rank = ((sorted.len() as f64) * p).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Summary statistics over reply delays, ignoring negative ones from skewed
+/// clocks.
+fn response_stats(delays: Vec<i64>) -> ResponseTimeStats {
+    let mut delays: Vec<i64> = delays.into_iter().filter(|d| *d >= 0).collect();
+    if delays.is_empty() {
+        return ResponseTimeStats::default();
+    }
+    delays.sort_unstable();
+    let count = delays.len();
+    let median = (delays[(count - 1) / 2] + delays[count / 2]) / 2;
+    ResponseTimeStats {
+        count: count as u64,
+        mean_secs: Some(delays.iter().sum::<i64>() / count as i64),
+        median_secs: Some(median),
+        p90_secs: Some(percentile(&delays, 0.9)),
+        fastest_secs: delays.first().copied(),
+        slowest_secs: delays.last().copied(),
+    }
+}
+
+async fn reply_delays(graph: &Graph, cypher: &str, email: &str) -> Result<Vec<i64>> {
+    let mut result = graph.execute(query(cypher).param("email", email)).await?;
+    let mut delays = Vec::new();
+    while let Some(row) = result.next().await? {
+        if let (Some(reply), Some(parent)) = (parse_date(row.get("reply").ok()), parse_date(row.get("parent").ok())) {
+            delays.push((reply - parent).num_seconds());
+        }
+    }
+    Ok(delays)
+}
+
+/// How quickly the contact replies, and how quickly others reply to them.
+pub async fn response_times(graph: &Graph, email: &str) -> Result<ResponseTimes> {
+    ensure_contact(graph, email).await?;
+
+    let theirs = r#"
+        MATCH (c:Contact {email: $email})<-[:SENT_BY]-(r:Email)-[:REPLIED_TO]->(p:Email)-[:SENT_BY]->(o:Contact)
+        WHERE o <> c
+        RETURN r.date as reply, p.date as parent
+    "#;
+    let to_them = r#"
+        MATCH (c:Contact {email: $email})<-[:SENT_BY]-(p:Email)<-[:REPLIED_TO]-(r:Email)-[:SENT_BY]->(o:Contact)
+        WHERE o <> c
+        RETURN r.date as reply, p.date as parent
+    "#;
+
+    Ok(ResponseTimes {
+        their_replies: response_stats(reply_delays(graph, theirs, email).await?),
+        replies_to_them: response_stats(reply_delays(graph, to_them, email).await?),
+    })
+}
+
+/// Longest dormancy period accepted, about a century.
+const MAX_DORMANT_DAYS: i64 = 36_500;
+
+/// Contacts the mailbox used to email regularly but has not in `days`,
+/// counting email to any of their addresses.
+pub async fn dormant(graph: &Graph, params: DormantQuery) -> Result<Vec<ContactInfo>> {
+    let cutoff = (Utc::now() - Duration::days(params.days.clamp(0, MAX_DORMANT_DAYS))).to_rfc3339();
+    let cypher = format!(
+        r#"
+        MATCH (c:Contact)
+        WHERE c.email <> $me AND {}
+        {}
+        WHERE email_count >= $min_emails AND last_contacted < $cutoff
+        {}
+        ORDER BY email_count DESC, last_contacted DESC
+        LIMIT $limit
+        "#,
+        contacts::PRIMARY,
+        contacts::STATS,
+        contacts::RETURN_INFO
+    );
+    let mut result = graph
+        .execute(
+            query(&cypher)
+                .param("me", mail::sender().email)
+                .param("min_emails", params.min_emails as i64)
+                .param("cutoff", cutoff)
+                .param("limit", params.limit.clamp(1, 200) as i64),
+        )
+        .await?;
+
+    let mut quiet = Vec::new();
+    while let Some(row) = result.next().await? {
+        quiet.push(contacts::info_from_row(&row)?);
+    }
+    Ok(quiet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_reply_delays() {
+        assert_eq!(response_stats(vec![]), ResponseTimeStats::default());
+
+        let stats = response_stats(vec![600, 60, -30, 3600, 120]);
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.mean_secs, Some(1095));
+        assert_eq!(stats.median_secs, Some(360));
+        assert_eq!(stats.p90_secs, Some(3600));
+        assert_eq!(stats.fastest_secs, Some(60));
+        assert_eq!(stats.slowest_secs, Some(3600));
+    }
+}
@@ -0,0 +1,584 @@
+//! Conversation threading after Jamie Zawinski's algorithm: messages are
+//! linked through `Message-ID`, `In-Reply-To` and `References`, and replies
+//! whose ancestors are unknown fall back to matching on the subject.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use neo4rs::{query, Graph};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::services::emails::parse_date;
+
+/// Emails written per query when rebuilding.
+const BATCH: usize = 500;
+
+/// What threading needs to know about a message.
+#[derive(Debug, Clone)]
+pub struct ThreadMessage {
+    pub id: Uuid,
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    pub subject: String,
+    pub date: DateTime<Utc>,
+}
+
+impl ThreadMessage {
+    /// Ancestors from the root down, ending with the direct parent.
+    fn ancestry(&self) -> Vec<String> {
+        let mut chain = self.references.clone();
+        if let Some(parent) = &self.in_reply_to {
+            chain.retain(|id| id != parent);
+            chain.push(parent.clone());
+        }
+        chain
+    }
+}
+
+/// Subject with list tags and reply prefixes (`Re:`, `Re[2]:`, `AW:`, `SV:`)
+/// removed and lowercased, and whether any reply prefix was present.
+pub fn normalize_subject(subject: &str) -> (String, bool) {
+    let mut rest = subject.trim();
+    let mut is_reply = false;
+    loop {
+        if rest.starts_with('[') {
+            if let Some(end) = rest.find(']') {
+                rest = rest[end + 1..].trim_start();
+                continue;
+            }
+        }
+        let Some((prefix, tail)) = rest.split_once(':') else { break };
+        let prefix = prefix.trim().to_lowercase();
+        let word = prefix.split('[').next().unwrap_or_default();
+        if matches!(word, "re" | "aw" | "sv" | "antw") && prefix.len() <= word.len() + 4 {
+            is_reply = true;
+            rest = tail.trim_start();
+        } else {
+            break;
+        }
+    }
+    (rest.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase(), is_reply)
+}
+
+/// Result of threading a set of messages.
+#[derive(Debug, Default)]
+pub struct Threading {
+    /// Message ids per thread, each in date order.
+    pub threads: Vec<Vec<Uuid>>,
+    /// Each reply's nearest ancestor within the set.
+    pub parents: HashMap<Uuid, Uuid>,
+}
+
+#[derive(Default)]
+struct Container {
+    message: Option<usize>,
+    parent: Option<usize>,
+}
+
+struct Containers {
+    nodes: Vec<Container>,
+    by_id: HashMap<String, usize>,
+}
+
+impl Containers {
+    fn get(&mut self, message_id: &str) -> usize {
+        if let Some(&index) = self.by_id.get(message_id) {
+            return index;
+        }
+        self.nodes.push(Container::default());
+        self.by_id.insert(message_id.to_string(), self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    /// Whether `ancestor` is `node` or above it.
+    fn is_ancestor(&self, ancestor: usize, mut node: usize) -> bool {
+        loop {
+            if node == ancestor {
+                return true;
+            }
+            match self.nodes[node].parent {
+                Some(parent) => node = parent,
+                None => return false,
+            }
+        }
+    }
+
+    fn link(&mut self, parent: usize, child: usize) {
+        self.nodes[child].parent = Some(parent);
+    }
+
+    fn root(&self, mut node: usize) -> usize {
+        while let Some(parent) = self.nodes[node].parent {
+            node = parent;
+        }
+        node
+    }
+}
+
+/// Thread a set of messages.
+pub fn thread(messages: &[ThreadMessage]) -> Threading {
+    let mut containers = Containers { nodes: Vec::new(), by_id: HashMap::new() };
+    let mut of_message = Vec::with_capacity(messages.len());
+
+    for (index, message) in messages.iter().enumerate() {
+        let known = message.message_id.as_deref().map(|id| containers.get(id));
+        let container = match known {
+            Some(container) if containers.nodes[container].message.is_none() => container,
+            // Missing or duplicate Message-ID: thread it on its own references only
+            _ => {
+                containers.nodes.push(Container::default());
+                containers.nodes.len() - 1
+            }
+        };
+        containers.nodes[container].message = Some(index);
+        of_message.push(container);
+
+        // Link the reference chain without overriding links already made
+        let mut previous: Option<usize> = None;
+        for id in message.ancestry() {
+            let current = containers.get(&id);
+            if let Some(parent) = previous {
+                if containers.nodes[current].parent.is_none() && !containers.is_ancestor(current, parent) {
+                    containers.link(parent, current);
+                }
+            }
+            previous = Some(current);
+        }
+
+        // The message's own parent is always its last reference
+        if let Some(parent) = previous {
+            if !containers.is_ancestor(container, parent) {
+                containers.link(parent, container);
+            }
+        }
+    }
+
+    let mut threading = Threading::default();
+    for (index, &container) in of_message.iter().enumerate() {
+        let mut node = container;
+        while let Some(parent) = containers.nodes[node].parent {
+            if let Some(message) = containers.nodes[parent].message {
+                threading.parents.insert(messages[index].id, messages[message].id);
+                break;
+            }
+            node = parent;
+        }
+    }
+
+    // Group by root container; empty roots hold siblings whose parent is missing
+    let mut by_root: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (index, &container) in of_message.iter().enumerate() {
+        by_root.entry(containers.root(container)).or_default().push(index);
+    }
+    let mut groups: Vec<Vec<usize>> = by_root.into_values().collect();
+    for group in &mut groups {
+        group.sort_by_key(|&i| (messages[i].date, messages[i].id));
+    }
+    groups.sort_by_key(|group| (messages[group[0]].date, messages[group[0]].id));
+
+    // Subject fallback: a root that is a reply joins the latest earlier thread
+    // with the same subject within the window. Unrelated messages that merely
+    // share a subject ("Weekly report") stay apart.
+    let window = subject_window();
+    let mut by_subject: HashMap<String, usize> = HashMap::new();
+    let mut merged: Vec<Vec<usize>> = Vec::new();
+    for group in groups {
+        let (key, is_reply) = normalize_subject(&messages[group[0]].subject);
+        let recent = |target: usize| {
+            let last = merged[target].iter().map(|&i| messages[i].date).max().unwrap_or_default();
+            messages[group[0]].date - last <= window
+        };
+        match by_subject.get(&key) {
+            Some(&target) if is_reply && !key.is_empty() && recent(target) => merged[target].extend(group),
+            _ => {
+                if !key.is_empty() {
+                    by_subject.insert(key, merged.len());
+                }
+                merged.push(group);
+            }
+        }
+    }
+
+    threading.threads = merged
+        .into_iter()
+        .map(|mut group| {
+            group.sort_by_key(|&i| (messages[i].date, messages[i].id));
+            group.into_iter().map(|i| messages[i].id).collect()
+        })
+        .collect();
+    threading
+}
+
+fn subject_window() -> Duration {
+    Duration::days(
+        std::env::var("THREAD_SUBJECT_WINDOW_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30),
+    )
+}
+
+async fn load(graph: &Graph, id: Uuid) -> Result<ThreadMessage> {
+    let cypher = r#"
+        MATCH (e:Email {id: $id})
+        RETURN e.message_id as message_id, e.in_reply_to as in_reply_to, e.references as references,
+               e.subject as subject, e.date as date
+    "#;
+    let mut result = graph.execute(query(cypher).param("id", id.to_string())).await?;
+    let row = result.next().await?.ok_or_else(|| anyhow!("Email not found"))?;
+    Ok(ThreadMessage {
+        id,
+        message_id: row.get("message_id").ok(),
+        in_reply_to: row.get("in_reply_to").ok(),
+        references: row.get("references").unwrap_or_default(),
+        subject: row.get("subject").unwrap_or_default(),
+        date: parse_date(row.get("date").ok()).unwrap_or_else(Utc::now),
+    })
+}
+
+/// Move every email of `from` threads into `target` and drop the emptied
+/// `Thread` nodes. Threads corrected by hand (split, moved or merged) are left
+/// alone.
+async fn merge_threads(graph: &Graph, target: Uuid, from: &[Uuid]) -> Result<()> {
+    if from.is_empty() {
+        return Ok(());
+    }
+    let from: Vec<String> = from.iter().map(Uuid::to_string).collect();
+    let cypher = r#"
+        MERGE (t:Thread {id: $target})
+        WITH t
+        MATCH (x:Email)
+        WHERE x.thread_id IN $from
+          AND NOT EXISTS { MATCH (x)-[:IN_THREAD]->(:Thread {manual: true}) }
+        SET x.thread_id = $target
+        WITH t, x
+        OPTIONAL MATCH (x)-[r:IN_THREAD]->(:Thread)
+        DELETE r
+        WITH DISTINCT t, x
+        MERGE (x)-[:IN_THREAD]->(t)
+    "#;
+    graph
+        .run(query(cypher).param("target", target.to_string()).param("from", from.clone()))
+        .await?;
+    graph
+        .run(
+            query("MATCH (t:Thread) WHERE t.id IN $from AND NOT coalesce(t.manual, false) DETACH DELETE t")
+                .param("from", from),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Place a stored email in its conversation: link it to its parent and to
+/// replies that arrived before it, join the parent's thread (or a recent
+/// thread with the same subject when it is a reply to something unknown), and
+/// merge threads it turns out to connect. Returns the thread id.
+pub async fn attach(graph: &Graph, id: Uuid) -> Result<Uuid> {
+    let message = load(graph, id).await?;
+    let ancestry = message.ancestry();
+    let own_id = message.message_id.clone().unwrap_or_default();
+
+    let cypher = r#"
+        MATCH (o:Email)
+        WHERE o.id <> $id
+          AND (o.message_id IN $ancestry
+               OR ($message_id <> '' AND (o.in_reply_to = $message_id OR $message_id IN o.references))
+               OR EXISTS { MATCH (:Email {id: $id})-[:REPLIED_TO]->(o) })
+        RETURN o.id as id, o.message_id as message_id, o.thread_id as thread_id,
+               o.in_reply_to as in_reply_to, o.references as references,
+               EXISTS { MATCH (:Email {id: $id})-[:REPLIED_TO]->(o) } as replied_to
+    "#;
+    let mut result = graph
+        .execute(
+            query(cypher)
+                .param("id", id.to_string())
+                .param("ancestry", ancestry.clone())
+                .param("message_id", own_id.clone()),
+        )
+        .await?;
+
+    // (ancestry position, id, thread) for ancestors; ids of direct replies
+    let mut ancestors: Vec<(usize, Uuid, Option<Uuid>)> = Vec::new();
+    let mut children = Vec::new();
+    let mut other_threads = Vec::new();
+    while let Some(row) = result.next().await? {
+        let Ok(other) = Uuid::parse_str(&row.get::<String>("id")?) else { continue };
+        let thread = row.get::<String>("thread_id").ok().and_then(|t| Uuid::parse_str(&t).ok());
+        let message_id: Option<String> = row.get("message_id").ok();
+        let position = message_id.as_ref().and_then(|m| ancestry.iter().position(|a| a == m));
+
+        if let Some(position) = position {
+            ancestors.push((position, other, thread));
+        } else if row.get::<bool>("replied_to").unwrap_or(false) {
+            // Explicit reply without a Message-ID to go by
+            ancestors.push((ancestry.len(), other, thread));
+        } else {
+            let in_reply_to: Option<String> = row.get("in_reply_to").ok();
+            let references: Vec<String> = row.get("references").unwrap_or_default();
+            let direct = match &in_reply_to {
+                Some(parent) => *parent == own_id,
+                None => references.last() == Some(&own_id),
+            };
+            if direct {
+                children.push(other);
+            }
+            other_threads.extend(thread);
+        }
+    }
+
+    // The nearest ancestor is the parent and decides the thread
+    ancestors.sort_by_key(|(position, _, _)| std::cmp::Reverse(*position));
+    let parent = ancestors.first().map(|(_, parent, _)| *parent);
+    let mut threads: Vec<Uuid> = ancestors.iter().filter_map(|(_, _, thread)| *thread).collect();
+    threads.extend(other_threads);
+    let mut seen = HashSet::new();
+    threads.retain(|t| seen.insert(*t));
+
+    let (subject_key, is_reply) = normalize_subject(&message.subject);
+    let target = match threads.first() {
+        Some(thread) => *thread,
+        None if is_reply && !subject_key.is_empty() => {
+            let cypher = r#"
+                MATCH (t:Thread {subject_key: $subject_key})
+                WHERE t.last_date >= $since
+                RETURN t.id as id
+                ORDER BY t.last_date DESC
+                LIMIT 1
+            "#;
+            let mut result = graph
+                .execute(
+                    query(cypher)
+                        .param("subject_key", subject_key.clone())
+                        .param("since", (message.date - subject_window()).to_rfc3339()),
+                )
+                .await?;
+            match result.next().await? {
+                Some(row) => Uuid::parse_str(&row.get::<String>("id")?)?,
+                None => Uuid::new_v4(),
+            }
+        }
+        None => Uuid::new_v4(),
+    };
+    merge_threads(graph, target, &threads[threads.len().min(1)..]).await?;
+
+    let cypher = r#"
+        MATCH (e:Email {id: $id})
+        OPTIONAL MATCH (e)-[r:IN_THREAD]->(:Thread)
+        DELETE r
+        WITH DISTINCT e
+        SET e.thread_id = $thread_id
+        MERGE (t:Thread {id: $thread_id})
+        ON CREATE SET t.subject = e.subject, t.subject_key = $subject_key
+        MERGE (e)-[:IN_THREAD]->(t)
+        WITH t
+        MATCH (t)<-[:IN_THREAD]-(x:Email)
+        WITH t, max(x.date) as last_date
+        SET t.last_date = last_date
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("id", id.to_string())
+                .param("thread_id", target.to_string())
+                .param("subject_key", subject_key),
+        )
+        .await?;
+
+    let cypher = r#"
+        MATCH (e:Email {id: $id})
+        OPTIONAL MATCH (p:Email {id: $parent})
+        FOREACH (_ IN CASE WHEN p IS NULL THEN [] ELSE [1] END | MERGE (e)-[:REPLIED_TO]->(p))
+        WITH e
+        UNWIND $children as child_id
+        MATCH (c:Email {id: child_id})
+        MERGE (c)-[:REPLIED_TO]->(e)
+    "#;
+    graph
+        .run(
+            query(cypher)
+                .param("id", id.to_string())
+                .param("parent", parent.map(|p| p.to_string()).unwrap_or_default())
+                .param("children", children.iter().map(Uuid::to_string).collect::<Vec<_>>()),
+        )
+        .await?;
+
+    Ok(target)
+}
+
+/// Whether replies to this thread should skip the inbox.
+pub async fn is_muted(graph: &Graph, thread_id: Uuid) -> Result<bool> {
+    let mut result = graph
+        .execute(
+            query("MATCH (t:Thread {id: $id}) RETURN coalesce(t.muted, false) as muted")
+                .param("id", thread_id.to_string()),
+        )
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get("muted").unwrap_or(false),
+        None => false,
+    })
+}
+
+/// Re-thread the whole mailbox, e.g. after importing mail threaded by older
+/// versions. Threads keep the id of their earliest email's thread where
+/// possible; threads corrected by hand are kept as they are. Returns the
+/// number of threads.
+pub async fn rebuild(graph: &Graph) -> Result<usize> {
+    let cypher = r#"
+        MATCH (e:Email)
+        WHERE NOT EXISTS { MATCH (e)-[:IN_THREAD]->(:Thread {manual: true}) }
+        RETURN e.id as id, e.message_id as message_id, e.in_reply_to as in_reply_to,
+               e.references as references, e.subject as subject, e.date as date, e.thread_id as thread_id
+    "#;
+    let mut result = graph.execute(query(cypher)).await?;
+    let mut messages = Vec::new();
+    let mut current: HashMap<Uuid, Uuid> = HashMap::new();
+    while let Some(row) = result.next().await? {
+        let Ok(id) = Uuid::parse_str(&row.get::<String>("id")?) else { continue };
+        if let Some(thread) = row.get::<String>("thread_id").ok().and_then(|t| Uuid::parse_str(&t).ok()) {
+            current.insert(id, thread);
+        }
+        messages.push(ThreadMessage {
+            id,
+            message_id: row.get("message_id").ok(),
+            in_reply_to: row.get("in_reply_to").ok(),
+            references: row.get("references").unwrap_or_default(),
+            subject: row.get("subject").unwrap_or_default(),
+            date: parse_date(row.get("date").ok()).unwrap_or_else(Utc::now),
+        });
+    }
+
+    let threading = thread(&messages);
+    let subjects: HashMap<Uuid, &str> = messages.iter().map(|m| (m.id, m.subject.as_str())).collect();
+    let mut used = HashSet::new();
+    let mut rows: Vec<HashMap<String, String>> = Vec::new();
+    for group in &threading.threads {
+        let thread_id = current
+            .get(&group[0])
+            .copied()
+            .filter(|t| used.insert(*t))
+            .unwrap_or_else(Uuid::new_v4);
+        let subject = subjects.get(&group[0]).copied().unwrap_or_default();
+        for id in group {
+            rows.push(HashMap::from([
+                ("id".to_string(), id.to_string()),
+                ("thread_id".to_string(), thread_id.to_string()),
+                ("subject".to_string(), subject.to_string()),
+                ("subject_key".to_string(), normalize_subject(subject).0),
+            ]));
+        }
+    }
+
+    let cypher = r#"
+        UNWIND $rows as row
+        MATCH (e:Email {id: row.id})
+        SET e.thread_id = row.thread_id
+        WITH e, row
+        OPTIONAL MATCH (e)-[r:IN_THREAD]->(:Thread)
+        DELETE r
+        WITH DISTINCT e, row
+        MERGE (t:Thread {id: row.thread_id})
+        SET t.subject = row.subject, t.subject_key = row.subject_key
+        MERGE (e)-[:IN_THREAD]->(t)
+    "#;
+    for chunk in rows.chunks(BATCH) {
+        graph.run(query(cypher).param("rows", chunk.to_vec())).await?;
+    }
+
+    let edges: Vec<HashMap<String, String>> = threading
+        .parents
+        .iter()
+        .map(|(child, parent)| {
+            HashMap::from([
+                ("child".to_string(), child.to_string()),
+                ("parent".to_string(), parent.to_string()),
+            ])
+        })
+        .collect();
+    let cypher = r#"
+        UNWIND $edges as edge
+        MATCH (c:Email {id: edge.child}), (p:Email {id: edge.parent})
+        MERGE (c)-[:REPLIED_TO]->(p)
+    "#;
+    for chunk in edges.chunks(BATCH) {
+        graph.run(query(cypher).param("edges", chunk.to_vec())).await?;
+    }
+
+    graph
+        .run(query("MATCH (t:Thread) WHERE NOT EXISTS { MATCH (t)<-[:IN_THREAD]-(:Email) } DETACH DELETE t"))
+        .await?;
+    graph
+        .run(query(
+            "MATCH (t:Thread)<-[:IN_THREAD]-(e:Email) WITH t, max(e.date) as last_date SET t.last_date = last_date",
+        ))
+        .await?;
+
+    Ok(threading.threads.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, refs: &[&str], subject: &str, minute: i64) -> ThreadMessage {
+        ThreadMessage {
+            id: Uuid::new_v4(),
+            message_id: Some(format!("<{}>", id)),
+            in_reply_to: refs.last().map(|r| format!("<{}>", r)),
+            references: refs.iter().map(|r| format!("<{}>", r)).collect(),
+            subject: subject.to_string(),
+            date: DateTime::<Utc>::UNIX_EPOCH + Duration::minutes(minute),
+        }
+    }
+
+    #[test]
+    fn normalizes_subjects() {
+        assert_eq!(normalize_subject("Re: RE[2]: [dev] Re:  Launch  plan"), ("launch plan".into(), true));
+        assert_eq!(normalize_subject("Launch plan"), ("launch plan".into(), false));
+        assert_eq!(normalize_subject("Fwd: Launch plan"), ("fwd: launch plan".into(), false));
+        assert_eq!(normalize_subject("Note: call me"), ("note: call me".into(), false));
+    }
+
+    #[test]
+    fn threads_by_references() {
+        let root = message("a", &[], "Plan", 0);
+        let reply = message("b", &["a"], "Re: Plan", 1);
+        let nested = message("c", &["a", "b"], "Re: Plan", 2);
+        // Parent missing, but its references lead back to the root
+        let orphan = message("e", &["a", "d"], "Re: Plan", 3);
+        let unrelated = message("x", &[], "Lunch", 4);
+
+        // Arrival order should not matter
+        let messages = vec![nested.clone(), orphan.clone(), unrelated.clone(), reply.clone(), root.clone()];
+        let threading = thread(&messages);
+
+        assert_eq!(threading.threads.len(), 2);
+        assert_eq!(threading.threads[0], vec![root.id, reply.id, nested.id, orphan.id]);
+        assert_eq!(threading.threads[1], vec![unrelated.id]);
+        assert_eq!(threading.parents[&reply.id], root.id);
+        assert_eq!(threading.parents[&nested.id], reply.id);
+        assert_eq!(threading.parents[&orphan.id], root.id);
+        assert!(!threading.parents.contains_key(&root.id));
+    }
+
+    #[test]
+    fn falls_back_to_subject_for_replies_only() {
+        let first = message("a", &[], "Weekly report", 0);
+        let second = message("b", &[], "Weekly report", 10);
+        // A reply from a client that dropped the headers
+        let reply = message("c", &[], "Re: weekly report", 11);
+
+        let threading = thread(&[first.clone(), second.clone(), reply.clone()]);
+        assert_eq!(threading.threads, vec![vec![first.id], vec![second.id, reply.id]]);
+    }
+
+    #[test]
+    fn ignores_reference_loops() {
+        let a = message("a", &["b"], "Loop", 0);
+        let b = message("b", &["a"], "Re: Loop", 1);
+        let threading = thread(&[a.clone(), b.clone()]);
+        assert_eq!(threading.threads.len(), 1);
+        assert_eq!(threading.parents.len(), 1);
+    }
+}
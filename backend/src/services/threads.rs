@@ -3,7 +3,100 @@ use chrono::Utc;
 use neo4rs::{query, Graph};
 use uuid::Uuid;
 
-use crate::models::{Contact, Email, EmailThread};
+use crate::models::{
+    Contact, EmailQuery, EmailThread, ThreadListResponse, ThreadSummary, UndoToken, UpdateThreadRequest,
+};
+use crate::services::emails::{email_conditions, email_from_row, filter_params, parse_date};
+use crate::services::{db::run_atomically, labels, threading, undo};
+
+/// Conversations with at least one email matching the query, most recently
+/// active first. Counts and labels cover the whole conversation.
+pub async fn list_threads(graph: &Graph, query_params: EmailQuery) -> Result<ThreadListResponse> {
+    let skip = (query_params.page.max(1) - 1) as i64 * query_params.limit as i64;
+    let limit = query_params.limit as i64;
+    let where_clause = email_conditions(&query_params);
+
+    // Page through threads newest first on the indexed `last_date`, and only
+    // then expand the messages of the threads on this page
+    let matching = format!("EXISTS {{ MATCH (e:Email {{thread_id: t.id}}) WHERE {} }}", where_clause);
+    let cypher = format!(
+        r#"
+        MATCH (t:Thread)
+        WHERE t.last_date IS NOT NULL AND {}
+        WITH t ORDER BY t.last_date DESC, t.id
+        SKIP $skip LIMIT $limit
+        MATCH (m:Email {{thread_id: t.id}})
+        OPTIONAL MATCH (m)-[:SENT_BY]->(from:Contact)
+        OPTIONAL MATCH (m)-[:SENT_TO|CC]->(to:Contact)
+        OPTIONAL MATCH (m)-[:HAS_LABEL]->(l:Label)
+        WITH t, m, from, collect(DISTINCT to) as recipients, collect(DISTINCT l.name) as labels,
+             EXISTS {{ MATCH (m)-[:HAS_ATTACHMENT]->(:Attachment) }} as has_attachment
+        ORDER BY m.date, m.id
+        WITH t, collect(m) as messages, collect(from) as senders, collect(recipients) as recipients,
+             collect(labels) as labels, collect(has_attachment) as attachment_flags
+        WITH t, messages, senders, recipients, labels, any(x IN attachment_flags WHERE x) as has_attachments,
+             messages[-1] as latest
+        ORDER BY t.last_date DESC, t.id
+        RETURN t.id as thread_id, messages[0].subject as subject, latest.snippet as snippet, latest.date as last_date,
+               size(messages) as message_count,
+               size([m IN messages WHERE NOT coalesce(m.is_read, false)]) as unread_count,
+               any(m IN messages WHERE m.is_starred) as is_starred,
+               senders, recipients, labels, has_attachments
+        "#,
+        matching
+    );
+
+    let mut result = graph
+        .execute(filter_params(query(&cypher), &query_params).param("skip", skip).param("limit", limit))
+        .await?;
+
+    let mut threads = Vec::new();
+    while let Some(row) = result.next().await? {
+        let senders: Vec<neo4rs::Node> = row.get("senders").unwrap_or_default();
+        let recipients: Vec<Vec<neo4rs::Node>> = row.get("recipients").unwrap_or_default();
+        let mut participants: Vec<Contact> = Vec::new();
+        for node in senders.into_iter().chain(recipients.into_iter().flatten()) {
+            let email: String = node.get("email").unwrap_or_default();
+            if !participants.iter().any(|p| p.email == email) {
+                participants.push(Contact { email, name: node.get("name").ok() });
+            }
+        }
+
+        let mut labels: Vec<String> = row.get::<Vec<Vec<String>>>("labels").unwrap_or_default().concat();
+        labels.sort();
+        labels.dedup();
+
+        threads.push(ThreadSummary {
+            id: Uuid::parse_str(&row.get::<String>("thread_id")?)?,
+            subject: row.get("subject").unwrap_or_default(),
+            participants,
+            message_count: row.get::<i64>("message_count").unwrap_or(0) as u64,
+            unread_count: row.get::<i64>("unread_count").unwrap_or(0) as u64,
+            snippet: row.get("snippet").unwrap_or_default(),
+            labels,
+            is_starred: row.get("is_starred").unwrap_or(false),
+            has_attachments: row.get("has_attachments").unwrap_or(false),
+            last_date: parse_date(row.get("last_date").ok()).unwrap_or_else(Utc::now),
+        });
+    }
+
+    let count_cypher = format!(
+        "MATCH (t:Thread) WHERE t.last_date IS NOT NULL AND {} RETURN count(t) as total",
+        matching
+    );
+    let mut count_result = graph.execute(filter_params(query(&count_cypher), &query_params)).await?;
+    let total = match count_result.next().await? {
+        Some(row) => row.get::<i64>("total").unwrap_or(0) as u64,
+        None => 0,
+    };
+
+    Ok(ThreadListResponse {
+        threads,
+        total,
+        page: query_params.page,
+        limit: query_params.limit,
+    })
+}
 
 pub async fn get_thread(graph: &Graph, id: Uuid) -> Result<EmailThread> {
     let cypher = r#"
@@ -11,9 +104,10 @@ pub async fn get_thread(graph: &Graph, id: Uuid) -> Result<EmailThread> {
         OPTIONAL MATCH (e)-[:SENT_BY]->(from:Contact)
         OPTIONAL MATCH (e)-[:SENT_TO]->(to:Contact)
         OPTIONAL MATCH (e)-[:HAS_LABEL]->(l:Label)
-        WITH e, from, collect(DISTINCT to) as tos, collect(DISTINCT l.name) as labels
+        OPTIONAL MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+        WITH e, from, collect(DISTINCT to) as tos, collect(DISTINCT l.name) as labels, collect(DISTINCT a) as attachments
         ORDER BY e.date ASC
-        RETURN e, from, tos, labels
+        RETURN e, from, tos, labels, attachments
     "#;
 
     let mut result = graph.execute(query(cypher).param("thread_id", id.to_string())).await?;
@@ -22,49 +116,14 @@ pub async fn get_thread(graph: &Graph, id: Uuid) -> Result<EmailThread> {
     let mut last_date = Utc::now();
 
     while let Some(row) = result.next().await? {
-        let e: neo4rs::Node = row.get("e")?;
-        let from_node: Option<neo4rs::Node> = row.get("from").ok();
-        let to_nodes: Vec<neo4rs::Node> = row.get("tos").unwrap_or_default();
-        let labels: Vec<String> = row.get("labels").unwrap_or_default();
-
-        let from = from_node.map(|n| Contact {
-            email: n.get("email").unwrap_or_default(),
-            name: n.get("name").ok(),
-        }).unwrap_or(Contact { email: "unknown@example.com".into(), name: None });
-
-        let to: Vec<Contact> = to_nodes.into_iter().map(|n| Contact {
-            email: n.get("email").unwrap_or_default(),
-            name: n.get("name").ok(),
-        }).collect();
-
-        let email_subject: String = e.get("subject").unwrap_or_default();
-        let email_date = e.get::<String>("date")
-            .map(|d| chrono::DateTime::parse_from_rfc3339(&d).ok())
-            .ok()
-            .flatten()
-            .map(|d| d.with_timezone(&Utc))
-            .unwrap_or_else(Utc::now);
+        let email = email_from_row(&row)?;
 
         if subject.is_empty() {
-            subject = email_subject.clone();
+            subject = email.subject.clone();
         }
-        last_date = email_date;
-
-        emails.push(Email {
-            id: Uuid::parse_str(&e.get::<String>("id")?).unwrap_or_default(),
-            subject: email_subject,
-            body: e.get("body").unwrap_or_default(),
-            snippet: e.get("snippet").unwrap_or_default(),
-            date: email_date,
-            is_read: e.get("is_read").unwrap_or(false),
-            is_starred: e.get("is_starred").unwrap_or(false),
-            thread_id: Some(id),
-            from,
-            to,
-            cc: vec![],
-            labels,
-            embedding: None,
-        });
+        last_date = email.date;
+
+        emails.push(email);
     }
 
     if emails.is_empty() {
@@ -84,5 +143,365 @@ pub async fn get_thread(graph: &Graph, id: Uuid) -> Result<EmailThread> {
         subject,
         last_message_date: last_date,
         participant_count,
+        muted: threading::is_muted(graph, id).await?,
+    })
+}
+
+async fn thread_exists(graph: &Graph, id: Uuid) -> Result<bool> {
+    let mut result = graph
+        .execute(query("MATCH (e:Email {thread_id: $id}) RETURN count(e) > 0 as exists").param("id", id.to_string()))
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get("exists").unwrap_or(false),
+        None => false,
     })
 }
+
+/// Label edits of a thread update, with the archive, mute and trash shortcuts
+/// folded in. With `replace`, existing labels are dropped before `add`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct LabelChanges {
+    replace: bool,
+    add: Vec<String>,
+    remove: Vec<String>,
+}
+
+impl LabelChanges {
+    fn from_request(req: &UpdateThreadRequest) -> Self {
+        let mut add = req.labels.clone().unwrap_or_default();
+        add.extend(req.add_labels.iter().cloned());
+        let mut remove = req.remove_labels.clone();
+        // Trashed emails keep INBOX so taking them out of TRASH restores them
+        if req.archive || req.muted == Some(true) {
+            remove.push("INBOX".into());
+        }
+        if req.trash {
+            add.push("TRASH".into());
+        }
+        LabelChanges { replace: req.labels.is_some(), add, remove }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.replace && self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// Apply read/starred/label changes to every email of a thread at once.
+pub async fn update_thread(graph: &Graph, id: Uuid, req: UpdateThreadRequest) -> Result<EmailThread> {
+    if !thread_exists(graph, id).await? {
+        return Err(anyhow!("Thread not found"));
+    }
+
+    let thread_id = id.to_string();
+    let mut queries = Vec::new();
+
+    if let Some(is_read) = req.is_read {
+        queries.push(
+            query("MATCH (e:Email {thread_id: $id}) SET e.is_read = $is_read")
+                .param("id", thread_id.clone())
+                .param("is_read", is_read),
+        );
+    }
+    if let Some(is_starred) = req.is_starred {
+        queries.push(
+            query("MATCH (e:Email {thread_id: $id}) SET e.is_starred = $is_starred")
+                .param("id", thread_id.clone())
+                .param("is_starred", is_starred),
+        );
+    }
+
+    if let Some(muted) = req.muted {
+        queries.push(
+            query("MERGE (t:Thread {id: $id}) SET t.muted = $muted")
+                .param("id", thread_id.clone())
+                .param("muted", muted),
+        );
+    }
+
+    let changes = LabelChanges::from_request(&req);
+    let labels_changed = !changes.is_empty();
+    let LabelChanges { replace, add, remove } = changes;
+    if replace {
+        queries.push(
+            query("MATCH (e:Email {thread_id: $id})-[r:HAS_LABEL]->(:Label) DELETE r").param("id", thread_id.clone()),
+        );
+    }
+    if !remove.is_empty() {
+        queries.push(
+            query(
+                r#"
+                MATCH (e:Email {thread_id: $id})-[r:HAS_LABEL]->(l:Label)
+                WHERE l.name IN $labels
+                DELETE r
+                "#,
+            )
+            .param("id", thread_id.clone())
+            .param("labels", remove),
+        );
+    }
+    if !add.is_empty() {
+        queries.push(
+            query(
+                r#"
+                MATCH (e:Email {thread_id: $id})
+                UNWIND $labels as name
+                MERGE (l:Label {name: name})
+                ON CREATE SET l.color = '#9e9e9e', l.ai_excluded = false
+                MERGE (e)-[:HAS_LABEL]->(l)
+                "#,
+            )
+            .param("id", thread_id.clone())
+            .param("labels", add.clone()),
+        );
+        queries.push(labels::hierarchy_query(&add));
+    }
+
+    let thread_emails = "MATCH (e:Email {thread_id: $id})";
+    if labels_changed {
+        queries.push(labels::trashed_at_query(thread_emails).param("id", thread_id.clone()));
+    }
+    // An explicit is_starred wins over STARRED in the label changes
+    if req.is_starred.is_some() {
+        queries.push(labels::starred_label_query(thread_emails).param("id", thread_id.clone()));
+    } else if labels_changed {
+        queries.push(labels::starred_flag_query(thread_emails).param("id", thread_id.clone()));
+    }
+
+    run_atomically(graph, queries).await?;
+    get_thread(graph, id).await
+}
+
+/// Move a thread to TRASH. A thread already entirely in TRASH is deleted,
+/// which can be undone with the returned token.
+pub async fn delete_thread(graph: &Graph, id: Uuid) -> Result<Option<UndoToken>> {
+    let thread_id = id.to_string();
+    let token = undo::new_token();
+    // Each statement checks the TRASH state itself, so exactly one applies.
+    // Deleting runs first so emails trashed here are not deleted as well.
+    let fully_trashed = r#"
+        MATCH (e:Email {thread_id: $id})
+        WITH collect(e) as emails
+        WHERE all(x IN emails WHERE EXISTS { MATCH (x)-[:HAS_LABEL]->(:Label {name: 'TRASH'}) })
+        UNWIND emails as e
+    "#;
+    let trash = r#"
+        MATCH (e:Email {thread_id: $id})
+        WITH collect(e) as emails
+        WHERE any(x IN emails WHERE NOT EXISTS { MATCH (x)-[:HAS_LABEL]->(:Label {name: 'TRASH'}) })
+        MATCH (t:Label {name: 'TRASH'})
+        UNWIND emails as e
+        MERGE (e)-[:HAS_LABEL]->(t)
+        SET e.trashed_at = coalesce(e.trashed_at, $now)
+    "#;
+    run_atomically(
+        graph,
+        vec![
+            undo::tombstone_emails_query(fully_trashed, &token).param("id", thread_id.clone()),
+            query(trash).param("id", thread_id).param("now", Utc::now().to_rfc3339()),
+        ],
+    )
+    .await?;
+
+    if undo::token_used(graph, token.undo_token).await? {
+        Ok(Some(token))
+    } else if thread_exists(graph, id).await? {
+        Ok(None)
+    } else {
+        Err(anyhow!("Thread not found"))
+    }
+}
+
+/// Point emails at `target`, marking the threads involved as manually
+/// corrected so automatic threading does not merge them back together.
+fn move_query(target: Uuid) -> neo4rs::Query {
+    query(
+        r#"
+        MATCH (e:Email)
+        WHERE e.id IN $email_ids
+        OPTIONAL MATCH (e)-[r:IN_THREAD]->(old:Thread)
+        SET old.manual = true
+        DELETE r
+        WITH DISTINCT e
+        SET e.thread_id = $target
+        MERGE (t:Thread {id: $target})
+        ON CREATE SET t.subject = e.subject
+        SET t.manual = true
+        MERGE (e)-[:IN_THREAD]->(t)
+        "#,
+    )
+    .param("target", target.to_string())
+}
+
+/// Refresh `last_date` of the given threads and drop those left empty.
+fn refresh_query(threads: &[Uuid]) -> neo4rs::Query {
+    query(
+        r#"
+        MATCH (t:Thread)
+        WHERE t.id IN $ids
+        OPTIONAL MATCH (t)<-[:IN_THREAD]-(e:Email)
+        WITH t, max(e.date) as last_date, count(e) as emails
+        SET t.last_date = last_date
+        FOREACH (_ IN CASE WHEN emails = 0 THEN [1] ELSE [] END | DETACH DELETE t)
+        "#,
+    )
+    .param("ids", threads.iter().map(Uuid::to_string).collect::<Vec<_>>())
+}
+
+async fn email_thread(graph: &Graph, email_id: Uuid) -> Result<Option<Uuid>> {
+    let mut result = graph
+        .execute(query("MATCH (e:Email {id: $id}) RETURN e.thread_id as thread_id").param("id", email_id.to_string()))
+        .await?;
+    let row = result.next().await?.ok_or_else(|| anyhow!("Email not found"))?;
+    Ok(row.get::<String>("thread_id").ok().and_then(|t| Uuid::parse_str(&t).ok()))
+}
+
+/// Move one email into another thread.
+pub async fn move_email(graph: &Graph, thread_id: Uuid, email_id: Uuid) -> Result<EmailThread> {
+    let current = email_thread(graph, email_id).await?;
+    if !thread_exists(graph, thread_id).await? {
+        return Err(anyhow!("Thread not found"));
+    }
+    if current == Some(thread_id) {
+        return get_thread(graph, thread_id).await;
+    }
+
+    let mut touched = vec![thread_id];
+    touched.extend(current);
+    run_atomically(
+        graph,
+        vec![
+            move_query(thread_id).param("email_ids", vec![email_id.to_string()]),
+            refresh_query(&touched),
+        ],
+    )
+    .await?;
+    get_thread(graph, thread_id).await
+}
+
+/// Ids from `email_id` onwards, given a thread's message ids ordered by date.
+fn split_point(ids: &[String], email_id: &str) -> Result<Vec<String>> {
+    match ids.iter().position(|id| id == email_id) {
+        None => Err(anyhow!("Email is not in this thread")),
+        Some(0) => Err(anyhow!("Cannot split a thread at its first message")),
+        Some(start) => Ok(ids[start..].to_vec()),
+    }
+}
+
+/// Start a new thread at `email_id`, taking it and every later message along.
+pub async fn split_thread(graph: &Graph, thread_id: Uuid, email_id: Uuid) -> Result<EmailThread> {
+    if email_thread(graph, email_id).await? != Some(thread_id) {
+        return Err(anyhow!("Email is not in this thread"));
+    }
+
+    let cypher = r#"
+        MATCH (e:Email {thread_id: $thread_id})
+        RETURN e.id as id
+        ORDER BY e.date, e.id
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("thread_id", thread_id.to_string()))
+        .await?;
+    let mut ids = Vec::new();
+    while let Some(row) = result.next().await? {
+        ids.push(row.get::<String>("id")?);
+    }
+    let moving = split_point(&ids, &email_id.to_string())?;
+
+    let new_thread = Uuid::new_v4();
+    run_atomically(
+        graph,
+        vec![
+            move_query(new_thread).param("email_ids", moving),
+            refresh_query(&[thread_id, new_thread]),
+        ],
+    )
+    .await?;
+    get_thread(graph, new_thread).await
+}
+
+/// Move every email of `source` into `target`.
+pub async fn merge_threads(graph: &Graph, target: Uuid, source: Uuid) -> Result<EmailThread> {
+    if target == source {
+        return Err(anyhow!("Cannot merge a thread with itself"));
+    }
+    if !thread_exists(graph, target).await? || !thread_exists(graph, source).await? {
+        return Err(anyhow!("Thread not found"));
+    }
+
+    let cypher = "MATCH (e:Email {thread_id: $id}) RETURN collect(e.id) as ids";
+    let mut result = graph.execute(query(cypher).param("id", source.to_string())).await?;
+    let ids: Vec<String> = match result.next().await? {
+        Some(row) => row.get("ids").unwrap_or_default(),
+        None => Vec::new(),
+    };
+
+    run_atomically(
+        graph,
+        vec![
+            move_query(target).param("email_ids", ids),
+            refresh_query(&[target, source]),
+        ],
+    )
+    .await?;
+    get_thread(graph, target).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> UpdateThreadRequest {
+        UpdateThreadRequest {
+            is_read: None,
+            is_starred: None,
+            labels: None,
+            add_labels: vec![],
+            remove_labels: vec![],
+            archive: false,
+            trash: false,
+            muted: None,
+        }
+    }
+
+    #[test]
+    fn folds_shortcuts_into_label_changes() {
+        assert!(LabelChanges::from_request(&request()).is_empty());
+        assert!(LabelChanges::from_request(&UpdateThreadRequest { muted: Some(false), ..request() }).is_empty());
+
+        let changes = LabelChanges::from_request(&UpdateThreadRequest {
+            add_labels: vec!["Work".into()],
+            remove_labels: vec!["Later".into()],
+            archive: true,
+            trash: true,
+            ..request()
+        });
+        assert_eq!(
+            changes,
+            LabelChanges {
+                replace: false,
+                add: vec!["Work".into(), "TRASH".into()],
+                remove: vec!["Later".into(), "INBOX".into()],
+            }
+        );
+
+        let changes = LabelChanges::from_request(&UpdateThreadRequest { muted: Some(true), ..request() });
+        assert_eq!(changes.remove, vec!["INBOX"]);
+
+        let changes = LabelChanges::from_request(&UpdateThreadRequest {
+            labels: Some(vec![]),
+            add_labels: vec!["Work".into()],
+            ..request()
+        });
+        assert!(changes.replace && !changes.is_empty());
+        assert_eq!(changes.add, vec!["Work"]);
+    }
+
+    #[test]
+    fn splits_from_the_chosen_message_onwards() {
+        let ids: Vec<String> = ["a", "b", "c"].iter().map(|id| id.to_string()).collect();
+        assert_eq!(split_point(&ids, "b").unwrap(), vec!["b", "c"]);
+        assert_eq!(split_point(&ids, "c").unwrap(), vec!["c"]);
+        assert_eq!(split_point(&ids, "a").unwrap_err().to_string(), "Cannot split a thread at its first message");
+        assert_eq!(split_point(&ids, "z").unwrap_err().to_string(), "Email is not in this thread");
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use chrono::{Duration as ChronoDuration, Utc};
+use neo4rs::{query, Graph};
+use uuid::Uuid;
+
+use crate::services::{emails, poller};
+
+/// Emails purged per polling round.
+const BATCH: i64 = 100;
+
+fn retention_days() -> i64 {
+    std::env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30)
+}
+
+/// Permanently delete emails that have been in TRASH longer than the
+/// retention period. Returns the number purged.
+pub async fn purge_expired(graph: &Graph) -> Result<usize> {
+    let now = Utc::now();
+    // Emails labelled TRASH by other means start their retention now
+    graph
+        .run(
+            query(
+                r#"
+                MATCH (e:Email)-[:HAS_LABEL]->(:Label {name: 'TRASH'})
+                WHERE e.trashed_at IS NULL
+                SET e.trashed_at = $now
+                "#,
+            )
+            .param("now", now.to_rfc3339()),
+        )
+        .await?;
+
+    let cutoff = now - ChronoDuration::days(retention_days());
+    let cypher = r#"
+        MATCH (e:Email)-[:HAS_LABEL]->(:Label {name: 'TRASH'})
+        WHERE e.trashed_at < $cutoff
+        RETURN e.id as id
+        LIMIT $limit
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("cutoff", cutoff.to_rfc3339()).param("limit", BATCH))
+        .await?;
+    let mut expired = Vec::new();
+    while let Some(row) = result.next().await? {
+        if let Ok(id) = Uuid::parse_str(&row.get::<String>("id")?) {
+            expired.push(id);
+        }
+    }
+
+    let count = expired.len();
+    for id in expired {
+        let email = emails::get_email(graph, id).await?;
+        emails::purge_email(graph, &email).await?;
+    }
+    Ok(count)
+}
+
+/// Empty expired mail from TRASH in the background, checking every
+/// `TRASH_PURGE_POLL_SECS` (default 3600).
+pub fn spawn_worker(graph: Graph) {
+    poller::spawn_poller("Trash purge", "TRASH_PURGE_POLL_SECS", 3600, Some(BATCH as usize), move || {
+        let graph = graph.clone();
+        async move { purge_expired(&graph).await }
+    });
+}
@@ -0,0 +1,355 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use neo4rs::{query, Graph};
+use uuid::Uuid;
+
+use crate::models::{RestoreSummary, UndoToken};
+use crate::services::{attachments, db::run_atomically, labels, poller, threading};
+
+/// Soft-deleted nodes swap their label for a tombstone label, which keeps them
+/// out of every query while their relationships stay in place for restore.
+const TOMBSTONES: [(&str, &str); 3] = [
+    ("Email", "DeletedEmail"),
+    ("Thread", "DeletedThread"),
+    ("Label", "DeletedLabel"),
+];
+
+/// Tombstoned emails purged per polling round.
+const BATCH: i64 = 100;
+
+fn window() -> ChronoDuration {
+    ChronoDuration::seconds(
+        std::env::var("UNDO_WINDOW_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30),
+    )
+}
+
+/// A fresh token for one destructive action, valid for `UNDO_WINDOW_SECS`
+/// (default 30).
+pub(crate) fn new_token() -> UndoToken {
+    UndoToken {
+        undo_token: Uuid::new_v4(),
+        expires_at: Utc::now() + window(),
+    }
+}
+
+fn stamped(cypher: String, token: &UndoToken) -> neo4rs::Query {
+    query(&cypher)
+        .param("token", token.undo_token.to_string())
+        .param("expires_at", token.expires_at.to_rfc3339())
+        .param("now", Utc::now().to_rfc3339())
+}
+
+/// Soft-delete the emails bound to `e` by `match_clause`, along with threads
+/// they leave without emails. The caller adds the clause's params.
+pub(crate) fn tombstone_emails_query(match_clause: &str, token: &UndoToken) -> neo4rs::Query {
+    stamped(
+        format!(
+            r#"
+            {}
+            OPTIONAL MATCH (e)-[:IN_THREAD]->(t:Thread)
+            REMOVE e:Email
+            SET e:DeletedEmail, e.deleted_at = $now, e.undo_token = $token, e.undo_expires_at = $expires_at
+            WITH collect(DISTINCT t) as threads
+            UNWIND threads as t
+            OPTIONAL MATCH (t)<-[:IN_THREAD]-(x:Email)
+            WITH t, max(x.date) as last_date
+            SET t.last_date = coalesce(last_date, t.last_date)
+            WITH t
+            WHERE last_date IS NULL
+            REMOVE t:Thread
+            SET t:DeletedThread, t.deleted_at = $now, t.undo_token = $token, t.undo_expires_at = $expires_at
+            "#,
+            match_clause
+        ),
+        token,
+    )
+}
+
+/// Soft-delete the labels bound to `l` by `match_clause`.
+pub(crate) fn tombstone_labels_query(match_clause: &str, token: &UndoToken) -> neo4rs::Query {
+    stamped(
+        format!(
+            r#"
+            {}
+            REMOVE l:Label
+            SET l:DeletedLabel, l.deleted_at = $now, l.undo_token = $token, l.undo_expires_at = $expires_at
+            "#,
+            match_clause
+        ),
+        token,
+    )
+}
+
+/// Whether a token whose tombstones carry `expires_at` can still be used at
+/// `now`; `None` means nothing was deleted under it.
+fn check_expiry(expires_at: Option<&str>, now: DateTime<Utc>) -> Result<()> {
+    let Some(expires_at) = expires_at else {
+        return Err(anyhow!("Undo token not found"));
+    };
+    if DateTime::parse_from_rfc3339(expires_at)? < now {
+        return Err(anyhow!("Undo token has expired"));
+    }
+    Ok(())
+}
+
+/// Whether anything was deleted under `token`.
+pub(crate) async fn token_used(graph: &Graph, token: Uuid) -> Result<bool> {
+    let cypher = r#"
+        RETURN EXISTS { MATCH (n:DeletedEmail {undo_token: $token}) }
+            OR EXISTS { MATCH (n:DeletedThread {undo_token: $token}) }
+            OR EXISTS { MATCH (n:DeletedLabel {undo_token: $token}) } as used
+    "#;
+    let mut result = graph.execute(query(cypher).param("token", token.to_string())).await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get("used").unwrap_or(false),
+        None => false,
+    })
+}
+
+/// Bring back everything deleted under `token` with the relationships it had.
+pub async fn restore(graph: &Graph, token: Uuid) -> Result<RestoreSummary> {
+    let cypher = r#"
+        CALL {
+            MATCH (n:DeletedEmail {undo_token: $token}) RETURN 'email' as kind, n
+            UNION ALL
+            MATCH (n:DeletedThread {undo_token: $token}) RETURN 'thread' as kind, n
+            UNION ALL
+            MATCH (n:DeletedLabel {undo_token: $token}) RETURN 'label' as kind, n
+        }
+        RETURN kind, n.id as id, n.name as name, n.undo_expires_at as expires_at
+    "#;
+    let mut result = graph.execute(query(cypher).param("token", token.to_string())).await?;
+    let mut summary = RestoreSummary::default();
+    let mut email_ids = Vec::new();
+    let mut label_names = Vec::new();
+    let mut expires_at = None;
+    while let Some(row) = result.next().await? {
+        match row.get::<String>("kind")?.as_str() {
+            "email" => {
+                summary.emails += 1;
+                email_ids.push(row.get::<String>("id")?);
+            }
+            "thread" => summary.threads += 1,
+            _ => {
+                summary.labels += 1;
+                label_names.push(row.get::<String>("name")?);
+            }
+        }
+        expires_at = row.get::<String>("expires_at").ok();
+    }
+
+    check_expiry(expires_at.as_deref(), Utc::now())?;
+    if !label_names.is_empty() {
+        let mut result = graph
+            .execute(
+                query("MATCH (l:Label) WHERE l.name IN $names RETURN count(l) > 0 as taken")
+                    .param("names", label_names.clone()),
+            )
+            .await?;
+        if let Some(row) = result.next().await? {
+            if row.get("taken").unwrap_or(false) {
+                return Err(anyhow!("Label already exists"));
+            }
+        }
+    }
+
+    let mut queries: Vec<neo4rs::Query> = TOMBSTONES
+        .iter()
+        .map(|(live, dead)| {
+            query(&format!(
+                "MATCH (n:{0} {{undo_token: $token}}) REMOVE n:{0}, n.deleted_at, n.undo_token, n.undo_expires_at SET n:{1}",
+                dead, live
+            ))
+            .param("token", token.to_string())
+        })
+        .collect();
+    if !label_names.is_empty() {
+        // Parents may have been deleted separately in the meantime
+        queries.push(labels::hierarchy_query(&label_names));
+    }
+    if !email_ids.is_empty() {
+        queries.push(
+            query(
+                r#"
+                MATCH (e:Email)-[r:IN_THREAD]->(t)
+                WHERE e.id IN $ids AND NOT t:Thread
+                DELETE r
+                "#,
+            )
+            .param("ids", email_ids.clone()),
+        );
+        queries.push(
+            query(
+                r#"
+                MATCH (e:Email)-[:IN_THREAD]->(t:Thread)
+                WHERE e.id IN $ids
+                WITH DISTINCT t
+                MATCH (t)<-[:IN_THREAD]-(x:Email)
+                WITH t, max(x.date) as last_date
+                SET t.last_date = last_date
+                "#,
+            )
+            .param("ids", email_ids.clone()),
+        );
+    }
+    run_atomically(graph, queries).await?;
+
+    // Emails whose thread is gone for good are threaded afresh
+    let cypher = r#"
+        MATCH (e:Email)
+        WHERE e.id IN $ids AND NOT EXISTS { MATCH (e)-[:IN_THREAD]->(:Thread) }
+        RETURN e.id as id
+    "#;
+    let mut result = graph.execute(query(cypher).param("ids", email_ids)).await?;
+    let mut unthreaded = Vec::new();
+    while let Some(row) = result.next().await? {
+        if let Ok(id) = Uuid::parse_str(&row.get::<String>("id")?) {
+            unthreaded.push(id);
+        }
+    }
+    for id in unthreaded {
+        threading::attach(graph, id).await?;
+    }
+
+    Ok(summary)
+}
+
+/// Permanently delete tombstones whose undo window has passed. Returns the
+/// number of emails purged.
+pub async fn purge_expired(graph: &Graph) -> Result<usize> {
+    let now = Utc::now().to_rfc3339();
+    let cypher = r#"
+        MATCH (e:DeletedEmail)
+        WHERE e.undo_expires_at < $now
+        WITH e LIMIT $limit
+        OPTIONAL MATCH (e)-[:HAS_ATTACHMENT]->(a:Attachment)
+        WITH e, collect(a.id) as attachments
+        DETACH DELETE e
+        RETURN attachments
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("now", now.clone()).param("limit", BATCH))
+        .await?;
+    let mut count = 0;
+    let mut attachment_ids = Vec::new();
+    while let Some(row) = result.next().await? {
+        count += 1;
+        for id in row.get::<Vec<String>>("attachments").unwrap_or_default() {
+            if let Ok(id) = Uuid::parse_str(&id) {
+                attachment_ids.push(id);
+            }
+        }
+    }
+    attachments::delete_orphans(graph, &attachment_ids).await?;
+
+    for dead in ["DeletedThread", "DeletedLabel"] {
+        let cypher = format!("MATCH (n:{}) WHERE n.undo_expires_at < $now DETACH DELETE n", dead);
+        graph.run(query(&cypher).param("now", now.clone())).await?;
+    }
+    Ok(count)
+}
+
+/// Purge expired tombstones in the background, checking every
+/// `UNDO_PURGE_POLL_SECS` (default 60).
+pub fn spawn_worker(graph: Graph) {
+    poller::spawn_poller("Undo purge", "UNDO_PURGE_POLL_SECS", 60, Some(BATCH as usize), move || {
+        let graph = graph.clone();
+        async move { purge_expired(&graph).await }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{contacts, db};
+
+    #[tokio::test]
+    async fn restores_emails_whose_contacts_were_merged_meanwhile() {
+        let Some(graph) = db::test_graph().await else { return };
+        let run = Uuid::new_v4().simple().to_string();
+        let (source, target, to) = (
+            format!("old-{}@example.com", run),
+            format!("new-{}@example.com", run),
+            format!("to-{}@example.com", run),
+        );
+        let email_id = Uuid::new_v4();
+        graph
+            .run(
+                query(
+                    r#"
+                    CREATE (e:Email {id: $id, subject: 'Hi', body: '', date: $now, message_id: $message_id})
+                    CREATE (e)-[:SENT_BY]->(:Contact {email: $source})
+                    CREATE (e)-[:SENT_TO]->(:Contact {email: $to})
+                    CREATE (:Contact {email: $target})
+                    "#,
+                )
+                .param("id", email_id.to_string())
+                .param("now", Utc::now().to_rfc3339())
+                .param("message_id", format!("<{}@example.com>", run))
+                .param("source", source.clone())
+                .param("target", target.clone())
+                .param("to", to.clone()),
+            )
+            .await
+            .unwrap();
+
+        let token = new_token();
+        graph
+            .run(tombstone_emails_query("MATCH (e:Email {id: $id})", &token).param("id", email_id.to_string()))
+            .await
+            .unwrap();
+        contacts::merge_contacts(&graph, &target, &source).await.unwrap();
+        let summary = restore(&graph, token.undo_token).await.unwrap();
+        assert_eq!(summary.emails, 1);
+
+        let mut result = graph
+            .execute(
+                query(
+                    r#"
+                    MATCH (e:Email {id: $id})-[:SENT_BY]->(from:Contact), (e)-[:SENT_TO]->(to:Contact)
+                    RETURN from.email as from, to.email as to
+                    "#,
+                )
+                .param("id", email_id.to_string()),
+            )
+            .await
+            .unwrap();
+        let row = result.next().await.unwrap().expect("restored email keeps its contacts");
+        assert_eq!(row.get::<String>("from").unwrap(), target);
+        assert_eq!(row.get::<String>("to").unwrap(), to);
+
+        graph
+            .run(
+                query(
+                    r#"
+                    MATCH (e:Email {id: $id})
+                    OPTIONAL MATCH (e)-[:IN_THREAD]->(t:Thread)
+                    DETACH DELETE e, t
+                    WITH 1 as done
+                    MATCH (c:Contact) WHERE c.email IN $contacts
+                    DETACH DELETE c
+                    "#,
+                )
+                .param("id", email_id.to_string())
+                .param("contacts", vec![target, to]),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn tokens_expire_after_their_window() {
+        let now = Utc::now();
+        let expires_at = (now + ChronoDuration::seconds(30)).to_rfc3339();
+        assert!(check_expiry(Some(&expires_at), now).is_ok());
+        assert!(check_expiry(Some(&expires_at), now + ChronoDuration::seconds(29)).is_ok());
+        assert_eq!(
+            check_expiry(Some(&expires_at), now + ChronoDuration::seconds(31)).unwrap_err().to_string(),
+            "Undo token has expired"
+        );
+        assert_eq!(check_expiry(None, now).unwrap_err().to_string(), "Undo token not found");
+    }
+}
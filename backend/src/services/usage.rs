@@ -0,0 +1,391 @@
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use neo4rs::{query, Graph};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Token counts reported by the provider for a single call.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+    #[error("Daily AI token quota exceeded for {scope}: {used} of {limit} tokens used")]
+    Tokens { scope: String, used: u64, limit: u64 },
+    #[error("Daily AI cost quota exceeded: ${used:.4} of ${limit:.4} used")]
+    Cost { used: f64, limit: f64 },
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageEntry {
+    pub day: String,
+    pub feature: String,
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FeatureTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaSettings {
+    pub daily_tokens: Option<u64>,
+    pub daily_cost_usd: Option<f64>,
+    pub per_feature_daily_tokens: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub user: String,
+    pub days: u32,
+    pub totals: BTreeMap<String, FeatureTotals>,
+    pub daily: Vec<UsageEntry>,
+    pub quota: QuotaSettings,
+}
+
+pub const FEATURES: &[&str] = &["summarize", "compose", "categorize", "search", "index"];
+
+/// USD per million (input, output) tokens. Override or extend with
+/// `AI_MODEL_PRICES`, e.g. `gpt-4o-mini=0.15:0.6,my-model=1:2`.
+fn model_price(model: &str) -> (f64, f64) {
+    if let Ok(prices) = std::env::var("AI_MODEL_PRICES") {
+        for entry in prices.split(',') {
+            let Some((name, price)) = entry.trim().split_once('=') else { continue };
+            if name.trim() != model {
+                continue;
+            }
+            let (input, output) = price.split_once(':').unwrap_or((price, "0"));
+            if let (Ok(input), Ok(output)) = (input.trim().parse(), output.trim().parse()) {
+                return (input, output);
+            }
+            tracing::warn!("Invalid AI_MODEL_PRICES entry: {}", entry);
+        }
+    }
+
+    match model {
+        "gpt-4o-mini" => (0.15, 0.60),
+        "gpt-4o" => (2.50, 10.00),
+        "text-embedding-3-small" => (0.02, 0.0),
+        "text-embedding-3-large" => (0.13, 0.0),
+        _ => (0.0, 0.0),
+    }
+}
+
+pub fn cost_usd(model: &str, usage: Usage) -> f64 {
+    let (input, output) = model_price(model);
+    (usage.prompt_tokens as f64 * input + usage.completion_tokens as f64 * output) / 1_000_000.0
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+fn quota_settings() -> QuotaSettings {
+    QuotaSettings {
+        daily_tokens: env_u64("AI_DAILY_TOKEN_QUOTA"),
+        daily_cost_usd: std::env::var("AI_DAILY_COST_QUOTA_USD").ok().and_then(|v| v.parse().ok()),
+        per_feature_daily_tokens: FEATURES
+            .iter()
+            .filter_map(|f| {
+                env_u64(&format!("AI_DAILY_TOKEN_QUOTA_{}", f.to_uppercase())).map(|q| (f.to_string(), q))
+            })
+            .collect(),
+    }
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+/// Tokens held against the quota for each call in flight, until its real
+/// usage is recorded. Override with `AI_QUOTA_RESERVE_TOKENS`.
+fn reserve_tokens() -> u64 {
+    env_u64("AI_QUOTA_RESERVE_TOKENS").unwrap_or(1000)
+}
+
+/// Quota held for one provider call. Pass it to [`record`] once the call
+/// returns, or to [`release`] if it fails.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    user: String,
+    feature: String,
+    day: String,
+    tokens: u64,
+    cost_usd: f64,
+}
+
+/// What a user has used today plus what calls in flight hold.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct DayTotals {
+    tokens: u64,
+    feature_tokens: u64,
+    cost_usd: f64,
+}
+
+/// The first limit already reached by `totals`, if any.
+fn exceeded(quota: &QuotaSettings, feature: &str, totals: DayTotals) -> Option<QuotaError> {
+    if let Some(&limit) = quota.per_feature_daily_tokens.get(feature) {
+        if totals.feature_tokens >= limit {
+            return Some(QuotaError::Tokens { scope: feature.to_string(), used: totals.feature_tokens, limit });
+        }
+    }
+    if let Some(limit) = quota.daily_tokens {
+        if totals.tokens >= limit {
+            return Some(QuotaError::Tokens { scope: "all features".into(), used: totals.tokens, limit });
+        }
+    }
+    if let Some(limit) = quota.daily_cost_usd {
+        if totals.cost_usd >= limit {
+            return Some(QuotaError::Cost { used: totals.cost_usd, limit });
+        }
+    }
+    None
+}
+
+/// Hold quota for a call to `model`, or reject it if the user has exhausted
+/// today's quota for this feature. The hold is taken in the same statement
+/// that reads the totals, so concurrent calls see each other's holds.
+pub async fn reserve(graph: &Graph, user: &str, feature: &str, model: &str) -> Result<Reservation> {
+    if !FEATURES.contains(&feature) {
+        return Err(anyhow!("Unknown AI feature: {}", feature));
+    }
+    let quota = quota_settings();
+    let limited = quota.daily_tokens.is_some()
+        || quota.daily_cost_usd.is_some()
+        || quota.per_feature_daily_tokens.contains_key(feature);
+    let tokens = if limited { reserve_tokens() } else { 0 };
+    let reservation = Reservation {
+        user: user.to_string(),
+        feature: feature.to_string(),
+        day: today(),
+        tokens,
+        cost_usd: cost_usd(model, Usage { prompt_tokens: tokens as u32, completion_tokens: 0 }),
+    };
+    if !limited {
+        return Ok(reservation);
+    }
+
+    let cypher = format!(
+        r#"
+        MERGE (q:AiQuota {{user: $user, day: $day}})
+        ON CREATE SET q.reserved_tokens = 0, q.reserved_cost_usd = 0.0
+        SET q.reserved_tokens = q.reserved_tokens + $tokens,
+            q.reserved_cost_usd = q.reserved_cost_usd + $cost,
+            q.`reserved_{0}` = coalesce(q.`reserved_{0}`, 0) + $tokens
+        WITH q
+        OPTIONAL MATCH (u:AiUsage {{user: $user, day: $day}})
+        WITH q,
+             sum(u.prompt_tokens + u.completion_tokens) as tokens,
+             sum(CASE WHEN u.feature = $feature THEN u.prompt_tokens + u.completion_tokens ELSE 0 END) as feature_tokens,
+             sum(u.cost_usd) as cost
+        RETURN tokens + q.reserved_tokens - $tokens as tokens,
+               feature_tokens + q.`reserved_{0}` - $tokens as feature_tokens,
+               cost + q.reserved_cost_usd - $cost as cost
+        "#,
+        feature
+    );
+    let mut result = graph.execute(reservation_query(&cypher, &reservation)).await?;
+    let totals = match result.next().await? {
+        Some(row) => DayTotals {
+            tokens: row.get::<i64>("tokens").unwrap_or(0).max(0) as u64,
+            feature_tokens: row.get::<i64>("feature_tokens").unwrap_or(0).max(0) as u64,
+            cost_usd: row.get("cost").unwrap_or(0.0),
+        },
+        None => DayTotals::default(),
+    };
+
+    if let Some(e) = exceeded(&quota, feature, totals) {
+        release(graph, &reservation).await?;
+        return Err(e.into());
+    }
+    Ok(reservation)
+}
+
+fn reservation_query(cypher: &str, reservation: &Reservation) -> neo4rs::Query {
+    query(cypher)
+        .param("user", reservation.user.clone())
+        .param("day", reservation.day.clone())
+        .param("feature", reservation.feature.clone())
+        .param("tokens", reservation.tokens as i64)
+        .param("cost", reservation.cost_usd)
+}
+
+/// Clause dropping a reservation's hold on the `AiQuota` node `q`.
+fn release_clause(feature: &str) -> String {
+    format!(
+        r#"
+        SET q.reserved_tokens = q.reserved_tokens - $tokens,
+            q.reserved_cost_usd = q.reserved_cost_usd - $cost,
+            q.`reserved_{0}` = q.`reserved_{0}` - $tokens
+        "#,
+        feature
+    )
+}
+
+/// Give back the quota held for a call that failed.
+pub async fn release(graph: &Graph, reservation: &Reservation) -> Result<()> {
+    if reservation.tokens == 0 {
+        return Ok(());
+    }
+    let cypher = format!(
+        "MATCH (q:AiQuota {{user: $user, day: $day}}) {}",
+        release_clause(&reservation.feature)
+    );
+    graph.run(reservation_query(&cypher, reservation)).await?;
+    Ok(())
+}
+
+/// Add one provider call to the user's daily totals for a feature and model,
+/// replacing the quota held for it.
+pub async fn record(
+    graph: &Graph,
+    reservation: &Reservation,
+    model: &str,
+    template: Option<&str>,
+    usage: Usage,
+) -> Result<()> {
+    let cost = cost_usd(model, usage);
+    tracing::info!(
+        target: "ai::usage",
+        user = %reservation.user,
+        feature = %reservation.feature,
+        model,
+        template = template.unwrap_or("-"),
+        prompt_tokens = usage.prompt_tokens,
+        completion_tokens = usage.completion_tokens,
+        cost_usd = cost,
+        "AI call accounted"
+    );
+
+    let cypher = format!(
+        r#"
+        MERGE (u:AiUsage {{user: $user, feature: $feature, model: $model, day: $today}})
+        ON CREATE SET u.requests = 0, u.prompt_tokens = 0, u.completion_tokens = 0, u.cost_usd = 0.0
+        SET u.requests = u.requests + 1,
+            u.prompt_tokens = u.prompt_tokens + $prompt_tokens,
+            u.completion_tokens = u.completion_tokens + $completion_tokens,
+            u.cost_usd = u.cost_usd + $usage_cost
+        WITH u
+        MATCH (q:AiQuota {{user: $user, day: $day}})
+        WHERE $tokens > 0
+        {}
+        "#,
+        release_clause(&reservation.feature)
+    );
+    graph
+        .run(
+            reservation_query(&cypher, reservation)
+                .param("model", model)
+                .param("today", today())
+                .param("prompt_tokens", usage.prompt_tokens as i64)
+                .param("completion_tokens", usage.completion_tokens as i64)
+                .param("usage_cost", cost),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Longest period a usage report covers.
+pub const MAX_REPORT_DAYS: u32 = 366;
+
+/// Usage for the last `days` days, clamped to `1..=MAX_REPORT_DAYS`.
+pub async fn usage_report(graph: &Graph, user: &str, days: u32) -> Result<UsageReport> {
+    let days = days.clamp(1, MAX_REPORT_DAYS);
+    let since = (Utc::now() - Duration::days(days.saturating_sub(1) as i64))
+        .format("%Y-%m-%d")
+        .to_string();
+
+    let cypher = r#"
+        MATCH (u:AiUsage {user: $user})
+        WHERE u.day >= $since
+        RETURN u.day as day, u.feature as feature, u.model as model, u.requests as requests,
+               u.prompt_tokens as prompt_tokens, u.completion_tokens as completion_tokens, u.cost_usd as cost
+        ORDER BY day DESC, feature
+    "#;
+    let mut result = graph
+        .execute(query(cypher).param("user", user).param("since", since))
+        .await?;
+
+    let mut daily = Vec::new();
+    let mut totals: BTreeMap<String, FeatureTotals> = BTreeMap::new();
+
+    while let Some(row) = result.next().await? {
+        let entry = UsageEntry {
+            day: row.get("day")?,
+            feature: row.get("feature")?,
+            model: row.get("model").unwrap_or_default(),
+            requests: row.get::<i64>("requests").unwrap_or(0) as u64,
+            prompt_tokens: row.get::<i64>("prompt_tokens").unwrap_or(0) as u64,
+            completion_tokens: row.get::<i64>("completion_tokens").unwrap_or(0) as u64,
+            cost_usd: row.get("cost").unwrap_or(0.0),
+        };
+
+        let total = totals.entry(entry.feature.clone()).or_default();
+        total.requests += entry.requests;
+        total.prompt_tokens += entry.prompt_tokens;
+        total.completion_tokens += entry.completion_tokens;
+        total.cost_usd += entry.cost_usd;
+
+        daily.push(entry);
+    }
+
+    Ok(UsageReport {
+        user: user.to_string(),
+        days,
+        totals,
+        daily,
+        quota: quota_settings(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(daily_tokens: Option<u64>, daily_cost_usd: Option<f64>, summarize: Option<u64>) -> QuotaSettings {
+        QuotaSettings {
+            daily_tokens,
+            daily_cost_usd,
+            per_feature_daily_tokens: summarize.map(|q| ("summarize".to_string(), q)).into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn prices_calls_per_million_tokens() {
+        let usage = Usage { prompt_tokens: 1_000_000, completion_tokens: 500_000 };
+        assert!((cost_usd("gpt-4o-mini", usage) - 0.45).abs() < 1e-9);
+        assert_eq!(cost_usd("unknown-model", usage), 0.0);
+    }
+
+    #[test]
+    fn rejects_once_a_limit_is_reached() {
+        let totals = |tokens, feature_tokens, cost_usd| DayTotals { tokens, feature_tokens, cost_usd };
+        let quota = settings(Some(10_000), Some(1.0), Some(2_000));
+
+        assert!(exceeded(&quota, "summarize", totals(5_000, 1_999, 0.5)).is_none());
+        assert!(matches!(
+            exceeded(&quota, "summarize", totals(5_000, 2_000, 0.5)),
+            Some(QuotaError::Tokens { ref scope, used: 2_000, limit: 2_000 }) if scope == "summarize"
+        ));
+        // The per-feature limit does not apply to other features
+        assert!(exceeded(&quota, "compose", totals(5_000, 2_000, 0.5)).is_none());
+        assert!(matches!(
+            exceeded(&quota, "compose", totals(10_000, 0, 0.5)),
+            Some(QuotaError::Tokens { ref scope, .. }) if scope == "all features"
+        ));
+        assert!(matches!(exceeded(&quota, "compose", totals(0, 0, 1.0)), Some(QuotaError::Cost { .. })));
+        assert!(exceeded(&settings(None, None, None), "compose", totals(u64::MAX, u64::MAX, 1e9)).is_none());
+    }
+}